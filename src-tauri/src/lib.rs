@@ -4,11 +4,30 @@ fn greet(name: &str) -> String {
     format!("Hello, {}! You've been greeted from Rust!", name)
 }
 
+/// GET /gateway is blocked by cors in the webview, so it is fetched from here.
+/// `endpoint_url` is the rest api the client talks to, ending with a slash
+#[tauri::command]
+async fn get_gateway_url(endpoint_url: String) -> Result<String, String> {
+    #[derive(serde::Deserialize)]
+    struct Gateway {
+        url: String,
+    }
+    let gateway = reqwest::get(format!("{}gateway", endpoint_url))
+        .await
+        .map_err(|e| e.to_string())?
+        .error_for_status()
+        .map_err(|e| e.to_string())?
+        .json::<Gateway>()
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(gateway.url)
+}
+
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     tauri::Builder::default()
         .plugin(tauri_plugin_opener::init())
-        .invoke_handler(tauri::generate_handler![greet])
+        .invoke_handler(tauri::generate_handler![greet, get_gateway_url])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
@@ -1,108 +1,295 @@
-use anyhow::Result;
+use std::sync::Mutex;
+
 use reqwest::{Method, Response};
 use secrecy::{ExposeSecret, SecretString};
+use serde::Deserialize;
 use serde_json::Value;
+#[cfg(target_arch = "wasm32")]
+use wasm_bindgen::prelude::*;
 
 use crate::api::USER_AGENT;
 
-use super::{connection::Connection, model::ReadyEvent, ratelimit::RateLimits, ENDPOINT_URL};
+use super::{
+    compression::Compression,
+    connection::Connection,
+    encoding::Encoding,
+    model::{
+        decode_json, ChannelId, Ignored, Message, MessageId, ReadyEvent, RoleId, UserId,
+        IGNORED_IN_MESSAGE,
+    },
+    ratelimit::RateLimits,
+    runtime::sleep,
+    Error, Result, ENDPOINT_URL,
+};
+
+/// which messages to fetch relative to a given message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GetMessages {
+    MostRecent,
+    Before(MessageId),
+    After(MessageId),
+    Around(MessageId),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllowedMentionType {
+    Roles,
+    Users,
+    Everyone,
+}
+
+impl AllowedMentionType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AllowedMentionType::Roles => "roles",
+            AllowedMentionType::Users => "users",
+            AllowedMentionType::Everyone => "everyone",
+        }
+    }
+}
+
+/// restricts who gets pinged by a sent message
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AllowedMentions {
+    pub parse: Vec<AllowedMentionType>,
+    pub roles: Vec<RoleId>,
+    pub users: Vec<UserId>,
+    pub replied_user: bool,
+}
+
+impl AllowedMentions {
+    fn to_json(&self) -> Value {
+        serde_json::json!({
+            "parse": self.parse.iter().map(AllowedMentionType::as_str).collect::<Vec<_>>(),
+            "roles": self.roles.iter().map(|x| x.to_string()).collect::<Vec<_>>(),
+            "users": self.users.iter().map(|x| x.to_string()).collect::<Vec<_>>(),
+            "replied_user": self.replied_user,
+        })
+    }
+}
+
+/// used when the tauri side can't be reached
+#[cfg(target_arch = "wasm32")]
+const DEFAULT_GATEWAY_URL: &str = "wss://gateway.discord.gg";
+
+/// how often a request is sent before giving up with [`Error::RateLimited`]
+const MAX_ATTEMPTS: u32 = 3;
 
 #[derive(Debug)]
 pub struct Client {
     ratelimits: RateLimits,
     client: reqwest::Client,
     token: SecretString,
+    /// where the rest api is, ending with a slash
+    endpoint_url: String,
+    /// cached answer of GET /gateway
+    gateway_url: Mutex<Option<String>>,
 }
 
 impl Client {
     pub fn from_user_token(token: SecretString) -> Self {
+        Self::with_urls(token, ENDPOINT_URL, None)
+    }
+
+    /// talks to another server than discord, e.g. a mock in tests. `gateway_url` is used
+    /// instead of asking `endpoint_url` for it
+    pub fn with_urls(token: SecretString, endpoint_url: &str, gateway_url: Option<&str>) -> Self {
+        let mut endpoint_url = endpoint_url.to_owned();
+        if !endpoint_url.ends_with('/') {
+            endpoint_url.push('/');
+        }
         Self {
             ratelimits: Default::default(),
             client: reqwest::Client::new(),
             token,
+            endpoint_url,
+            gateway_url: Mutex::new(gateway_url.map(str::to_owned)),
         }
     }
 
     pub async fn connect(&self) -> Result<(Connection, ReadyEvent)> {
         let url = self.get_gateway_url().await?;
-        Connection::new(&url, self.token.clone()).await
+        Connection::new(
+            &url,
+            self.token.clone(),
+            Compression::default(),
+            Encoding::default(),
+            None,
+        )
+        .await
+    }
+
+    /// `limit` is clamped by discord to 1-100 and defaults to 50
+    pub async fn get_messages(
+        &self,
+        channel: ChannelId,
+        what: GetMessages,
+        limit: Option<u64>,
+    ) -> Result<Vec<Message>> {
+        let mut url = format!(
+            "channels/{}/messages?limit={}",
+            channel,
+            limit.unwrap_or(50)
+        );
+        match what {
+            GetMessages::MostRecent => {}
+            GetMessages::Before(id) => url.push_str(&format!("&before={}", id)),
+            GetMessages::After(id) => url.push_str(&format!("&after={}", id)),
+            GetMessages::Around(id) => url.push_str(&format!("&around={}", id)),
+        }
+        let response = self.request(Method::GET, &url, None).await?;
+        decode_json(&response.text().await?, &IGNORED_IN_MESSAGE)
+    }
+
+    pub async fn get_message(&self, channel: ChannelId, message: MessageId) -> Result<Message> {
+        let url = format!("channels/{}/messages/{}", channel, message);
+        let response = self.request(Method::GET, &url, None).await?;
+        decode_json(&response.text().await?, &IGNORED_IN_MESSAGE)
+    }
+
+    /// `reply_to` turns the message into a reply, `nonce` is echoed back in the MESSAGE_CREATE
+    /// event so the sent message can be matched to a pending one
+    pub async fn send_message(
+        &self,
+        channel: ChannelId,
+        content: &str,
+        reply_to: Option<MessageId>,
+        allowed_mentions: Option<&AllowedMentions>,
+        nonce: Option<&str>,
+    ) -> Result<Message> {
+        let mut body = serde_json::json!({
+            "content": content,
+        });
+        if let Some(reply_to) = reply_to {
+            body["message_reference"] = serde_json::json!({
+                "channel_id": channel.to_string(),
+                "message_id": reply_to.to_string(),
+            });
+        }
+        if let Some(allowed_mentions) = allowed_mentions {
+            body["allowed_mentions"] = allowed_mentions.to_json();
+        }
+        if let Some(nonce) = nonce {
+            body["nonce"] = nonce.into();
+        }
+        let url = format!("channels/{}/messages", channel);
+        let response = self.request(Method::POST, &url, Some(body)).await?;
+        decode_json(&response.text().await?, &IGNORED_IN_MESSAGE)
+    }
+
+    pub async fn edit_message(
+        &self,
+        channel: ChannelId,
+        message: MessageId,
+        content: &str,
+    ) -> Result<Message> {
+        let body = serde_json::json!({
+            "content": content,
+        });
+        let url = format!("channels/{}/messages/{}", channel, message);
+        let response = self.request(Method::PATCH, &url, Some(body)).await?;
+        decode_json(&response.text().await?, &IGNORED_IN_MESSAGE)
+    }
+
+    pub async fn delete_message(&self, channel: ChannelId, message: MessageId) -> Result<()> {
+        let url = format!("channels/{}/messages/{}", channel, message);
+        self.request(Method::DELETE, &url, None).await?;
+        Ok(())
     }
 
     /// pass None for json_body to make a request without a body
     async fn request(
         &self,
         method: Method,
-        url: &str,
+        path: &str,
         json_body: Option<Value>,
     ) -> Result<Response> {
-        let url = format!("{}{}", ENDPOINT_URL, url);
-        let url = &url;
-        self.ratelimits.pre_check(url);
-        let response;
-        if json_body.is_some() {
-            response = self
-                .client
-                .request(method.clone(), url)
-                .header("Content-Type", "application/json")
-                .header("User-Agent", USER_AGENT)
-                .header("Authorization", self.token.expose_secret())
-                .body(json_body.as_ref().unwrap().to_string())
-                .send()
-                .await?;
-        } else {
-            response = self
+        let url = format!("{}{}", self.endpoint_url, path);
+        let mut attempts = 0;
+        let response = loop {
+            // waits without blocking, other buckets keep going in the meantime
+            // held until the response is checked, in case it is the one telling us the limit
+            let _turn = self.ratelimits.pre_check(&method, path).await;
+            let mut request = self
                 .client
-                .request(method.clone(), url)
+                .request(method.clone(), &url)
                 .header("Content-Type", "application/json")
                 .header("User-Agent", USER_AGENT)
-                .header("Authorization", self.token.expose_secret())
-                .send()
-                .await?;
-        }
-        if self.ratelimits.check_for_ratelimit(url, &response) {
-            let response;
-            if json_body.is_some() {
-                response = self
-                    .client
-                    .request(method, url)
-                    .header("Content-Type", "application/json")
-                    .header("User-Agent", USER_AGENT)
-                    .header("Authorization", self.token.expose_secret())
-                    .body(json_body.unwrap().to_string())
-                    .send()
-                    .await?;
-            } else {
-                response = self
-                    .client
-                    .request(method, url)
-                    .header("Content-Type", "application/json")
-                    .header("User-Agent", USER_AGENT)
-                    .header("Authorization", self.token.expose_secret())
-                    .send()
-                    .await?;
+                .header("Authorization", self.token.expose_secret());
+            if let Some(ref json_body) = json_body {
+                request = request.body(json_body.to_string());
+            }
+            let response = request.send().await?;
+            attempts += 1;
+            match self
+                .ratelimits
+                .check_for_ratelimit(&method, path, &response)
+            {
+                Some(retry_after) if attempts < MAX_ATTEMPTS => sleep(retry_after).await,
+                Some(retry_after) => return Err(Error::RateLimited { retry_after }),
+                None => break response,
             }
-            self.ratelimits.check_for_ratelimit(url, &response);
-            return Ok(response);
+        };
+        match response.status().as_u16() {
+            200..=299 => Ok(response),
+            401 => Err(Error::Auth),
+            status => Err(Error::http(status, &response.text().await?)),
         }
-        Ok(response)
     }
 
+    /// asks discord once and reuses the answer, it only changes when discord moves the gateway
     async fn get_gateway_url(&self) -> Result<String> {
-        // let response = self
-        //     .client
-        //     .request(Method::GET, format!("{}{}", ENDPOINT_URL, "/gateway"))
-        //     .header("Content-Type", "application/json")
-        //     .send()
-        //     .await?;
-        // Ok(response
-        //     .json::<Value>()
-        //     .await?
-        //     .get("url")
-        //     .expect("no url in response to get_gateway_url")
-        //     .as_str()
-        //     .expect("could not parse str")
-        //     .replace("\"", ""))
-        // TODO it currents receives a cors cross origin refferer error
-        Ok("wss://gateway.discord.gg".to_string())
+        if let Some(ref url) = *self.gateway_url.lock().expect("gateway url poisoned") {
+            return Ok(url.clone());
+        }
+        let url = match self.fetch_gateway_url().await {
+            Ok(url) => url,
+            // outside tauri, not cached so tauri is asked again on the next connect
+            #[cfg(target_arch = "wasm32")]
+            Err(Error::Io(err)) => {
+                super::runtime::log(&format!("unable to get the gateway url: {}", err));
+                return Ok(DEFAULT_GATEWAY_URL.to_owned());
+            }
+            Err(err) => return Err(err),
+        };
+        *self.gateway_url.lock().expect("gateway url poisoned") = Some(url.clone());
+        Ok(url)
+    }
+
+    /// the webview blocks `/gateway` because of cors, so the tauri side fetches it
+    #[cfg(target_arch = "wasm32")]
+    async fn fetch_gateway_url(&self) -> Result<String> {
+        let args = js_sys::Object::new();
+        js_sys::Reflect::set(
+            &args,
+            &"endpointUrl".into(),
+            &self.endpoint_url.as_str().into(),
+        )
+        .expect("setting a field of a new object");
+        let url = invoke("get_gateway_url", args.into())
+            .await
+            .map_err(|err| Error::Io(format!("{:?}", err).into()))?;
+        url.as_string()
+            .ok_or_else(|| Error::decode("get_gateway_url did not return a string"))
     }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn fetch_gateway_url(&self) -> Result<String> {
+        let response = self.request(Method::GET, "gateway", None).await?;
+        let gateway: GatewayResponse = decode_json(&response.text().await?, &Ignored::NONE)?;
+        Ok(gateway.url)
+    }
+}
+
+/// the answer to GET /gateway
+#[derive(Deserialize)]
+struct GatewayResponse {
+    url: String,
+}
+
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(catch, js_namespace = ["window", "__TAURI__", "core"])]
+    async fn invoke(cmd: &str, args: JsValue) -> std::result::Result<JsValue, JsValue>;
 }
@@ -0,0 +1,224 @@
+use flate2::{Decompress, FlushDecompress, Status};
+use serde::{Deserialize, Serialize};
+
+use super::{Error, Result};
+
+/// zlib-stream ends every complete payload with a sync flush
+const ZLIB_SUFFIX: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// how the gateway compresses what it sends
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Compression {
+    None,
+    /// every payload is compressed on its own, asked for in IDENTIFY
+    Payload,
+    /// one zlib context for the whole connection, payloads can be split over several frames
+    #[default]
+    ZlibStream,
+    /// one zstd context for the whole connection
+    #[cfg(feature = "zstd")]
+    ZstdStream,
+}
+
+impl Compression {
+    /// the `compress` query parameter of the gateway url
+    pub(crate) fn query(self) -> Option<&'static str> {
+        match self {
+            Compression::None | Compression::Payload => None,
+            Compression::ZlibStream => Some("zlib-stream"),
+            #[cfg(feature = "zstd")]
+            Compression::ZstdStream => Some("zstd-stream"),
+        }
+    }
+}
+
+/// decompresses the binary frames of one websocket connection, a reconnect needs a new one
+pub(crate) enum Inflater {
+    None,
+    Payload,
+    ZlibStream {
+        context: Decompress,
+        /// frames received since the last complete payload
+        buffer: Vec<u8>,
+    },
+    #[cfg(feature = "zstd")]
+    ZstdStream(zstd::stream::raw::Decoder<'static>),
+}
+
+impl Inflater {
+    pub(crate) fn new(compression: Compression) -> Result<Self> {
+        Ok(match compression {
+            Compression::None => Inflater::None,
+            Compression::Payload => Inflater::Payload,
+            Compression::ZlibStream => Inflater::ZlibStream {
+                context: Decompress::new(true),
+                buffer: Vec::new(),
+            },
+            #[cfg(feature = "zstd")]
+            Compression::ZstdStream => {
+                Inflater::ZstdStream(zstd::stream::raw::Decoder::new().map_err(|e| {
+                    Error::Io(format!("unable to create zstd context: {}", e).into())
+                })?)
+            }
+        })
+    }
+
+    /// returns the json once a complete payload has been received. an error is an [`Error::Io`],
+    /// the stream contexts can't be used after one so the connection has to be reopened
+    pub(crate) fn push(&mut self, frame: &[u8]) -> Result<Option<Vec<u8>>> {
+        match *self {
+            // uncompressed etf
+            Inflater::None => Ok(Some(frame.to_vec())),
+            Inflater::Payload => {
+                let mut context = Decompress::new(true);
+                inflate(&mut context, frame, FlushDecompress::Finish).map(Some)
+            }
+            Inflater::ZlibStream {
+                ref mut context,
+                ref mut buffer,
+            } => {
+                buffer.extend_from_slice(frame);
+                if !buffer.ends_with(&ZLIB_SUFFIX) {
+                    return Ok(None);
+                }
+                let json = inflate(context, buffer, FlushDecompress::Sync);
+                buffer.clear();
+                json.map(Some)
+            }
+            #[cfg(feature = "zstd")]
+            Inflater::ZstdStream(ref mut context) => unzstd(context, frame).map(Some),
+        }
+    }
+}
+
+fn inflate(context: &mut Decompress, mut input: &[u8], flush: FlushDecompress) -> Result<Vec<u8>> {
+    let mut output = Vec::with_capacity(input.len() * 4);
+    loop {
+        let total_in = context.total_in();
+        let status = context
+            .decompress_vec(input, &mut output, flush)
+            .map_err(|e| Error::Io(format!("unable to decompress: {}", e).into()))?;
+        input = &input[(context.total_in() - total_in) as usize..];
+        let output_full = output.len() == output.capacity();
+        match status {
+            Status::StreamEnd => break,
+            Status::BufError if !output_full => break,
+            _ if input.is_empty() && !output_full => break,
+            _ => output.reserve(output.capacity().max(1024)),
+        }
+    }
+    Ok(output)
+}
+
+#[cfg(feature = "zstd")]
+fn unzstd(context: &mut zstd::stream::raw::Decoder<'static>, frame: &[u8]) -> Result<Vec<u8>> {
+    use zstd::stream::raw::{InBuffer, Operation, OutBuffer};
+
+    let mut input = InBuffer::around(frame);
+    let mut output = Vec::with_capacity(frame.len() * 4);
+    loop {
+        let position = output.len();
+        let mut buffer = OutBuffer::around_pos(&mut output, position);
+        context
+            .run(&mut input, &mut buffer)
+            .map_err(|e| Error::Io(format!("unable to decompress: {}", e).into()))?;
+        // a full output buffer can mean there is more to flush
+        if input.pos() == frame.len() && output.len() < output.capacity() {
+            break;
+        }
+        output.reserve(output.capacity().max(1024));
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::test_util::hex;
+
+    /// HELLO, HEARTBEAT_ACK and RESUMED as sent by the gateway with `compress=zlib-stream`
+    const ZLIB_STREAM_FRAMES: [&str; 3] = [
+        "789c34c9410a83301005d0bbfc752249a9a5cc558cc8a8432ba42ac9d85242ee6e37dd3d78050a5a8f180d\
+         f21fdb0ef2ce6006153c85938ec23a2cab4a7a73045dfda5fdfda089270175e8021eacf2e1afddd36c8f6c\
+         85b37a3b5ad7deee01a604bc96296d39805ce36a8fbed6130000ffff",
+        "aac665b721d86e90402d000000ffff",
+        "02ab510a720d0ef5757551022b3485a8823990525700000000ffff",
+    ];
+
+    const PAYLOADS: [&str; 3] = [
+        r#"{"t":null,"s":null,"op":10,"d":{"heartbeat_interval":41250,"_trace":["[\"gateway-prd-us-east1-b-0568\",{\"micros\":0.0}]"]}}"#,
+        r#"{"t":null,"s":null,"op":11,"d":null}"#,
+        r#"{"t":"RESUMED","s":5,"op":0,"d":{"_trace":["[\"gateway-prd-us-east1-b-0568\",{\"micros\":0.0}]"]}}"#,
+    ];
+
+    #[test]
+    fn zlib_stream_shares_the_context() {
+        let mut inflater = Inflater::new(Compression::ZlibStream).unwrap();
+        for (frame, payload) in ZLIB_STREAM_FRAMES.iter().zip(PAYLOADS) {
+            let json = inflater.push(&hex(frame)).unwrap().unwrap();
+            assert_eq!(String::from_utf8(json).unwrap(), payload);
+        }
+    }
+
+    #[test]
+    fn zlib_stream_buffers_until_the_suffix() {
+        let mut inflater = Inflater::new(Compression::ZlibStream).unwrap();
+        let frame = hex(ZLIB_STREAM_FRAMES[0]);
+        let (first, second) = frame.split_at(frame.len() / 2);
+        assert_eq!(inflater.push(first).unwrap(), None);
+        let json = inflater.push(second).unwrap().unwrap();
+        assert_eq!(String::from_utf8(json).unwrap(), PAYLOADS[0]);
+    }
+
+    #[test]
+    fn zlib_stream_needs_the_previous_frames() {
+        let mut inflater = Inflater::new(Compression::ZlibStream).unwrap();
+        assert!(matches!(
+            inflater.push(&hex(ZLIB_STREAM_FRAMES[2])),
+            Err(Error::Io(_))
+        ));
+    }
+
+    #[test]
+    fn payload_compression() {
+        let mut encoder =
+            flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        std::io::Write::write_all(&mut encoder, PAYLOADS[0].as_bytes()).unwrap();
+        let frame = encoder.finish().unwrap();
+        let mut inflater = Inflater::new(Compression::Payload).unwrap();
+        for _ in 0..2 {
+            let json = inflater.push(&frame).unwrap().unwrap();
+            assert_eq!(String::from_utf8(json).unwrap(), PAYLOADS[0]);
+        }
+    }
+
+    #[test]
+    fn large_payload() {
+        let payload = format!(r#"{{"d":"{}"}}"#, "discidium".repeat(10_000));
+        let mut context = flate2::Compress::new(flate2::Compression::default(), true);
+        let mut frame = Vec::with_capacity(payload.len());
+        context
+            .compress_vec(payload.as_bytes(), &mut frame, flate2::FlushCompress::Sync)
+            .unwrap();
+        let mut inflater = Inflater::new(Compression::ZlibStream).unwrap();
+        let json = inflater.push(&frame).unwrap().unwrap();
+        assert_eq!(String::from_utf8(json).unwrap(), payload);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_stream_shares_the_context() {
+        use std::io::Write;
+
+        let mut encoder = zstd::stream::write::Encoder::new(Vec::new(), 0).unwrap();
+        let mut inflater = Inflater::new(Compression::ZstdStream).unwrap();
+        for payload in PAYLOADS {
+            encoder.write_all(payload.as_bytes()).unwrap();
+            encoder.flush().unwrap();
+            let frame = std::mem::take(encoder.get_mut());
+            let json = inflater.push(&frame).unwrap().unwrap();
+            assert_eq!(String::from_utf8(json).unwrap(), payload);
+        }
+    }
+}
@@ -1,40 +1,112 @@
 use std::{
-    cell::RefCell,
-    net::TcpStream,
-    rc::Rc,
-    sync::{mpsc, Arc, Mutex},
+    collections::{BTreeMap, VecDeque},
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll},
+    time::Duration,
 };
 
-use anyhow::{Error, Result};
-use ewebsock::{connect, Options, WsEvent, WsMessage, WsReceiver, WsSender};
+use chrono::{DateTime, Utc};
 use futures::{
-    channel::mpsc::{Receiver, Sender, UnboundedReceiver, UnboundedSender},
-    StreamExt,
+    channel::{
+        mpsc::{UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    FutureExt, SinkExt, Stream, StreamExt,
 };
 use secrecy::{ExposeSecret, SecretString};
 use serde_json::Value;
-use time::Time;
-use web_sys::console::time;
-use yew::platform::{spawn_local, time::sleep};
+use web_time::Instant;
 
-use crate::api::model::{receive_json, Event, GatewayEvent};
+use crate::api::model::{inflate_ws_event, receive_json, Event, GatewayEvent};
 
-use super::model::{ReadyEvent, UserId};
+use super::{
+    compression::{Compression, Inflater},
+    encoding::Encoding,
+    model::{
+        self, Activity, ChannelId, Member, ReadyEvent, ServerId, ServerMembersChunkEvent, UserId,
+    },
+    recording::Recorder,
+    runtime::{self, sleep},
+    websocket::{self, WsEvent, WsSender},
+    CloseCode, Error, Reconnect, Result, API_VERSION,
+};
 
+/// the dispatches received after READY are read by polling it as a [`Stream`],
+/// reconnecting and resuming happens behind it
 pub struct Connection {
     ws_sender: UnboundedSender<Status>,
-    ws_receiver: UnboundedReceiver<GatewayEvent>,
-    token: SecretString,
-    session_id: Option<String>,
-    last_sequence: usize,
-    identify: Value,
+    ws_receiver: UnboundedReceiver<Result<Event>>,
     user_id: UserId,
-    ws_url: String,
+    /// tells the answers to member requests apart
+    next_nonce: AtomicU64,
     // voice
 }
 
+/// which members to ask for with [`Connection::request_server_members`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MemberQuery {
+    /// members whose username or nickname starts with the query, at most `limit` of them
+    Prefix {
+        query: String,
+        limit: u64,
+    },
+    Users(Vec<UserId>),
+}
+
+/// every chunk of the answer to a member request put together
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerMembers {
+    pub members: Vec<Member>,
+    /// requested user ids that are not in the server
+    pub not_found: Vec<UserId>,
+}
+
+/// what to receive for a server that is being looked at
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServerSubscription {
+    pub activities: bool,
+    /// ranges of the member list to receive for each channel, e.g. `[(0, 99)]`
+    pub channels: BTreeMap<ChannelId, Vec<(u64, u64)>>,
+    /// only used by [`Connection::subscribe_servers`]
+    pub member_updates: bool,
+    /// members to receive presence updates for
+    pub members: Vec<UserId>,
+    pub threads: bool,
+    pub typing: bool,
+}
+
+impl ServerSubscription {
+    fn to_json(&self) -> Value {
+        let channels = self
+            .channels
+            .iter()
+            .map(|(id, ranges)| {
+                let ranges = ranges.iter().map(|&(from, to)| [from, to]);
+                (
+                    id.to_string(),
+                    serde_json::json!(ranges.collect::<Vec<_>>()),
+                )
+            })
+            .collect::<serde_json::Map<_, _>>();
+        serde_json::json!({
+            "activities": self.activities,
+            "channels": channels,
+            "members": self.members,
+            "threads": self.threads,
+            "typing": self.typing,
+        })
+    }
+}
+
 impl Connection {
-    pub async fn new(url: &str, token: SecretString) -> Result<(Self, ReadyEvent)> {
+    pub async fn new(
+        url: &str,
+        token: SecretString,
+        compression: Compression,
+        encoding: Encoding,
+        recorder: Option<Recorder>,
+    ) -> Result<(Self, ReadyEvent)> {
         let d = serde_json::json!({
             "token": token.expose_secret(),
             "properties": {
@@ -43,163 +115,684 @@ impl Connection {
                 "$device": "discidium",
             },
             "large_threshold": 250,
-            "compress": true,
+            "compress": compression == Compression::Payload,
         });
         let identify = serde_json::json!({
             "op": 2, // IDENTIFY
             "d": d,
         });
-        // let d = serde_json::json!({
-        //     "seq": "",
-        //     "session_id": "",
-        //     "token": token.expose_secret(),
-        // });
-        // let identify = serde_json::json!({
-        //     "op": 6, // RESUME
-        //     "d": d,
-        // });
 
         let (ws_sender, to_ws_receiver) = futures::channel::mpsc::unbounded();
         let (to_ws_sender, mut ws_receiver) = futures::channel::mpsc::unbounded();
-        spawn_local(keepalive(
-            url.to_string(),
-            identify.clone(),
-            to_ws_sender,
-            to_ws_receiver,
-        ));
-
-        let sequence;
-        let ready;
-        match ws_receiver.next().await.unwrap() {
-            GatewayEvent::Dispatch(seq, Event::Ready(event)) => {
-                sequence = seq;
-                ready = event;
-            }
-            GatewayEvent::InvalidateSession => {
-                web_sys::console::log_1(&format!("invalidate session").into());
-                todo!("invalidate session")
-            }
-            other => {
-                web_sys::console::log_1(&format!("unknown response: {:?}", other).into());
-                panic!("unknown response: {:?}", other)
-            }
-        }
-        web_sys::console::log_1(&format!("a").into());
+        let gateway = Gateway {
+            url: url.to_string(),
+            token,
+            compression,
+            encoding,
+            identify,
+            session: None,
+            last_sequence: None,
+            failed_attempts: 0,
+            limit: CommandLimit::default(),
+            queue: VecDeque::new(),
+            pending_members: BTreeMap::new(),
+            recorder,
+            sender: to_ws_sender,
+            receiver: to_ws_receiver,
+        };
+        runtime::spawn(gateway.run());
 
-        let session_id = ready.session_id.clone();
-        web_sys::console::log_1(&format!("{:?}", ready.clone()).into());
-        println!("{:?}", ready.clone());
+        // invalid sessions and reconnects before READY are handled by the gateway task
+        let ready = loop {
+            match ws_receiver.next().await {
+                Some(Ok(Event::Ready(event))) => break *event,
+                Some(Err(err)) => return Err(err),
+                Some(Ok(other)) => {
+                    runtime::log(&format!("event before ready: {:?}", other));
+                }
+                None => return Err(Error::Gateway { close_code: None }),
+            }
+        };
 
         Ok((
             Self {
                 ws_sender,
                 ws_receiver,
-                token,
-                session_id: Some(session_id),
-                last_sequence: sequence,
-                identify,
                 user_id: ready.user.id,
-                ws_url: url.to_string(),
+                next_nonce: AtomicU64::new(0),
             },
             ready,
         ))
     }
+
+    pub fn user_id(&self) -> UserId {
+        self.user_id
+    }
+
+    /// op 3, replaces the whole presence of the current user
+    pub fn update_presence(
+        &self,
+        status: model::Status,
+        activities: &[Activity],
+        afk: bool,
+        since: Option<DateTime<Utc>>,
+    ) -> Result<()> {
+        let d = serde_json::json!({
+            "activities": activities,
+            "afk": afk,
+            "since": since.map(|x| x.timestamp_millis()),
+            "status": status,
+        });
+        self.send(3, d)
+    }
+
+    /// op 4, `channel` None leaves voice, `server` None is for calls in private channels
+    pub fn update_voice_state(
+        &self,
+        server: Option<ServerId>,
+        channel: Option<ChannelId>,
+        self_mute: bool,
+        self_deaf: bool,
+    ) -> Result<()> {
+        let d = serde_json::json!({
+            "channel_id": channel,
+            "guild_id": server,
+            "self_deaf": self_deaf,
+            "self_mute": self_mute,
+        });
+        self.send(4, d)
+    }
+
+    /// op 8, resolves once every GUILD_MEMBERS_CHUNK of the answer has been received.
+    /// the chunks are still passed on as events
+    pub async fn request_server_members(
+        &self,
+        server: ServerId,
+        query: MemberQuery,
+    ) -> Result<ServerMembers> {
+        let nonce = self.next_nonce.fetch_add(1, Ordering::Relaxed).to_string();
+        let mut d = serde_json::json!({
+            "guild_id": server,
+            "nonce": nonce,
+            "presences": false,
+        });
+        match query {
+            MemberQuery::Prefix { query, limit } => {
+                d["query"] = query.into();
+                d["limit"] = limit.into();
+            }
+            MemberQuery::Users(users) => d["user_ids"] = serde_json::json!(users),
+        }
+        let (reply, answer) = oneshot::channel();
+        self.ws_sender
+            .unbounded_send(Status::RequestMembers {
+                nonce,
+                payload: command(8, d),
+                reply,
+            })
+            .map_err(|_| Error::Gateway { close_code: None })?;
+        // the sender is dropped if the session is lost before the last chunk
+        answer
+            .await
+            .map_err(|_| Error::Gateway { close_code: None })
+    }
+
+    /// op 14, the older way of subscribing to a single server
+    pub fn request_lazy_server(
+        &self,
+        server: ServerId,
+        subscription: &ServerSubscription,
+    ) -> Result<()> {
+        let mut d = subscription.to_json();
+        d["guild_id"] = serde_json::json!(server);
+        self.send(14, d)
+    }
+
+    /// op 37, subscribes to typing, threads, activities and member list ranges of servers
+    pub fn subscribe_servers(
+        &self,
+        subscriptions: &BTreeMap<ServerId, ServerSubscription>,
+    ) -> Result<()> {
+        let subscriptions = subscriptions
+            .iter()
+            .map(|(id, subscription)| {
+                let mut json = subscription.to_json();
+                json["member_updates"] = subscription.member_updates.into();
+                (id.to_string(), json)
+            })
+            .collect::<serde_json::Map<_, _>>();
+        self.send(37, serde_json::json!({ "subscriptions": subscriptions }))
+    }
+
+    /// op 34, the answer arrives as [`Event::LastMessages`]
+    pub fn request_last_messages(&self, server: ServerId, channels: &[ChannelId]) -> Result<()> {
+        let d = serde_json::json!({
+            "channel_ids": channels,
+            "guild_id": server,
+        });
+        self.send(34, d)
+    }
+
+    /// queued by the gateway task, which keeps to the send limit
+    fn send(&self, op: u8, d: Value) -> Result<()> {
+        self.ws_sender
+            .unbounded_send(Status::SendMessage(command(op, d)))
+            .map_err(|_| Error::Gateway { close_code: None })
+    }
+}
+
+/// events that could not be decoded are [`Error::Decode`] items, the stream goes on after them.
+/// the last item before the stream ends is the error that made the gateway give up,
+/// e.g. [`Error::Auth`] when the token stopped working
+impl Stream for Connection {
+    type Item = Result<Event>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Event>>> {
+        self.ws_receiver.poll_next_unpin(cx)
+    }
+}
+
+fn command(op: u8, d: Value) -> Value {
+    serde_json::json!({
+        "op": op,
+        "d": d,
+    })
+}
+
+/// what is needed to RESUME a session after the websocket drops
+struct Session {
+    session_id: String,
+    resume_gateway_url: String,
 }
 
-async fn keepalive(
+/// why a single websocket connection ended
+enum SessionEnd {
+    /// the Connection was dropped, stop reconnecting
+    Aborted,
+    /// the gateway asked us to reconnect (op 7)
+    Reconnect,
+    /// the gateway invalidated the session (op 9), true if it can be resumed
+    InvalidSession(bool),
+    /// the websocket closed or could not be opened, with the close code if the gateway sent one
+    Closed(Option<CloseCode>),
+}
+
+/// owns the websocket for the lifetime of a Connection, reconnecting and resuming as needed
+struct Gateway {
     url: String,
+    token: SecretString,
+    compression: Compression,
+    encoding: Encoding,
     identify: Value,
-    sender: UnboundedSender<GatewayEvent>,
-    mut receiver: UnboundedReceiver<Status>,
-) {
-    let (ws_sender, mut ws_receiver) = connect(url, Options::default()).unwrap();
-    let ws_sender = Rc::new(RefCell::new(ws_sender));
-    {
-        let mut a = ws_receiver.try_recv();
-        while a.is_none() {
-            a = ws_receiver.try_recv();
-            sleep(std::time::Duration::from_millis(100)).await;
-        }
-        match a.as_ref().unwrap() {
-            WsEvent::Opened => {}
-            other => {
-                web_sys::console::log_1(&format!("{:?}", other).into());
-                eprintln!("{:?}", other);
+    session: Option<Session>,
+    last_sequence: Option<usize>,
+    /// closes since the last READY or RESUMED, for backing off
+    failed_attempts: u32,
+    /// payloads sent on the current websocket
+    limit: CommandLimit,
+    /// commands waiting for the send limit, kept over reconnects
+    queue: VecDeque<Value>,
+    /// member requests by nonce, until their last chunk
+    pending_members: BTreeMap<String, PendingMembers>,
+    /// writes every frame sent and received, kept over reconnects
+    recorder: Option<Recorder>,
+    /// dispatches go to the Connection, the sequence numbers stay here for heartbeats and resuming
+    sender: UnboundedSender<Result<Event>>,
+    receiver: UnboundedReceiver<Status>,
+}
+
+impl Gateway {
+    async fn run(mut self) {
+        loop {
+            match self.connect_once().await {
+                SessionEnd::Aborted => break,
+                SessionEnd::Reconnect => self.failed_attempts = 0,
+                SessionEnd::InvalidSession(resumable) => {
+                    if !resumable {
+                        self.forget_session();
+                    }
+                    // discord asks for a random wait of 1-5 seconds before identifying again
+                    let delay = 1000 + (random_fraction() * 4000.) as u64;
+                    sleep(std::time::Duration::from_millis(delay)).await;
+                }
+                SessionEnd::Closed(close_code) => {
+                    // closing without a code is usually the network, so resume
+                    match close_code.map_or(Reconnect::Resume, CloseCode::reconnect) {
+                        Reconnect::Resume => {}
+                        Reconnect::Identify => self.forget_session(),
+                        Reconnect::Stop => {
+                            let code = close_code.expect("closing without a code resumes");
+                            let _ = self.sender.unbounded_send(Err(code.into()));
+                            break;
+                        }
+                    }
+                    self.failed_attempts += 1;
+                    // resume straight away once, then back off exponentially up to a minute
+                    if self.failed_attempts > 1 {
+                        let delay = 1000 * 2_u64.pow((self.failed_attempts - 2).min(6));
+                        sleep(std::time::Duration::from_millis(delay)).await;
+                    }
+                }
             }
         }
     }
 
-    ws_sender
-        .clone()
-        .borrow_mut()
-        .send(WsMessage::Text(identify.to_string()));
+    /// the next connection will IDENTIFY instead of resuming
+    fn forget_session(&mut self) {
+        self.session = None;
+        self.last_sequence = None;
+        // a new session won't answer requests made in the old one
+        self.pending_members.clear();
+    }
 
-    // get heartbeat
-    let mut interval;
-    match receive_json(&mut ws_receiver, GatewayEvent::decode)
-        .await
-        .unwrap()
-    {
-        GatewayEvent::Hello(heartbeat_interval) => {
-            interval = heartbeat_interval;
+    /// the url to open and the RESUME payload if there is a session to resume, otherwise IDENTIFY
+    fn handshake(&self) -> (String, Value) {
+        match self.session {
+            Some(ref session) => {
+                let d = serde_json::json!({
+                    "seq": self.last_sequence,
+                    "session_id": session.session_id,
+                    "token": self.token.expose_secret(),
+                });
+                let resume = serde_json::json!({
+                    "op": 6, // RESUME
+                    "d": d,
+                });
+                (self.with_query(&session.resume_gateway_url), resume)
+            }
+            None => (self.with_query(&self.url), self.identify.clone()),
         }
-        _ => panic!("expected hello during handshake"),
     }
 
-    let mut next_tick_at = time::OffsetDateTime::now_utc().time().millisecond() + interval as u16;
-    let mut last_sequence = 0;
+    /// the resume url from READY comes without query parameters, so both get the same ones
+    fn with_query(&self, url: &str) -> String {
+        let mut url = format!(
+            "{}/?v={}&encoding={}",
+            url.trim_end_matches('/'),
+            API_VERSION,
+            self.encoding.query()
+        );
+        if let Some(compress) = self.compression.query() {
+            url.push_str("&compress=");
+            url.push_str(compress);
+        }
+        url
+    }
+
+    async fn connect_once(&mut self) -> SessionEnd {
+        let (url, handshake) = self.handshake();
+        let mut inflater = match Inflater::new(self.compression) {
+            Ok(inflater) => inflater,
+            Err(err) => {
+                runtime::log(&format!("{}", err));
+                return SessionEnd::Closed(None);
+            }
+        };
+        let (mut ws_sender, mut ws_receiver) = match websocket::connect(&url) {
+            Ok(ws) => ws,
+            Err(err) => {
+                runtime::log(&format!("unable to connect: {:?}", err));
+                return SessionEnd::Closed(None);
+            }
+        };
+        match ws_receiver.next().await {
+            Some(WsEvent::Opened) => {}
+            other => {
+                runtime::log(&format!("unable to open the websocket: {:?}", other));
+                return SessionEnd::Closed(None);
+            }
+        }
+        // everything received from here on is recorded as it comes in
+        let recorder = self.recorder.clone();
+        if let Some(ref recorder) = recorder {
+            recorder.opened(self.compression, self.encoding);
+        }
+        let mut ws_receiver = ws_receiver.inspect(move |event| {
+            if let Some(ref recorder) = recorder {
+                recorder.received(event);
+            }
+        });
+
+        // get heartbeat
+        let mut heartbeat = match receive_json(
+            &mut ws_receiver,
+            &mut inflater,
+            self.encoding,
+            GatewayEvent::decode,
+        )
+        .await
+        {
+            Ok(GatewayEvent::Hello(heartbeat_interval)) => match Heartbeat::new(heartbeat_interval)
+            {
+                Ok(heartbeat) => heartbeat,
+                Err(err) => {
+                    runtime::log(&format!("{}", err));
+                    return SessionEnd::Closed(None);
+                }
+            },
+            Err(Error::Gateway { close_code }) => return SessionEnd::Closed(close_code),
+            other => {
+                runtime::log(&format!("expected hello: {:?}", other));
+                return SessionEnd::Closed(None);
+            }
+        };
+
+        self.limit = CommandLimit::default();
+        self.send(&mut ws_sender, &handshake).await;
 
-    spawn_local(async move {
         loop {
-            match receive_json(&mut ws_receiver, GatewayEvent::decode).await {
-                Ok(event) => {
-                    sender.unbounded_send(event).unwrap();
+            self.send_queued(&mut ws_sender).await;
+
+            // sleeps until something arrives, a heartbeat is due or the send limit frees up
+            let mut wait = heartbeat.next_at.saturating_duration_since(Instant::now());
+            if !self.queue.is_empty() {
+                wait = wait.min(self.limit.wait());
+            }
+            let wake = {
+                let timer = sleep(wait).fuse();
+                futures::pin_mut!(timer);
+                futures::select! {
+                    received = ws_receiver.next().fuse() => Wake::Received(received),
+                    status = self.receiver.next().fuse() => Wake::Status(status),
+                    () = timer => Wake::Timer,
+                }
+            };
+
+            match wake {
+                Wake::Received(Some(received)) => {
+                    let received = match inflate_ws_event(received, &mut inflater, self.encoding) {
+                        Ok(Some(json)) => GatewayEvent::decode(json),
+                        // a zlib-stream payload split over several frames
+                        Ok(None) => continue,
+                        // the websocket failed or the compression context is corrupt, nothing
+                        // after it can be read. the next connection gets a new inflater
+                        Err(err @ Error::Io(_)) => {
+                            runtime::log(&format!("{}", err));
+                            ws_sender.close_resumable();
+                            return SessionEnd::Closed(None);
+                        }
+                        Err(err) => Err(err),
+                    };
+                    match self
+                        .handle_event(received, &mut ws_sender, &mut heartbeat)
+                        .await
+                    {
+                        // the session is resumed next, a normal close would end it
+                        Some(end @ (SessionEnd::Reconnect | SessionEnd::InvalidSession(true))) => {
+                            ws_sender.close_resumable();
+                            return end;
+                        }
+                        Some(end) => return end,
+                        None => {}
+                    }
+                }
+                Wake::Received(None) => return SessionEnd::Closed(None),
+                Wake::Status(Some(Status::SendMessage(val))) => self.queue.push_back(val),
+                Wake::Status(Some(Status::RequestMembers {
+                    nonce,
+                    payload,
+                    reply,
+                })) => {
+                    let pending = PendingMembers {
+                        members: Vec::new(),
+                        not_found: Vec::new(),
+                        reply,
+                    };
+                    self.pending_members.insert(nonce, pending);
+                    self.queue.push_back(payload);
+                }
+                // the connection was dropped
+                Wake::Status(None) => {
+                    let _ = ws_sender.close().await;
+                    return SessionEnd::Aborted;
+                }
+                Wake::Timer => {}
+            }
+
+            if heartbeat.is_due() {
+                if heartbeat.awaiting_ack {
+                    // no ACK since the last heartbeat, the connection is dead even if still open
+                    runtime::log("heartbeat was not acknowledged, reconnecting");
+                    ws_sender.close_resumable();
+                    return SessionEnd::Reconnect;
                 }
-                Err(_) => {}
+                self.send_heartbeat(&mut ws_sender, &mut heartbeat).await;
             }
-            sleep(std::time::Duration::from_millis(100)).await;
         }
-    });
+    }
 
-    'outer: loop {
-        sleep(std::time::Duration::from_millis(100)).await;
+    /// every payload goes through here so it counts towards the send limit
+    async fn send(&mut self, ws_sender: &mut WsSender, payload: &Value) {
+        // a failed send shows up as the websocket closing
+        if let Err(err) = ws_sender.send(self.encoding.encode(payload)).await {
+            runtime::log(&format!("unable to send: {}", err));
+        }
+        if let Some(ref recorder) = self.recorder {
+            recorder.sent(payload, self.encoding, &self.token);
+        }
+        self.limit.sent();
+    }
 
-        loop {
-            match receiver.try_next() {
-                Ok(Some(Status::SendMessage(val))) => ws_sender
-                    .clone()
-                    .borrow_mut()
-                    .send(WsMessage::Text(val.to_string())),
-                Ok(Some(Status::Sequence(seq))) => last_sequence = seq,
-                Ok(Some(Status::ChangeInterval(new_interval))) => {
-                    interval = new_interval;
-                    next_tick_at =
-                        time::OffsetDateTime::now_utc().time().millisecond() + interval as u16;
+    async fn send_heartbeat(&mut self, ws_sender: &mut WsSender, heartbeat: &mut Heartbeat) {
+        let map = serde_json::json!({
+            "op": 1, // HEARTBEAT
+            "d": self.last_sequence,
+        });
+        self.send(ws_sender, &map).await;
+        heartbeat.sent();
+    }
+
+    /// sends queued commands as long as the send limit allows
+    async fn send_queued(&mut self, ws_sender: &mut WsSender) {
+        while self.limit.wait().is_zero() {
+            let Some(command) = self.queue.pop_front() else {
+                break;
+            };
+            self.send(ws_sender, &command).await;
+        }
+    }
+
+    /// adds a chunk to the member request it answers, replying once it is complete
+    fn collect_chunk(&mut self, chunk: &ServerMembersChunkEvent) {
+        let Some(ref nonce) = chunk.nonce else {
+            return;
+        };
+        let Some(pending) = self.pending_members.get_mut(nonce) else {
+            return;
+        };
+        pending.members.extend(chunk.members.iter().cloned());
+        pending.not_found.extend(chunk.not_found.iter().copied());
+        if chunk.chunk_index + 1 < chunk.chunk_count {
+            return;
+        }
+        if let Some(pending) = self.pending_members.remove(nonce) {
+            let members = ServerMembers {
+                members: pending.members,
+                not_found: pending.not_found,
+            };
+            // the request may have been given up on
+            let _ = pending.reply.send(members);
+        }
+    }
+
+    /// forwards a received payload, returns Some if the connection has to be reopened
+    async fn handle_event(
+        &mut self,
+        received: Result<GatewayEvent>,
+        ws_sender: &mut WsSender,
+        heartbeat: &mut Heartbeat,
+    ) -> Option<SessionEnd> {
+        match received {
+            Ok(GatewayEvent::Dispatch(sequence, event)) => {
+                self.last_sequence = Some(sequence);
+                match *event {
+                    Event::Ready(ref ready) => {
+                        self.session = Some(Session {
+                            session_id: ready.session_id.clone(),
+                            resume_gateway_url: ready.resume_gateway_url.clone(),
+                        });
+                        self.failed_attempts = 0;
+                    }
+                    Event::Resumed => self.failed_attempts = 0,
+                    Event::ServerMembersChunk(ref chunk) => self.collect_chunk(chunk),
+                    _ => {}
+                }
+                if self.sender.unbounded_send(Ok(*event)).is_err() {
+                    return Some(SessionEnd::Aborted);
+                }
+            }
+            Ok(GatewayEvent::DispatchError(sequence, err)) => {
+                self.last_sequence = Some(sequence);
+                if self.sender.unbounded_send(Err(err)).is_err() {
+                    return Some(SessionEnd::Aborted);
                 }
-                Ok(Some(Status::Aborted)) => break 'outer,
-                Ok(None) => break 'outer,
-                Err(_) => break,
             }
+            // the gateway can ask for a heartbeat at any time, it has to be answered right away
+            Ok(GatewayEvent::Heartbeat(_)) => self.send_heartbeat(ws_sender, heartbeat).await,
+            Ok(GatewayEvent::HeartbeatAck) => heartbeat.awaiting_ack = false,
+            Ok(GatewayEvent::Reconnect) => return Some(SessionEnd::Reconnect),
+            Ok(GatewayEvent::InvalidateSession(resumable)) => {
+                return Some(SessionEnd::InvalidSession(resumable))
+            }
+            Ok(GatewayEvent::Hello(_)) => {}
+            Err(Error::Gateway { close_code }) => {
+                runtime::log(&format!("gateway closed: {:?}", close_code));
+                return Some(SessionEnd::Closed(close_code));
+            }
+            Err(err) => {
+                if self.sender.unbounded_send(Err(err)).is_err() {
+                    return Some(SessionEnd::Aborted);
+                }
+            }
+        }
+        None
+    }
+}
+
+/// what the gateway task woke up for
+enum Wake {
+    Received(Option<WsEvent>),
+    Status(Option<Status>),
+    Timer,
+}
+
+/// heartbeat timing for a single websocket connection
+struct Heartbeat {
+    interval: Duration,
+    next_at: Instant,
+    /// a heartbeat was sent and the gateway has not acknowledged it yet
+    awaiting_ack: bool,
+}
+
+/// discord sends around 41 seconds, anything longer is clamped so timers stay in range
+const MAX_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+impl Heartbeat {
+    /// the first heartbeat is sent after interval * jitter, so clients don't all send at once
+    fn new(interval: usize) -> Result<Self, Error> {
+        if interval == 0 {
+            return Err(
+                Error::decode("hello has a heartbeat interval of 0").in_field("heartbeat_interval")
+            );
         }
+        let interval = u64::try_from(interval)
+            .map(Duration::from_millis)
+            .unwrap_or(MAX_HEARTBEAT_INTERVAL)
+            .min(MAX_HEARTBEAT_INTERVAL);
+        Ok(Self {
+            interval,
+            next_at: after(interval.mul_f64(random_fraction())),
+            awaiting_ack: false,
+        })
+    }
+
+    fn is_due(&self) -> bool {
+        Instant::now() >= self.next_at
+    }
+
+    fn sent(&mut self) {
+        self.next_at = after(self.interval);
+        self.awaiting_ack = true;
+    }
+}
+
+/// now + duration, at most MAX_HEARTBEAT_INTERVAL away
+fn after(duration: Duration) -> Instant {
+    let now = Instant::now();
+    now.checked_add(duration.min(MAX_HEARTBEAT_INTERVAL))
+        .unwrap_or(now)
+}
+
+/// a number in 0..1 for jittering heartbeats and reconnects, it does not need to be good
+fn random_fraction() -> f64 {
+    time::OffsetDateTime::now_utc().nanosecond() as f64 / 1_000_000_000.
+}
 
-        if time::OffsetDateTime::now_utc().time().millisecond() >= next_tick_at {
-            next_tick_at = time::OffsetDateTime::now_utc().time().millisecond() + interval as u16;
-            let map = serde_json::json!({
-                "op": 1,
-                "d": last_sequence
-            });
-            println!("heartbeat");
-            ws_sender
-                .clone()
-                .borrow_mut()
-                .send(WsMessage::Text(map.to_string()));
+/// the gateway closes connections that send more than 120 payloads in 60 seconds
+const COMMAND_LIMIT: usize = 120;
+const COMMAND_WINDOW: Duration = Duration::from_secs(60);
+/// kept free so heartbeats are never held back by commands
+const RESERVED_FOR_HEARTBEATS: usize = 5;
+
+/// payloads sent on one websocket in the last COMMAND_WINDOW
+#[derive(Default)]
+struct CommandLimit {
+    sent: VecDeque<Instant>,
+}
+
+impl CommandLimit {
+    /// how long until a command may be sent, zero if it can be sent now
+    fn wait(&mut self) -> Duration {
+        let now = Instant::now();
+        while self
+            .sent
+            .front()
+            .is_some_and(|&x| now.duration_since(x) >= COMMAND_WINDOW)
+        {
+            self.sent.pop_front();
+        }
+        if self.sent.len() < COMMAND_LIMIT - RESERVED_FOR_HEARTBEATS {
+            return Duration::ZERO;
         }
+        // the oldest sends free up first
+        let oldest = self.sent[self.sent.len() + RESERVED_FOR_HEARTBEATS - COMMAND_LIMIT];
+        COMMAND_WINDOW.saturating_sub(now.duration_since(oldest))
+    }
+
+    fn sent(&mut self) {
+        self.sent.push_back(Instant::now());
     }
 }
 
+/// chunks received so far for one member request
+struct PendingMembers {
+    members: Vec<Member>,
+    not_found: Vec<UserId>,
+    reply: oneshot::Sender<ServerMembers>,
+}
+
 enum Status {
     SendMessage(Value),
-    Sequence(usize),
-    ChangeInterval(usize),
-    Aborted,
+    RequestMembers {
+        nonce: String,
+        payload: Value,
+        reply: oneshot::Sender<ServerMembers>,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heartbeat_intervals_are_checked() {
+        assert!(matches!(Heartbeat::new(0), Err(Error::Decode { .. })));
+        let heartbeat = Heartbeat::new(41250).unwrap();
+        assert_eq!(heartbeat.interval, Duration::from_millis(41250));
+        assert!(heartbeat.next_at <= Instant::now() + heartbeat.interval);
+        let mut heartbeat = Heartbeat::new(usize::MAX).unwrap();
+        assert_eq!(heartbeat.interval, MAX_HEARTBEAT_INTERVAL);
+        heartbeat.sent();
+        assert!(heartbeat.awaiting_ack);
+    }
 }
@@ -0,0 +1,451 @@
+use std::io::Read;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Number, Value};
+
+use super::{websocket::WsMessage, Error, Result};
+
+const VERSION: u8 = 131;
+const NEW_FLOAT_EXT: u8 = 70;
+const COMPRESSED: u8 = 80;
+const SMALL_INTEGER_EXT: u8 = 97;
+const INTEGER_EXT: u8 = 98;
+const FLOAT_EXT: u8 = 99;
+const ATOM_EXT: u8 = 100;
+const SMALL_TUPLE_EXT: u8 = 104;
+const LARGE_TUPLE_EXT: u8 = 105;
+const NIL_EXT: u8 = 106;
+const STRING_EXT: u8 = 107;
+const LIST_EXT: u8 = 108;
+const BINARY_EXT: u8 = 109;
+const SMALL_BIG_EXT: u8 = 110;
+const LARGE_BIG_EXT: u8 = 111;
+const SMALL_ATOM_EXT: u8 = 115;
+const MAP_EXT: u8 = 116;
+const ATOM_UTF8_EXT: u8 = 118;
+const SMALL_ATOM_UTF8_EXT: u8 = 119;
+
+/// how deeply terms may nest before the payload is refused, discord's are far shallower
+const MAX_DEPTH: usize = 128;
+
+/// how gateway payloads are serialized on the websocket
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    #[default]
+    Json,
+    /// erlang term format, smaller and faster to decode than json
+    Etf,
+}
+
+impl Encoding {
+    /// the `encoding` query parameter of the gateway url
+    pub(crate) fn query(self) -> &'static str {
+        match self {
+            Encoding::Json => "json",
+            Encoding::Etf => "etf",
+        }
+    }
+
+    /// `payload` is a complete, already decompressed payload
+    pub(crate) fn decode(self, payload: &[u8]) -> Result<Value> {
+        match self {
+            Encoding::Json => Ok(serde_json::from_slice(payload)?),
+            Encoding::Etf => etf_to_json(payload),
+        }
+    }
+
+    pub(crate) fn encode(self, payload: &Value) -> WsMessage {
+        match self {
+            Encoding::Json => WsMessage::Text(payload.to_string()),
+            Encoding::Etf => WsMessage::Binary(json_to_etf(payload)),
+        }
+    }
+}
+
+/// atoms become strings, except nil/null and the booleans
+pub(crate) fn etf_to_json(payload: &[u8]) -> Result<Value> {
+    let mut reader = Reader {
+        input: payload,
+        depth: 0,
+    };
+    if reader.u8()? != VERSION {
+        return Err(Error::decode("etf payload without version byte"));
+    }
+    let value = reader.term()?;
+    if !reader.input.is_empty() {
+        return Err(Error::decode("trailing bytes after etf term"));
+    }
+    Ok(value)
+}
+
+/// strings are sent as binaries, which is what discord expects for keys too
+pub(crate) fn json_to_etf(value: &Value) -> Vec<u8> {
+    let mut output = vec![VERSION];
+    write_term(&mut output, value);
+    output
+}
+
+struct Reader<'a> {
+    input: &'a [u8],
+    /// how many terms are being read, to refuse payloads that would overflow the stack
+    depth: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.input.len() < len {
+            return Err(Error::decode("etf payload ended early"));
+        }
+        let (taken, rest) = self.input.split_at(len);
+        self.input = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<usize> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]) as usize)
+    }
+
+    fn u32(&mut self) -> Result<usize> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    }
+
+    fn string(&mut self, len: usize) -> Result<String> {
+        String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|e| Error::decode(format!("invalid utf-8 in etf: {}", e)))
+    }
+
+    fn term(&mut self) -> Result<Value> {
+        if self.depth == MAX_DEPTH {
+            return Err(Error::decode("etf terms nested too deeply"));
+        }
+        self.depth += 1;
+        let term = self.tagged_term();
+        self.depth -= 1;
+        term
+    }
+
+    fn tagged_term(&mut self) -> Result<Value> {
+        match self.u8()? {
+            SMALL_INTEGER_EXT => Ok(self.u8()?.into()),
+            INTEGER_EXT => {
+                let bytes = self.take(4)?;
+                Ok(i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]).into())
+            }
+            NEW_FLOAT_EXT => {
+                let bytes = self.take(8)?;
+                let float = f64::from_be_bytes(bytes.try_into().expect("8 bytes"));
+                Ok(Number::from_f64(float).map_or(Value::Null, Value::Number))
+            }
+            FLOAT_EXT => {
+                let text = self.string(31)?;
+                let float = text
+                    .trim_end_matches('\0')
+                    .parse::<f64>()
+                    .map_err(|e| Error::decode(format!("invalid etf float: {}", e)))?;
+                Ok(Number::from_f64(float).map_or(Value::Null, Value::Number))
+            }
+            SMALL_BIG_EXT => {
+                let len = self.u8()? as usize;
+                self.big(len)
+            }
+            LARGE_BIG_EXT => {
+                let len = self.u32()?;
+                self.big(len)
+            }
+            ATOM_EXT | ATOM_UTF8_EXT => {
+                let len = self.u16()?;
+                self.atom(len)
+            }
+            SMALL_ATOM_EXT | SMALL_ATOM_UTF8_EXT => {
+                let len = self.u8()? as usize;
+                self.atom(len)
+            }
+            SMALL_TUPLE_EXT => {
+                let len = self.u8()? as usize;
+                self.array(len)
+            }
+            LARGE_TUPLE_EXT => {
+                let len = self.u32()?;
+                self.array(len)
+            }
+            NIL_EXT => Ok(Value::Array(Vec::new())),
+            // a list of small integers, erlang's idea of a string
+            STRING_EXT => {
+                let len = self.u16()?;
+                Ok(self.take(len)?.iter().map(|&x| Value::from(x)).collect())
+            }
+            LIST_EXT => {
+                let len = self.u32()?;
+                let list = self.array(len)?;
+                // proper lists end with an empty list
+                match self.term()? {
+                    Value::Array(tail) if tail.is_empty() => Ok(list),
+                    _ => Err(Error::decode("improper etf list")),
+                }
+            }
+            BINARY_EXT => {
+                let len = self.u32()?;
+                self.string(len).map(Value::String)
+            }
+            MAP_EXT => {
+                let len = self.u32()?;
+                let mut map = Map::new();
+                for _ in 0..len {
+                    let key = match self.term()? {
+                        Value::String(key) => key,
+                        Value::Number(key) => key.to_string(),
+                        other => return Err(Error::decode(format!("etf map key {}", other))),
+                    };
+                    let value = self.term()?;
+                    map.insert(key, value);
+                }
+                Ok(Value::Object(map))
+            }
+            COMPRESSED => {
+                let len = self.u32()?;
+                // the length is untrusted, like in `array`, and inflating stops right after it
+                // so a small payload can't take all the memory
+                let mut inflated = Vec::with_capacity(len.min(self.input.len()));
+                flate2::read::ZlibDecoder::new(self.input)
+                    .take(len as u64 + 1)
+                    .read_to_end(&mut inflated)
+                    .map_err(|e| Error::decode(format!("unable to decompress etf: {}", e)))?;
+                if inflated.len() != len {
+                    return Err(Error::decode("compressed etf term has the wrong length"));
+                }
+                self.input = &[];
+                let mut reader = Reader {
+                    input: &inflated,
+                    depth: self.depth,
+                };
+                reader.term()
+            }
+            tag => Err(Error::decode(format!("unsupported etf tag {}", tag))),
+        }
+    }
+
+    /// snowflakes arrive as bigs, anything wider than 64 bits is refused
+    fn big(&mut self, len: usize) -> Result<Value> {
+        let negative = self.u8()? != 0;
+        let digits = self.take(len)?;
+        if digits.len() > 8 {
+            return Err(Error::decode("etf integer does not fit in 64 bits"));
+        }
+        let magnitude = digits
+            .iter()
+            .rev()
+            .fold(0u64, |acc, &x| (acc << 8) | x as u64);
+        if !negative {
+            return Ok(magnitude.into());
+        }
+        0i64.checked_sub_unsigned(magnitude)
+            .map(Value::from)
+            .ok_or_else(|| Error::decode("etf integer does not fit in 64 bits"))
+    }
+
+    fn atom(&mut self, len: usize) -> Result<Value> {
+        Ok(match self.string(len)?.as_str() {
+            "nil" | "null" => Value::Null,
+            "true" => Value::Bool(true),
+            "false" => Value::Bool(false),
+            other => Value::String(other.to_owned()),
+        })
+    }
+
+    fn array(&mut self, len: usize) -> Result<Value> {
+        // the length is untrusted, so don't preallocate all of it
+        let mut array = Vec::with_capacity(len.min(self.input.len()));
+        for _ in 0..len {
+            array.push(self.term()?);
+        }
+        Ok(Value::Array(array))
+    }
+}
+
+fn write_term(output: &mut Vec<u8>, value: &Value) {
+    match *value {
+        Value::Null => write_atom(output, "nil"),
+        Value::Bool(true) => write_atom(output, "true"),
+        Value::Bool(false) => write_atom(output, "false"),
+        Value::Number(ref number) => {
+            if let Some(small) = number.as_u64().and_then(|x| u8::try_from(x).ok()) {
+                output.extend([SMALL_INTEGER_EXT, small]);
+            } else if let Some(integer) = number.as_i64().and_then(|x| i32::try_from(x).ok()) {
+                output.push(INTEGER_EXT);
+                output.extend(integer.to_be_bytes());
+            } else if let Some(unsigned) = number.as_u64() {
+                write_big(output, false, unsigned);
+            } else if let Some(signed) = number.as_i64() {
+                write_big(output, true, signed.unsigned_abs());
+            } else {
+                output.push(NEW_FLOAT_EXT);
+                output.extend(number.as_f64().unwrap_or_default().to_be_bytes());
+            }
+        }
+        Value::String(ref string) => write_binary(output, string),
+        Value::Array(ref array) if array.is_empty() => output.push(NIL_EXT),
+        Value::Array(ref array) => {
+            output.push(LIST_EXT);
+            output.extend((array.len() as u32).to_be_bytes());
+            for item in array {
+                write_term(output, item);
+            }
+            output.push(NIL_EXT);
+        }
+        Value::Object(ref map) => {
+            output.push(MAP_EXT);
+            output.extend((map.len() as u32).to_be_bytes());
+            for (key, value) in map {
+                write_binary(output, key);
+                write_term(output, value);
+            }
+        }
+    }
+}
+
+fn write_atom(output: &mut Vec<u8>, atom: &str) {
+    output.extend([SMALL_ATOM_UTF8_EXT, atom.len() as u8]);
+    output.extend(atom.as_bytes());
+}
+
+fn write_binary(output: &mut Vec<u8>, string: &str) {
+    output.push(BINARY_EXT);
+    output.extend((string.len() as u32).to_be_bytes());
+    output.extend(string.as_bytes());
+}
+
+fn write_big(output: &mut Vec<u8>, negative: bool, magnitude: u64) {
+    let digits = magnitude.to_le_bytes();
+    let len = 8 - magnitude.leading_zeros() as usize / 8;
+    output.extend([SMALL_BIG_EXT, len as u8, negative as u8]);
+    output.extend(&digits[..len]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{
+        model::{Event, GatewayEvent, MessageId},
+        test_util::hex,
+    };
+
+    /// HELLO and MESSAGE_DELETE as sent by the gateway with `encoding=etf`, keys are atoms
+    const HELLO: &str = "83740000000477017477036e696c77017377036e696c77026f70610a7701647400\
+        00000277126865617274626561745f696e74657276616c620000a12277065f74726163656c0000\
+        00016d0000002e5b22676174657761792d7072642d75732d65617374312d622d30353638222c7b\
+        226d6963726f73223a302e307d5d6a";
+    const MESSAGE_DELETE: &str = "837400000004770174770e4d4553534147455f44454c455445770173\
+        610577026f7061007701647400000003770269646e0800c771d8e69b003212770a6368616e6e65\
+        6c5f69646e0800070002c15a06710277086775696c645f69646e0800002080c008232101";
+    /// HELLO again, as a zlib compressed term
+    const COMPRESSED_HELLO: &str = "83500000007d789c258a4b0ac2301040932a2e5c7a84593b252e14\
+        f12a45caa41d34927e48a60e221ec69b1a70f3dee23d31c6acd58aaec610d5e6bfab69a6adda5e\
+        4aad7477674ae299a40da3707a52f4c67c4137ad24ea3896cb0e0575033712567ae19c7a5c3232\
+        6539a047773c9d61ff86217469ca7071b5fb5c1f3ff576252b";
+
+    #[test]
+    fn decodes_hello() {
+        let value = Encoding::Etf.decode(&hex(HELLO)).unwrap();
+        assert_eq!(value["t"], Value::Null);
+        assert_eq!(value["d"]["heartbeat_interval"], 41250);
+        assert!(matches!(
+            GatewayEvent::decode(value),
+            Ok(GatewayEvent::Hello(41250))
+        ));
+    }
+
+    #[test]
+    fn decodes_snowflakes_from_bigs() {
+        let value = Encoding::Etf.decode(&hex(MESSAGE_DELETE)).unwrap();
+        let event = match GatewayEvent::decode(value) {
+            Ok(GatewayEvent::Dispatch(5, event)) => *event,
+            other => panic!("expected a dispatch, got {:?}", other),
+        };
+        match event {
+            Event::MessageDelete(event) => {
+                assert_eq!(event.id, MessageId(1311111111111111111));
+                assert_eq!(event.channel_id.0, 175928847299117063);
+                assert_eq!(event.server_id.map(|x| x.0), Some(81384788765712384));
+            }
+            other => panic!("expected MESSAGE_DELETE, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn decodes_compressed_terms() {
+        assert_eq!(
+            etf_to_json(&hex(COMPRESSED_HELLO)).unwrap(),
+            etf_to_json(&hex(HELLO)).unwrap()
+        );
+    }
+
+    #[test]
+    fn recorded_payloads_round_trip() {
+        for payload in [HELLO, MESSAGE_DELETE, COMPRESSED_HELLO] {
+            let value = etf_to_json(&hex(payload)).unwrap();
+            assert_eq!(etf_to_json(&json_to_etf(&value)).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn identify_round_trips() {
+        let identify = serde_json::json!({
+            "op": 2,
+            "d": {
+                "token": "token",
+                "properties": { "$os": "linux", "$browser": "discidium", "$device": "discidium" },
+                "large_threshold": 250,
+                "compress": false,
+                "presence": { "since": null, "activities": [], "afk": false },
+                "numbers": [0, 255, 256, -1, i32::MIN, u32::MAX, i64::MIN, u64::MAX, 1.5],
+            },
+        });
+        let etf = json_to_etf(&identify);
+        assert_eq!(etf_to_json(&etf).unwrap(), identify);
+        assert_eq!(
+            Encoding::Json
+                .decode(identify.to_string().as_bytes())
+                .unwrap(),
+            identify
+        );
+    }
+
+    #[test]
+    fn refuses_wrong_compressed_lengths() {
+        let payload = hex(COMPRESSED_HELLO);
+        let len = u32::from_be_bytes(payload[2..6].try_into().unwrap());
+        for wrong in [0, len - 1, len + 1, u32::MAX] {
+            let mut payload = payload.clone();
+            payload[2..6].copy_from_slice(&wrong.to_be_bytes());
+            assert!(etf_to_json(&payload).is_err(), "{}", wrong);
+        }
+    }
+
+    #[test]
+    fn refuses_deeply_nested_terms() {
+        let nested = |depth: usize| {
+            let mut payload = vec![VERSION];
+            payload.extend([SMALL_TUPLE_EXT, 1].repeat(depth));
+            payload.push(NIL_EXT);
+            etf_to_json(&payload)
+        };
+        assert!(nested(MAX_DEPTH - 1).is_ok());
+        assert!(nested(MAX_DEPTH).is_err());
+        assert!(nested(1_000_000).is_err());
+    }
+
+    #[test]
+    fn refuses_truncated_payloads() {
+        let payload = hex(MESSAGE_DELETE);
+        for len in 0..payload.len() {
+            assert!(etf_to_json(&payload[..len]).is_err());
+        }
+    }
+}
@@ -0,0 +1,462 @@
+use std::{collections::BTreeMap, fmt, time::Duration};
+
+use serde_json::Value;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// everything that can go wrong talking to discord
+#[derive(Debug)]
+pub enum Error {
+    /// discord answered with an error status, `code` is one of discord's json error codes
+    Http {
+        status: u16,
+        code: Option<u64>,
+        message: String,
+        errors: FieldErrors,
+    },
+    /// still ratelimited after retrying
+    RateLimited { retry_after: Duration },
+    /// something received did not have the expected shape
+    Decode {
+        /// the type being decoded, None if it failed before reaching one
+        type_name: Option<&'static str>,
+        /// the field that was missing or invalid
+        field: Option<String>,
+        /// where in the received json, e.g. `guilds[3].channels[0].type`
+        path: String,
+        reason: String,
+    },
+    /// the gateway closed the connection, `close_code` is None if it closed without one
+    Gateway { close_code: Option<CloseCode> },
+    /// the token was rejected
+    Auth,
+    /// the request or websocket failed before discord answered, or the gateway's compressed
+    /// stream could not be decompressed
+    Io(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl Error {
+    /// reads discord's `{"code", "message", "errors"}` body, falling back to the raw body
+    pub(crate) fn http(status: u16, body: &str) -> Self {
+        let Ok(Value::Object(mut map)) = serde_json::from_str::<Value>(body) else {
+            return Error::Http {
+                status,
+                code: None,
+                message: body.to_owned(),
+                errors: FieldErrors::default(),
+            };
+        };
+        Error::Http {
+            status,
+            code: map.get("code").and_then(Value::as_u64),
+            message: match map.remove("message") {
+                Some(Value::String(message)) => message,
+                _ => body.to_owned(),
+            },
+            errors: map
+                .remove("errors")
+                .map(FieldErrors::decode)
+                .unwrap_or_default(),
+        }
+    }
+
+    /// discord's json error code, e.g. 50013 for missing permissions
+    pub fn code(&self) -> Option<u64> {
+        match *self {
+            Error::Http { code, .. } => code,
+            _ => None,
+        }
+    }
+
+    pub(crate) fn decode(reason: impl Into<String>) -> Self {
+        Error::Decode {
+            type_name: None,
+            field: None,
+            path: String::new(),
+            reason: reason.into(),
+        }
+    }
+
+    /// records the key the error happened under, called from the inside out
+    pub(crate) fn in_field(mut self, key: &str) -> Self {
+        if let Error::Decode {
+            ref mut field,
+            ref mut path,
+            ..
+        } = self
+        {
+            field.get_or_insert_with(|| key.to_owned());
+            *path = match path.chars().next() {
+                None => key.to_owned(),
+                Some('[') => format!("{}{}", key, path),
+                Some(_) => format!("{}.{}", key, path),
+            };
+        }
+        self
+    }
+
+    /// only the innermost type is kept
+    pub(crate) fn in_type<T>(mut self) -> Self {
+        if let Error::Decode {
+            ref mut type_name, ..
+        } = self
+        {
+            type_name.get_or_insert_with(|| {
+                let name = std::any::type_name::<T>();
+                name.rsplit("::").next().unwrap_or(name)
+            });
+        }
+        self
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Http {
+                status,
+                code,
+                ref message,
+                ..
+            } => match code {
+                Some(code) => write!(f, "http {}: {} ({})", status, message, code),
+                None => write!(f, "http {}: {}", status, message),
+            },
+            Error::RateLimited { retry_after } => {
+                write!(f, "ratelimited, retry after {:?}", retry_after)
+            }
+            Error::Decode {
+                type_name,
+                ref path,
+                ref reason,
+                ..
+            } => write!(
+                f,
+                "unable to decode {} at `{}`: {}",
+                type_name.unwrap_or("value"),
+                path,
+                reason
+            ),
+            Error::Gateway {
+                close_code: Some(close_code),
+            } => write!(f, "gateway closed with {}", close_code),
+            Error::Gateway { close_code: None } => f.write_str("gateway closed"),
+            Error::Auth => f.write_str("invalid token"),
+            Error::Io(ref err) => write!(f, "io error: {}", err),
+        }
+    }
+}
+
+/// why the gateway closed the websocket
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseCode {
+    UnknownError,
+    UnknownOpcode,
+    DecodeError,
+    /// a payload was sent before IDENTIFY
+    NotAuthenticated,
+    /// the token is invalid
+    AuthenticationFailed,
+    AlreadyAuthenticated,
+    /// the sequence sent when resuming is invalid
+    InvalidSequence,
+    /// too many payloads were sent
+    RateLimited,
+    SessionTimedOut,
+    InvalidShard,
+    ShardingRequired,
+    InvalidApiVersion,
+    InvalidIntents,
+    DisallowedIntents,
+    Other(u16),
+}
+
+/// what to do after the gateway closed the websocket
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reconnect {
+    /// reconnect and RESUME the session
+    Resume,
+    /// reconnect with a new session
+    Identify,
+    /// retrying won't help, give up on the connection
+    Stop,
+}
+
+impl CloseCode {
+    pub fn reconnect(self) -> Reconnect {
+        match self {
+            CloseCode::NotAuthenticated
+            | CloseCode::InvalidSequence
+            | CloseCode::SessionTimedOut => Reconnect::Identify,
+            CloseCode::AuthenticationFailed
+            | CloseCode::InvalidShard
+            | CloseCode::ShardingRequired
+            | CloseCode::InvalidApiVersion
+            | CloseCode::InvalidIntents
+            | CloseCode::DisallowedIntents => Reconnect::Stop,
+            _ => Reconnect::Resume,
+        }
+    }
+}
+
+impl From<u16> for CloseCode {
+    fn from(code: u16) -> Self {
+        match code {
+            4000 => CloseCode::UnknownError,
+            4001 => CloseCode::UnknownOpcode,
+            4002 => CloseCode::DecodeError,
+            4003 => CloseCode::NotAuthenticated,
+            4004 => CloseCode::AuthenticationFailed,
+            4005 => CloseCode::AlreadyAuthenticated,
+            4007 => CloseCode::InvalidSequence,
+            4008 => CloseCode::RateLimited,
+            4009 => CloseCode::SessionTimedOut,
+            4010 => CloseCode::InvalidShard,
+            4011 => CloseCode::ShardingRequired,
+            4012 => CloseCode::InvalidApiVersion,
+            4013 => CloseCode::InvalidIntents,
+            4014 => CloseCode::DisallowedIntents,
+            other => CloseCode::Other(other),
+        }
+    }
+}
+
+impl From<CloseCode> for u16 {
+    fn from(code: CloseCode) -> Self {
+        match code {
+            CloseCode::UnknownError => 4000,
+            CloseCode::UnknownOpcode => 4001,
+            CloseCode::DecodeError => 4002,
+            CloseCode::NotAuthenticated => 4003,
+            CloseCode::AuthenticationFailed => 4004,
+            CloseCode::AlreadyAuthenticated => 4005,
+            CloseCode::InvalidSequence => 4007,
+            CloseCode::RateLimited => 4008,
+            CloseCode::SessionTimedOut => 4009,
+            CloseCode::InvalidShard => 4010,
+            CloseCode::ShardingRequired => 4011,
+            CloseCode::InvalidApiVersion => 4012,
+            CloseCode::InvalidIntents => 4013,
+            CloseCode::DisallowedIntents => 4014,
+            CloseCode::Other(other) => other,
+        }
+    }
+}
+
+impl fmt::Display for CloseCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CloseCode::Other(code) => write!(f, "{}", code),
+            known => write!(f, "{} ({:?})", u16::from(known), known),
+        }
+    }
+}
+
+/// an invalid token is reported as [`Error::Auth`] like it is for http requests
+impl From<CloseCode> for Error {
+    fn from(code: CloseCode) -> Self {
+        match code {
+            CloseCode::AuthenticationFailed => Error::Auth,
+            code => Error::Gateway {
+                close_code: Some(code),
+            },
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            Error::Io(ref err) => Some(&**err),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Self {
+        if err.is_decode() {
+            return Error::decode(err.to_string());
+        }
+        Error::Io(Box::new(err))
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::decode(err.to_string())
+    }
+}
+
+impl From<chrono::ParseError> for Error {
+    fn from(err: chrono::ParseError) -> Self {
+        Error::decode(err.to_string())
+    }
+}
+
+impl From<std::num::ParseIntError> for Error {
+    fn from(err: std::num::ParseIntError) -> Self {
+        Error::decode(err.to_string())
+    }
+}
+
+/// what discord did not like about the fields of a request body, nested like the body itself
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FieldErrors {
+    /// errors for this field itself
+    pub errors: Vec<FieldError>,
+    /// keyed by field name, or by index for arrays
+    pub fields: BTreeMap<String, FieldErrors>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldError {
+    /// e.g. `BASE_TYPE_MAX_LENGTH`
+    pub code: String,
+    pub message: String,
+}
+
+impl FieldErrors {
+    fn decode(value: Value) -> Self {
+        let mut errors = FieldErrors::default();
+        let Value::Object(map) = value else {
+            return errors;
+        };
+        for (key, value) in map {
+            if key == "_errors" {
+                errors.errors = match value {
+                    Value::Array(array) => array.iter().filter_map(FieldError::decode).collect(),
+                    _ => Vec::new(),
+                };
+            } else {
+                errors.fields.insert(key, FieldErrors::decode(value));
+            }
+        }
+        errors
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty() && self.fields.is_empty()
+    }
+
+    /// the errors of a nested field, `path` like `embeds.0.title`
+    pub fn get(&self, path: &str) -> Option<&FieldErrors> {
+        path.split('.')
+            .try_fold(self, |errors, key| errors.fields.get(key))
+    }
+
+    /// every error with the path of the field it is for, for highlighting form fields
+    pub fn flatten(&self) -> Vec<(String, &FieldError)> {
+        let mut flattened = Vec::new();
+        self.flatten_into(String::new(), &mut flattened);
+        flattened
+    }
+
+    fn flatten_into<'a>(&'a self, path: String, flattened: &mut Vec<(String, &'a FieldError)>) {
+        flattened.extend(self.errors.iter().map(|x| (path.clone(), x)));
+        for (key, errors) in &self.fields {
+            let path = match path.is_empty() {
+                true => key.clone(),
+                false => format!("{}.{}", path, key),
+            };
+            errors.flatten_into(path, flattened);
+        }
+    }
+}
+
+impl FieldError {
+    fn decode(value: &Value) -> Option<Self> {
+        Some(Self {
+            code: value.get("code")?.as_str()?.to_owned(),
+            message: value.get("message")?.as_str()?.to_owned(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_nested_field_errors() {
+        let body = r#"{
+            "code": 50035,
+            "message": "Invalid Form Body",
+            "errors": {
+                "content": {"_errors": [
+                    {"code": "BASE_TYPE_MAX_LENGTH", "message": "Must be 2000 or fewer in length."}
+                ]},
+                "embeds": {"0": {"title": {"_errors": [
+                    {"code": "BASE_TYPE_REQUIRED", "message": "This field is required"},
+                    {"code": "BASE_TYPE_MAX_LENGTH", "message": "Must be 256 or fewer in length."}
+                ]}}}
+            }
+        }"#;
+        let err = Error::http(400, body);
+        assert_eq!(err.code(), Some(50035));
+        let Error::Http {
+            status,
+            ref message,
+            ref errors,
+            ..
+        } = err
+        else {
+            panic!("expected an http error, got {:?}", err);
+        };
+        assert_eq!(status, 400);
+        assert_eq!(message, "Invalid Form Body");
+        assert!(errors.errors.is_empty());
+        assert_eq!(errors.get("embeds.0.title").unwrap().errors.len(), 2);
+        assert!(errors.get("embeds.1").is_none());
+
+        let flattened = errors
+            .flatten()
+            .into_iter()
+            .map(|(path, x)| (path, x.code.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            flattened,
+            [
+                ("content".to_owned(), "BASE_TYPE_MAX_LENGTH"),
+                ("embeds.0.title".to_owned(), "BASE_TYPE_REQUIRED"),
+                ("embeds.0.title".to_owned(), "BASE_TYPE_MAX_LENGTH"),
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_bodies_that_are_not_json() {
+        let err = Error::http(502, "Bad Gateway");
+        assert_eq!(err.code(), None);
+        assert!(matches!(err, Error::Http { ref message, ref errors, .. }
+            if message == "Bad Gateway" && errors.is_empty()));
+    }
+
+    #[test]
+    fn close_codes_decide_how_to_reconnect() {
+        let expected = [
+            (1000, Reconnect::Resume),
+            (1006, Reconnect::Resume),
+            (4000, Reconnect::Resume),
+            (4001, Reconnect::Resume),
+            (4002, Reconnect::Resume),
+            (4003, Reconnect::Identify),
+            (4004, Reconnect::Stop),
+            (4005, Reconnect::Resume),
+            (4007, Reconnect::Identify),
+            (4008, Reconnect::Resume),
+            (4009, Reconnect::Identify),
+            (4010, Reconnect::Stop),
+            (4011, Reconnect::Stop),
+            (4012, Reconnect::Stop),
+            (4013, Reconnect::Stop),
+            (4014, Reconnect::Stop),
+            (4015, Reconnect::Resume),
+        ];
+        for (code, reconnect) in expected {
+            let close_code = CloseCode::from(code);
+            assert_eq!(u16::from(close_code), code);
+            assert_eq!(close_code.reconnect(), reconnect, "close code {}", code);
+        }
+    }
+}
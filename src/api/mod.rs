@@ -1,15 +1,29 @@
 pub mod client;
+mod compression;
 pub mod connection;
+mod encoding;
+mod error;
 pub mod model;
+pub mod permissions;
 mod ratelimit;
+mod recording;
+mod runtime;
 pub mod state;
-// #[cfg(feature = "web")]
-// mod websocket;
+#[cfg(test)]
+mod test_util;
+mod websocket;
 
-pub use connection::Connection;
+pub use compression::Compression;
+pub use connection::{Connection, MemberQuery, ServerMembers, ServerSubscription};
+pub use encoding::Encoding;
+pub use error::{CloseCode, Error, FieldError, FieldErrors, Reconnect, Result};
 pub use model::*;
+pub use recording::{Recorder, Replay};
 pub use state::State;
 
+/// gateway and rest api version, keep in sync with ENDPOINT_URL
+const API_VERSION: u8 = 9;
+/// the default of [`Client`](client::Client), see [`Client::with_urls`](client::Client::with_urls)
 const ENDPOINT_URL: &str = "https://discord.com/api/v9/";
 const CDN_URL: &str = "https://cdn.discordapp.com/";
 const USER_AGENT: &str = "discidium";
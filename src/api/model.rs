@@ -1,171 +1,543 @@
-#![allow(dead_code, unused)] // TODO :(
-
-use std::io::Read;
-
-use anyhow::{Error, Result};
-use chrono::{DateTime, FixedOffset};
-use ewebsock::{connect, Options, WsEvent, WsMessage, WsReceiver, WsSender};
-use serde_json::{Map, Value};
-use yew::platform::time::sleep;
-
-use super::CDN_URL;
-
-#[derive(Debug, Clone)]
-pub struct WrappedMap(Map<String, Value>);
-
-impl WrappedMap {
-    fn get<T, F: FnOnce(WrappedMap) -> Result<T>>(
-        &mut self,
-        key: &str,
-        decode: F,
-    ) -> Option<Result<T>> {
-        let value = self.0.remove(key)?;
-        if value.is_null() {
-            return None;
+use std::{cell::Cell, collections::BTreeSet, sync::Mutex};
+
+use chrono::{DateTime, FixedOffset, Utc};
+use futures::{Stream, StreamExt};
+use serde::{
+    de::{self, DeserializeOwned},
+    ser, Deserialize, Deserializer, Serialize, Serializer,
+};
+use serde_json::Value;
+
+use super::{
+    compression::Inflater,
+    encoding::Encoding,
+    error::{CloseCode, Error, Result},
+    permissions::Permissions,
+    runtime,
+    websocket::{WsEvent, WsMessage},
+    CDN_URL,
+};
+
+thread_local! {
+    /// unknown fields and enum values are errors instead of being logged, on by default in tests.
+    /// per thread, so tests running in parallel don't change each other's decoding
+    static STRICT: Cell<bool> = const { Cell::new(cfg!(test)) };
+}
+/// what has been logged as unknown already, so each is only logged once
+static LOGGED_UNKNOWN: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+/// fields discord sends that are not decoded on purpose, for a type and the types in its fields.
+/// types decoded from a buffered value, like servers and channels, have their own
+pub(crate) struct Ignored {
+    fields: &'static [&'static str],
+    /// keyed by the name of the field the type is in
+    nested: &'static [(&'static str, &'static Ignored)],
+}
+
+impl Ignored {
+    pub(crate) const NONE: Ignored = Ignored {
+        fields: &[],
+        nested: &[],
+    };
+
+    /// `path` leads to the field, without array indices
+    fn contains(&self, path: &[&str]) -> bool {
+        match path {
+            [] => false,
+            [field] => self.fields.contains(field),
+            [first, rest @ ..] => self
+                .nested
+                .iter()
+                .any(|(name, nested)| name == first && nested.contains(rest)),
         }
-        Some(WrappedValue(value).to_decoder(decode))
     }
+}
 
-    fn get_value<T, F: FnOnce(WrappedValue) -> Result<T>>(
-        &mut self,
-        key: &str,
-        decode: F,
-    ) -> Option<Result<T>> {
-        let value = self.0.remove(key)?;
-        if value.is_null() {
-            return None;
-        }
-        Some(WrappedValue(value).to_value_decoder(decode))
+pub(crate) static IGNORED_IN_READY: Ignored = Ignored {
+    fields: &[
+        "_trace",
+        "analytics_token",
+        "api_code_version",
+        "auth",
+        "auth_session_id_hash",
+        "broadcaster_user_ids",
+        "connected_accounts",
+        "consents",
+        "country_code",
+        "experiments",
+        "explicit_content_scan_version",
+        "friend_suggestion_count",
+        "game_relationships",
+        "geo_ordered_rtc_regions",
+        "guild_experiments",
+        "guild_join_requests",
+        "notes",
+        "notification_settings",
+        "read_state",
+        "session_type",
+        "sessions",
+        "static_client_session_id",
+        "tutorial",
+        "user_guild_settings",
+        "user_settings",
+        "user_settings_proto",
+    ],
+    nested: &[("presences", &IGNORED_IN_PRESENCE)],
+};
+
+static IGNORED_IN_SERVER: Ignored = Ignored {
+    fields: &[
+        "application_command_counts",
+        "application_id",
+        "clan",
+        "discovery_splash",
+        "embedded_activities",
+        "guild_scheduled_events",
+        "home_header",
+        "hub_type",
+        "incidents_data",
+        "inventory_settings",
+        "latest_onboarding_question_id",
+        "lazy",
+        "max_members",
+        "max_stage_video_channel_users",
+        "max_video_channel_users",
+        "nsfw",
+        "premium_progress_bar_enabled",
+        "presences",
+        "region",
+        "safety_alerts_channel_id",
+        "soundboard_sounds",
+        "stage_instances",
+        "unavailable",
+        "version",
+        "voice_states",
+    ],
+    nested: &[
+        ("members", &IGNORED_IN_MEMBER),
+        ("roles", &IGNORED_IN_ROLE),
+        ("stickers", &IGNORED_IN_STICKER),
+    ],
+};
+
+static IGNORED_IN_ROLE: Ignored = Ignored {
+    fields: &["colors", "tags", "version"],
+    nested: &[],
+};
+
+static IGNORED_IN_STICKER: Ignored = Ignored {
+    fields: &["asset", "type", "user"],
+    nested: &[],
+};
+
+static IGNORED_IN_MEMBER: Ignored = Ignored {
+    fields: &[
+        "avatar_decoration_data",
+        "banner",
+        "unusual_dm_activity_until",
+    ],
+    nested: &[],
+};
+
+/// every channel but threads
+static IGNORED_IN_CHANNEL: Ignored = Ignored {
+    fields: &[],
+    nested: &[("permission_overwrites", &IGNORED_IN_OVERWRITE)],
+};
+
+static IGNORED_IN_OVERWRITE: Ignored = Ignored {
+    fields: &["allow_new", "deny_new"],
+    nested: &[],
+};
+
+static IGNORED_IN_THREAD: Ignored = Ignored {
+    fields: &["applied_tags", "member", "member_ids_preview"],
+    nested: &[],
+};
+
+pub(crate) static IGNORED_IN_MESSAGE: Ignored = Ignored {
+    fields: &["components"],
+    nested: &[
+        ("attachments", &IGNORED_IN_ATTACHMENT),
+        ("embeds", &IGNORED_IN_EMBED),
+        ("member", &IGNORED_IN_MEMBER),
+        ("reactions", &IGNORED_IN_REACTION),
+        ("referenced_message", &IGNORED_IN_MESSAGE),
+    ],
+};
+
+static IGNORED_IN_ATTACHMENT: Ignored = Ignored {
+    fields: &["content_scan_version", "placeholder", "placeholder_version"],
+    nested: &[],
+};
+
+static IGNORED_IN_EMBED: Ignored = Ignored {
+    fields: &["content_scan_version", "reference_id"],
+    nested: &[
+        ("image", &IGNORED_IN_EMBED_MEDIA),
+        ("thumbnail", &IGNORED_IN_EMBED_MEDIA),
+        ("video", &IGNORED_IN_EMBED_MEDIA),
+    ],
+};
+
+static IGNORED_IN_EMBED_MEDIA: Ignored = Ignored {
+    fields: &[
+        "content_type",
+        "flags",
+        "placeholder",
+        "placeholder_version",
+    ],
+    nested: &[],
+};
+
+static IGNORED_IN_REACTION: Ignored = Ignored {
+    fields: &["count_details"],
+    nested: &[],
+};
+
+static IGNORED_IN_PRESENCE: Ignored = Ignored {
+    fields: &[],
+    nested: &[("activities", &IGNORED_IN_ACTIVITY)],
+};
+
+static IGNORED_IN_ACTIVITY: Ignored = Ignored {
+    fields: &[],
+    // only the name of the emoji is kept
+    nested: &[(
+        "emoji",
+        &Ignored {
+            fields: &["animated", "id"],
+            nested: &[],
+        },
+    )],
+};
+
+static IGNORED_IN_RELATIONSHIP_REMOVE: Ignored = Ignored {
+    fields: &["nickname", "since"],
+    nested: &[],
+};
+
+static IGNORED_IN_LAST_MESSAGES: Ignored = Ignored {
+    fields: &[],
+    nested: &[("messages", &IGNORED_IN_MESSAGE)],
+};
+
+static IGNORED_IN_ROLE_EVENT: Ignored = Ignored {
+    fields: &[],
+    nested: &[("role", &IGNORED_IN_ROLE)],
+};
+
+static IGNORED_IN_STICKERS_EVENT: Ignored = Ignored {
+    fields: &[],
+    nested: &[("stickers", &IGNORED_IN_STICKER)],
+};
+
+static IGNORED_IN_MEMBERS_CHUNK: Ignored = Ignored {
+    fields: &[],
+    nested: &[("members", &IGNORED_IN_MEMBER)],
+};
+
+/// makes unknown fields and enum values an error on the current thread,
+/// to catch what discord added in tests
+pub fn set_strict_decoding(strict: bool) {
+    STRICT.set(strict);
+}
+
+/// logs something discord sent that is not known yet, or errors in strict mode
+fn unknown(what: String) -> Result<()> {
+    if STRICT.get() {
+        return Err(Error::decode(what));
+    }
+    let first_time = LOGGED_UNKNOWN
+        .lock()
+        .map(|mut x| x.insert(what.clone()))
+        .unwrap_or(false);
+    if first_time {
+        runtime::log(&what);
     }
+    Ok(())
+}
 
-    fn get_array<T, F: Clone + Fn(WrappedMap) -> Result<T>>(
-        &mut self,
-        key: &str,
-        decode: F,
-    ) -> Option<Result<Vec<T>>> {
-        let value = self.0.remove(key)?;
-        if value.is_null() {
-            return None;
+/// decodes json received from discord, fields not in `T` or `ignored` are reported
+/// through [`unknown`]
+pub(crate) fn decode_json<T: DeserializeOwned>(json: &str, ignored: &Ignored) -> Result<T> {
+    let mut deserializer = serde_json::Deserializer::from_str(json);
+    let decoded = decode_with(&mut deserializer, Some(ignored))?;
+    deserializer.end()?;
+    Ok(decoded)
+}
+
+pub(crate) fn decode_value<T: DeserializeOwned>(value: Value, ignored: &Ignored) -> Result<T> {
+    decode_with(value, Some(ignored))
+}
+
+/// for objects that may contain anything, like a partial MESSAGE_UPDATE
+fn decode_partial_value<T: DeserializeOwned>(value: Value) -> Result<T> {
+    decode_with(value, None)
+}
+
+/// unknown fields are not reported without `expected`
+fn decode_with<'de, T, D>(deserializer: D, expected: Option<&Ignored>) -> Result<T>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    let mut unused = Vec::new();
+    let mut callback = |path: serde_ignored::Path| unused.push(path.to_string());
+    let deserializer = serde_ignored::Deserializer::new(deserializer, &mut callback);
+    let decoded = serde_path_to_error::deserialize(deserializer).map_err(|err| {
+        let path = match err.path().iter().next() {
+            Some(_) => err.path().to_string(),
+            None => String::new(),
+        };
+        let reason = err.into_inner().to_string();
+        // serde reports a missing field at the object containing it
+        let field = reason
+            .strip_prefix("missing field `")
+            .and_then(|x| x.split('`').next())
+            .or_else(|| {
+                path.rsplit('.')
+                    .next()
+                    .map(|x| x.split('[').next().unwrap_or(x))
+            })
+            .map(str::to_owned)
+            .filter(|x| !x.is_empty());
+        Error::Decode {
+            type_name: None,
+            field,
+            path,
+            reason,
+        }
+        .in_type::<T>()
+    })?;
+    if let Some(ignored) = expected {
+        for path in unused {
+            // array indices are left out so a field is logged once, not once per element
+            let path = path
+                .split('.')
+                .filter(|x| *x != "?" && x.parse::<usize>().is_err())
+                .collect::<Vec<_>>();
+            let Some(&field) = path.last() else {
+                continue;
+            };
+            if !ignored.contains(&path) {
+                let name = std::any::type_name::<T>();
+                let name = name.rsplit("::").next().unwrap_or(name);
+                let path = path.join(".");
+                unknown(format!("unknown field {:?} in {}", path, name)).map_err(|e| {
+                    match e {
+                        Error::Decode { reason, .. } => Error::Decode {
+                            type_name: None,
+                            field: Some(field.to_owned()),
+                            path,
+                            reason,
+                        },
+                        other => other,
+                    }
+                    .in_type::<T>()
+                })?;
+            }
         }
-        Some(WrappedValue(value).to_array_decoder(decode))
     }
+    Ok(decoded)
+}
+
+/// decodes a value that was buffered to look at it first, like a channel for its type
+fn decode_buffered<T: DeserializeOwned, E: de::Error>(
+    value: Value,
+    ignored: &Ignored,
+) -> Result<T, E> {
+    decode_value(value, ignored).map_err(E::custom)
+}
+
+/// discord sends numbers that don't fit in a javascript number as strings,
+/// older gateway versions send some of them as numbers
+enum NumberOrString {
+    Number(u64),
+    String(String),
+}
+
+impl<'de> Deserialize<'de> for NumberOrString {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl de::Visitor<'_> for Visitor {
+            type Value = NumberOrString;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a number or a string")
+            }
+
+            fn visit_u64<E: de::Error>(self, value: u64) -> Result<Self::Value, E> {
+                Ok(NumberOrString::Number(value))
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+                Ok(NumberOrString::String(value.to_owned()))
+            }
 
-    fn get_array_value<T, F: Clone + Fn(WrappedValue) -> Result<T>>(
-        &mut self,
-        key: &str,
-        decode: F,
-    ) -> Option<Result<Vec<T>>> {
-        let value = self.0.remove(key)?;
-        if value.is_null() {
-            return None;
+            fn visit_string<E: de::Error>(self, value: String) -> Result<Self::Value, E> {
+                Ok(NumberOrString::String(value))
+            }
         }
-        Some(WrappedValue(value).to_array_value_decoder(decode))
+
+        deserializer.deserialize_any(Visitor)
     }
+}
 
-    /// type_name is used in the panic message
-    fn check_empty_panic(self, type_name: &str) {
-        if !self.0.is_empty() {
-            // panic!("value not taken out of {}: {:?}", type_name, self.0);
-            println!("value not taken out of {}: {:?}", type_name, self.0);
-        }
+pub(crate) fn u64_or_string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    match NumberOrString::deserialize(deserializer)? {
+        NumberOrString::Number(number) => Ok(number),
+        NumberOrString::String(string) => string.parse().map_err(de::Error::custom),
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct WrappedValue(Value);
+/// ids are snowflakes sent as strings
+mod snowflake {
+    use serde::{Deserializer, Serializer};
 
-impl WrappedValue {
-    fn to_string(self) -> Result<String> {
-        if !self.0.is_string() {
-            return Err(Error::msg(format!(
-                "{:?} is not a string",
-                self.0.to_string()
-            )));
-        }
-        Ok(self.0.as_str().unwrap().to_string())
+    pub fn serialize<S: Serializer>(id: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(id)
     }
 
-    fn to_bool(self) -> Result<bool> {
-        if !self.0.is_boolean() {
-            return Err(Error::msg(format!(
-                "{:?} is not a bool",
-                self.0.to_string()
-            )));
-        }
-        Ok(self.0.as_bool().unwrap())
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+        super::u64_or_string(deserializer)
     }
+}
 
-    fn to_u64(self) -> Result<u64> {
-        if !self.0.is_u64() {
-            return Err(Error::msg(format!("{:?} is not a u64", self.0.to_string())));
-        }
-        Ok(self.0.as_u64().unwrap())
-    }
+/// the discriminator is a string of 4 digits, "0" for users with a unique username
+mod discriminator {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
 
-    fn to_decoder<T, F: FnOnce(WrappedMap) -> Result<T>>(self, decode: F) -> Result<T> {
-        if !self.0.is_object() {
-            return Err(Error::msg(format!(
-                "{:?} is not an object",
-                self.0.to_string()
-            )));
+    pub fn serialize<S: Serializer>(discriminator: &u16, serializer: S) -> Result<S::Ok, S::Error> {
+        match *discriminator {
+            0 => serializer.serialize_str("0"),
+            discriminator => serializer.collect_str(&format_args!("{:04}", discriminator)),
         }
-        decode(self.to_map()?)
     }
 
-    fn to_value_decoder<T, F: FnOnce(WrappedValue) -> Result<T>>(self, decode: F) -> Result<T> {
-        decode(self)
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u16, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(Error::custom)
     }
+}
+
+/// for fields with a default, null is treated the same as missing
+fn nullable<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
 
-    fn to_map(self) -> Result<WrappedMap> {
-        if !self.0.is_object() {
-            return Err(Error::msg(format!(
-                "{:?} is not an object",
-                self.0.to_string()
-            )));
+fn nullable_true<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    Ok(Option::<bool>::deserialize(deserializer)?.unwrap_or(true))
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// an enum sent as a number, values that are not known yet end up in `Unknown`
+macro_rules! number_enum {
+    (
+        $(#[$meta:meta])*
+        pub enum $name:ident ($what:literal) {
+            $($variant:ident = $value:literal,)*
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Copy, Clone, Hash, Eq, PartialEq, Debug, Serialize, Deserialize)]
+        #[serde(try_from = "u64", into = "u64")]
+        pub enum $name {
+            $($variant,)*
+            Unknown(u64),
         }
-        Ok(WrappedMap(self.0.as_object().unwrap().clone()))
-    }
 
-    fn to_array_decoder<T, F: Clone + Fn(WrappedMap) -> Result<T>>(
-        self,
-        decode: F,
-    ) -> Result<Vec<T>> {
-        if !self.0.is_array() {
-            return Err(Error::msg(format!(
-                "{:?} is not an array",
-                self.0.to_string()
-            )));
+        impl TryFrom<u64> for $name {
+            type Error = Error;
+
+            fn try_from(value: u64) -> Result<Self> {
+                Ok(match value {
+                    $($value => $name::$variant,)*
+                    other => {
+                        unknown(format!(concat!("unknown ", $what, " {}"), other))?;
+                        $name::Unknown(other)
+                    }
+                })
+            }
         }
-        self.0
-            .as_array()
-            .unwrap()
-            .iter()
-            .map(|x| WrappedValue(x.clone()).to_decoder(decode.clone()))
-            .collect::<Result<Vec<_>>>()
-    }
 
-    fn to_array_value_decoder<T, F: Clone + Fn(WrappedValue) -> Result<T>>(
-        self,
-        decode: F,
-    ) -> Result<Vec<T>> {
-        if !self.0.is_array() {
-            return Err(Error::msg(format!(
-                "{:?} is not an array",
-                self.0.to_string()
-            )));
+        impl From<$name> for u64 {
+            fn from(value: $name) -> u64 {
+                match value {
+                    $($name::$variant => $value,)*
+                    $name::Unknown(other) => other,
+                }
+            }
         }
-        self.0
-            .as_array()
-            .unwrap()
-            .iter()
-            .map(|x| WrappedValue(x.clone()).to_value_decoder(decode.clone()))
-            .collect::<Result<Vec<_>>>()
-    }
+    };
+}
+
+/// the first millisecond of 2015, snowflakes count their timestamp from there
+pub const DISCORD_EPOCH: u64 = 1_420_070_400_000;
+
+/// an id newtype around a snowflake, which encodes when it was created
+macro_rules! snowflake_id {
+    ($(#[$meta:meta])* $name:ident) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, Hash, PartialEq, PartialOrd, Ord, Eq, Debug, Serialize, Deserialize)]
+        pub struct $name(#[serde(with = "snowflake")] pub u64);
+
+        impl $name {
+            /// the smallest id created at `time`, for `before` and `after` cursors
+            pub fn from_timestamp(time: DateTime<Utc>) -> Self {
+                let millis = u64::try_from(time.timestamp_millis()).unwrap_or(0);
+                Self(millis.saturating_sub(DISCORD_EPOCH) << 22)
+            }
+
+            pub fn created_at(self) -> DateTime<Utc> {
+                let millis = (self.0 >> 22) + DISCORD_EPOCH;
+                DateTime::from_timestamp_millis(millis as i64).unwrap_or_default()
+            }
+
+            /// the internal worker that generated the id
+            pub fn worker_id(self) -> u8 {
+                ((self.0 >> 17) & 0x1f) as u8
+            }
+
+            /// the internal process that generated the id
+            pub fn process_id(self) -> u8 {
+                ((self.0 >> 12) & 0x1f) as u8
+            }
+
+            /// counts up for every id generated on the process
+            pub fn increment(self) -> u16 {
+                (self.0 & 0xfff) as u16
+            }
+        }
+
+        impl std::fmt::Display for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                std::fmt::Display::fmt(&self.0, f)
+            }
+        }
+
+        impl std::str::FromStr for $name {
+            type Err = std::num::ParseIntError;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                s.parse().map(Self)
+            }
+        }
+    };
 }
 
 pub struct Mention {
     prefix: &'static str,
-    id: usize,
+    id: u64,
 }
 
 impl std::fmt::Display for Mention {
@@ -176,14 +548,9 @@ impl std::fmt::Display for Mention {
     }
 }
 
-#[derive(Clone, Copy, Hash, PartialEq, PartialOrd, Ord, Eq, Debug)]
-pub struct UserId(pub usize);
+snowflake_id!(UserId);
 
 impl UserId {
-    fn decode(value: WrappedValue) -> Result<Self> {
-        Ok(Self(value.to_string()?.parse::<usize>()?))
-    }
-
     #[inline(always)]
     pub fn mention(&self) -> Mention {
         Mention {
@@ -193,14 +560,9 @@ impl UserId {
     }
 }
 
-#[derive(Clone, Copy, Hash, PartialEq, PartialOrd, Ord, Eq, Debug)]
-pub struct ChannelId(pub usize);
+snowflake_id!(ChannelId);
 
 impl ChannelId {
-    fn decode(value: WrappedValue) -> Result<Self> {
-        Ok(Self(value.to_string()?.parse::<usize>()?))
-    }
-
     #[inline(always)]
     pub fn mention(&self) -> Mention {
         Mention {
@@ -210,45 +572,21 @@ impl ChannelId {
     }
 }
 
-#[derive(Clone, Copy, Hash, PartialEq, PartialOrd, Ord, Eq, Debug)]
-pub struct ApplicationId(pub usize);
-
-impl ApplicationId {
-    fn decode(value: WrappedValue) -> Result<Self> {
-        Ok(Self(value.to_string()?.parse::<usize>()?))
-    }
-}
+snowflake_id!(ApplicationId);
 
-#[derive(Clone, Copy, Hash, PartialEq, PartialOrd, Ord, Eq, Debug)]
-pub struct ServerId(pub usize);
+snowflake_id!(ServerId);
 
 impl ServerId {
-    fn decode(value: WrappedValue) -> Result<Self> {
-        Ok(Self(value.to_string()?.parse::<usize>()?))
-    }
-
     pub fn everyone(self) -> RoleId {
         RoleId(self.0)
     }
 }
 
-#[derive(Clone, Copy, Hash, PartialEq, PartialOrd, Ord, Eq, Debug)]
-pub struct MessageId(pub usize);
+snowflake_id!(MessageId);
 
-impl MessageId {
-    fn decode(value: WrappedValue) -> Result<Self> {
-        Ok(Self(value.to_string()?.parse::<usize>()?))
-    }
-}
-
-#[derive(Clone, Copy, Hash, PartialEq, PartialOrd, Ord, Eq, Debug)]
-pub struct RoleId(pub usize);
+snowflake_id!(RoleId);
 
 impl RoleId {
-    fn decode(value: WrappedValue) -> Result<Self> {
-        Ok(Self(value.to_string()?.parse::<usize>()?))
-    }
-
     #[inline(always)]
     pub fn mention(&self) -> Mention {
         Mention {
@@ -258,27 +596,35 @@ impl RoleId {
     }
 }
 
-#[derive(Clone, Copy, Hash, PartialEq, PartialOrd, Ord, Eq, Debug)]
-pub struct EmojiId(pub usize);
+snowflake_id!(EmojiId);
 
-impl EmojiId {
-    fn decode(value: WrappedValue) -> Result<Self> {
-        Ok(Self(value.to_string()?.parse::<usize>()?))
-    }
-}
+snowflake_id!(StickerId);
 
-#[derive(Debug, Clone, PartialEq)]
+snowflake_id!(AttachmentId);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(from = "EmojiName", into = "EmojiName")]
 pub struct Emoji(pub String);
 
-impl Emoji {
-    fn decode(mut map: WrappedMap) -> Result<Self> {
-        Ok(Self(
-            map.get_value("name", WrappedValue::to_string).unwrap()?,
-        ))
+/// only the name of an activity emoji is kept
+#[derive(Serialize, Deserialize)]
+struct EmojiName {
+    name: String,
+}
+
+impl From<EmojiName> for Emoji {
+    fn from(emoji: EmojiName) -> Self {
+        Self(emoji.name)
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+impl From<Emoji> for EmojiName {
+    fn from(emoji: Emoji) -> Self {
+        Self { name: emoji.0 }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ChannelCategory {
     pub flags: u64,
     pub id: ChannelId,
@@ -289,381 +635,323 @@ pub struct ChannelCategory {
     pub version: u64,
 }
 
-impl ChannelCategory {
-    fn decode(mut map: WrappedMap) -> Result<Self> {
-        let flags = map.get_value("flags", WrappedValue::to_u64).unwrap()?;
-        let id = map.get_value("id", ChannelId::decode).unwrap()?;
-        let name = map.get_value("name", WrappedValue::to_string).unwrap()?;
-        let parent_id = map.get_value("parent_id", ChannelId::decode).transpose()?;
-        let permission_overwrites = map
-            .get_array("permission_overwrites", PermissionOverwrite::decode)
-            .unwrap()?;
-        let position = map.get_value("position", WrappedValue::to_u64).unwrap()?;
-        let version = map.get_value("version", WrappedValue::to_u64).unwrap()?;
-        map.check_empty_panic("ChannelCategory");
-        Ok(Self {
-            flags,
-            id,
-            name,
-            parent_id,
-            permission_overwrites,
-            position,
-            version,
-        })
-    }
-}
-
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "RawPermissionOverwrite", into = "RawPermissionOverwrite")]
 pub enum PermissionOverwrite {
     Member(PermissionOverwriteMember),
     Role(PermissionOverwriteRole),
+    /// never applied
+    Unknown(u64),
 }
 
 impl PermissionOverwrite {
-    pub fn decode(mut map: WrappedMap) -> Result<Self> {
-        Ok(
-            match map
-                .get_value("type", WrappedValue::to_string)
-                .unwrap()?
-                .as_str()
-            {
-                "member" => PermissionOverwrite::Member(PermissionOverwriteMember::decode(map)?),
-                "role" => PermissionOverwrite::Role(PermissionOverwriteRole::decode(map)?),
-                other => todo!("{:?}", other),
-            },
-        )
+    pub fn allow(&self) -> Permissions {
+        match *self {
+            PermissionOverwrite::Member(ref overwrite) => overwrite.allow,
+            PermissionOverwrite::Role(ref overwrite) => overwrite.allow,
+            PermissionOverwrite::Unknown(_) => Permissions::empty(),
+        }
+    }
+
+    pub fn deny(&self) -> Permissions {
+        match *self {
+            PermissionOverwrite::Member(ref overwrite) => overwrite.deny,
+            PermissionOverwrite::Role(ref overwrite) => overwrite.deny,
+            PermissionOverwrite::Unknown(_) => Permissions::empty(),
+        }
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
-pub struct PermissionOverwriteMember {
-    allow: Vec<Permission>,
-    allow_new: Vec<Permission>,
-    deny: Vec<Permission>,
-    deny_new: Vec<Permission>,
-    id: UserId,
-}
-
-impl PermissionOverwriteMember {
-    fn decode(mut map: WrappedMap) -> Result<Self> {
-        let allow = map.get_value("allow", Permission::decode).unwrap()?;
-        let allow_new = map.get_value("allow_new", Permission::decode).unwrap()?;
-        let deny = map.get_value("deny", Permission::decode).unwrap()?;
-        let deny_new = map.get_value("deny_new", Permission::decode).unwrap()?;
-        let id = map.get_value("id", UserId::decode).unwrap()?;
-        map.check_empty_panic("PermissionOverwriteMember");
-        Ok(Self {
-            allow,
-            allow_new,
-            deny,
-            deny_new,
-            id,
+/// an overwrite as sent, the id is a role or a user depending on the type
+#[derive(Serialize, Deserialize)]
+struct RawPermissionOverwrite {
+    allow: Permissions,
+    deny: Permissions,
+    #[serde(with = "snowflake")]
+    id: u64,
+    #[serde(rename = "type", deserialize_with = "overwrite_type")]
+    kind: u64,
+}
+
+/// the type of an overwrite sent as a name that isn't known, it has no number
+const UNKNOWN_OVERWRITE_NAME: u64 = u64::MAX;
+
+/// older gateway versions send the type as a string
+fn overwrite_type<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    match NumberOrString::deserialize(deserializer)? {
+        NumberOrString::Number(kind) => Ok(kind),
+        NumberOrString::String(kind) => match kind.as_str() {
+            "role" => Ok(0),
+            "member" => Ok(1),
+            other => {
+                unknown(format!("unknown overwrite type {:?}", other))
+                    .map_err(de::Error::custom)?;
+                Ok(UNKNOWN_OVERWRITE_NAME)
+            }
+        },
+    }
+}
+
+impl TryFrom<RawPermissionOverwrite> for PermissionOverwrite {
+    type Error = Error;
+
+    fn try_from(raw: RawPermissionOverwrite) -> Result<Self> {
+        Ok(match raw.kind {
+            0 => PermissionOverwrite::Role(PermissionOverwriteRole {
+                allow: raw.allow,
+                deny: raw.deny,
+                id: RoleId(raw.id),
+            }),
+            1 => PermissionOverwrite::Member(PermissionOverwriteMember {
+                allow: raw.allow,
+                deny: raw.deny,
+                id: UserId(raw.id),
+            }),
+            // already reported when decoding the name
+            UNKNOWN_OVERWRITE_NAME => PermissionOverwrite::Unknown(UNKNOWN_OVERWRITE_NAME),
+            other => {
+                unknown(format!("unknown overwrite type {}", other))?;
+                PermissionOverwrite::Unknown(other)
+            }
         })
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
-pub struct PermissionOverwriteRole {
-    allow: Vec<Permission>,
-    allow_new: Vec<Permission>,
-    deny: Vec<Permission>,
-    deny_new: Vec<Permission>,
-    id: RoleId,
-}
-
-impl PermissionOverwriteRole {
-    fn decode(mut map: WrappedMap) -> Result<Self> {
-        let allow = map.get_value("allow", Permission::decode).unwrap()?;
-        let allow_new = map.get_value("allow_new", Permission::decode).unwrap()?;
-        let deny = map.get_value("deny", Permission::decode).unwrap()?;
-        let deny_new = map.get_value("deny_new", Permission::decode).unwrap()?;
-        let id = map.get_value("id", RoleId::decode).unwrap()?;
-        map.check_empty_panic("PermissionOverwriteMember");
-        Ok(Self {
-            allow,
-            allow_new,
-            deny,
-            deny_new,
+impl From<PermissionOverwrite> for RawPermissionOverwrite {
+    fn from(overwrite: PermissionOverwrite) -> Self {
+        let (kind, id) = match overwrite {
+            PermissionOverwrite::Role(ref overwrite) => (0, overwrite.id.0),
+            PermissionOverwrite::Member(ref overwrite) => (1, overwrite.id.0),
+            PermissionOverwrite::Unknown(kind) => (kind, 0),
+        };
+        Self {
+            allow: overwrite.allow(),
+            deny: overwrite.deny(),
             id,
-        })
+            kind,
+        }
     }
 }
 
 #[derive(Debug, Clone, PartialEq)]
-pub enum Permission {
-    ViewChannels,
-    ManageChannels,
-    ManageRoles,
-    CreateExpressions,
-    ManageExpressinos,
-    ViewAuditLog,
-    ManagePermissions,
-    ManageWebhooks,
-    ManageServer,
-    CreateInvite,
-    ChangeNickname,
-    ManageNicknames,
-    KickMembers,
-    BanMembers,
-    TimeoutMembers,
-    SendMessages,
-    SendMessagesThreads,
-    CreatePublicThreads,
-    CreatePrivateThreads,
-    EmbedLinks,
-    AttachFiles,
-    AddReactions,
-    UseExternalEmoji,
-    UseExternalStickers,
-    MentionEveryone,
-    ManageMessages,
-    ManageThreads,
-    ReadMessageHistory,
-    SendTTSMessages,
-    SendVoiceMessages,
-    CreatePolls,
-    Connect,
-    Speak,
-    Video,
-    UseSoundboard,
-    UseExternalSounds,
-    UseVoiceActivity,
-    PrioritySpeaker,
-    MuteMembers,
-    DeafenMembers,
-    MoveMembers,
-    SetVoiceChannelStatus,
-    UseApplicationCommands,
-    UseActivities,
-    UseExternalApps,
-    CreateEvents,
-    ManageEvents,
-    Administrator,
-}
-
-impl Permission {
-    fn decode(value: WrappedValue) -> Result<Vec<Self>> {
-        let mut value = value
-            .clone()
-            .to_u64()
-            .unwrap_or_else(|_| value.to_string().unwrap().parse().unwrap());
-        let mut vec = vec![];
-        if value >= 2_u64.pow(50) {
-            vec.push(Self::UseExternalApps);
-            value -= 2_u64.pow(50);
-        }
-        if value >= 2_u64.pow(49) {
-            vec.push(Self::CreatePolls);
-            value -= 2_u64.pow(49);
-        }
-        if value >= 2_u64.pow(48) {
-            vec.push(Self::SetVoiceChannelStatus);
-            value -= 2_u64.pow(48);
-        }
-        if value >= 2_u64.pow(46) {
-            vec.push(Self::SendVoiceMessages);
-            value -= 2_u64.pow(46);
-        }
-        if value >= 2_u64.pow(45) {
-            vec.push(Self::UseExternalSounds);
-            value -= 2_u64.pow(45);
-        }
-        if value >= 2_u64.pow(44) {
-            vec.push(Self::CreateEvents);
-            value -= 2_u64.pow(44);
-        }
-        if value >= 2_u64.pow(42) {
-            vec.push(Self::UseSoundboard);
-            value -= 2_u64.pow(42);
-        }
-        if value >= 2_u64.pow(39) {
-            vec.push(Self::UseActivities);
-            value -= 2_u64.pow(39);
-        }
-        if value >= 2_u64.pow(38) {
-            vec.push(Self::SendMessagesThreads);
-            value -= 2_u64.pow(38);
-        }
-        if value >= 2_u64.pow(37) {
-            vec.push(Self::UseExternalStickers);
-            value -= 2_u64.pow(37);
-        }
-        if value >= 2_u64.pow(36) {
-            vec.push(Self::CreatePrivateThreads);
-            value -= 2_u64.pow(36);
-        }
-        if value >= 2_u64.pow(35) {
-            vec.push(Self::CreatePublicThreads);
-            value -= 2_u64.pow(35);
-        }
-        if value >= 2_u64.pow(34) {
-            vec.push(Self::ManageThreads);
-            value -= 2_u64.pow(34);
-        }
-        if value >= 2_u64.pow(33) {
-            vec.push(Self::ManageEvents);
-            value -= 2_u64.pow(33);
-        }
-        if value >= 2_u64.pow(31) {
-            vec.push(Self::UseApplicationCommands);
-            value -= 2_u64.pow(31);
-        }
-        if value >= 2_u64.pow(29) {
-            vec.push(Self::ManageWebhooks);
-            value -= 2_u64.pow(29);
-        }
-        if value >= 2_u64.pow(28) {
-            vec.push(Self::ManagePermissions);
-            value -= 2_u64.pow(28);
-        }
-        if value >= 2_u64.pow(25) {
-            vec.push(Self::UseVoiceActivity);
-            value -= 2_u64.pow(25);
-        }
-        if value >= 2_u64.pow(24) {
-            vec.push(Self::MoveMembers);
-            value -= 2_u64.pow(24);
-        }
-        if value >= 2_u64.pow(23) {
-            vec.push(Self::DeafenMembers);
-            value -= 2_u64.pow(23);
-        }
-        if value >= 2_u64.pow(22) {
-            vec.push(Self::MuteMembers);
-            value -= 2_u64.pow(22);
-        }
-        if value >= 2_u64.pow(21) {
-            vec.push(Self::Speak);
-            value -= 2_u64.pow(21);
-        }
-        if value >= 2_u64.pow(20) {
-            vec.push(Self::Connect);
-            value -= 2_u64.pow(20);
-        }
-        if value >= 2_u64.pow(18) {
-            vec.push(Self::UseExternalEmoji);
-            value -= 2_u64.pow(18);
-        }
-        if value >= 2_u64.pow(17) {
-            vec.push(Self::MentionEveryone);
-            value -= 2_u64.pow(17);
-        }
-        if value >= 2_u64.pow(16) {
-            vec.push(Self::ReadMessageHistory);
-            value -= 2_u64.pow(16);
-        }
-        if value >= 2_u64.pow(15) {
-            vec.push(Self::AttachFiles);
-            value -= 2_u64.pow(15);
-        }
-        if value >= 2_u64.pow(14) {
-            vec.push(Self::EmbedLinks);
-            value -= 2_u64.pow(14);
-        }
-        if value >= 2_u64.pow(13) {
-            vec.push(Self::ManageMessages);
-            value -= 2_u64.pow(13);
-        }
-        if value >= 2_u64.pow(12) {
-            vec.push(Self::SendTTSMessages);
-            value -= 2_u64.pow(12);
-        }
-        if value >= 2_u64.pow(11) {
-            vec.push(Self::SendMessages);
-            value -= 2_u64.pow(11);
-        }
-        if value >= 2_u64.pow(10) {
-            vec.push(Self::ViewChannels);
-            value -= 2_u64.pow(10);
-        }
-        if value >= 2_u64.pow(9) {
-            vec.push(Self::Video);
-            value -= 2_u64.pow(9);
-        }
-        if value >= 2_u64.pow(8) {
-            vec.push(Self::PrioritySpeaker);
-            value -= 2_u64.pow(8);
-        }
-        if value >= 2_u64.pow(6) {
-            vec.push(Self::AddReactions);
-            value -= 2_u64.pow(6);
-        }
-        if value >= 2_u64.pow(4) {
-            vec.push(Self::ManageChannels);
-            value -= 2_u64.pow(4);
+pub struct PermissionOverwriteMember {
+    pub allow: Permissions,
+    pub deny: Permissions,
+    pub id: UserId,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PermissionOverwriteRole {
+    pub allow: Permissions,
+    pub deny: Permissions,
+    pub id: RoleId,
+}
+
+/// servers can be unavailable during an outage, in which case only the id is sent
+#[derive(Debug, Clone, PartialEq)]
+pub enum PossibleServer {
+    Online(Box<Server>),
+    Offline(ServerId),
+}
+
+impl PossibleServer {
+    pub fn id(&self) -> ServerId {
+        match *self {
+            PossibleServer::Online(ref server) => server.id,
+            PossibleServer::Offline(id) => id,
         }
-        if value >= 1 {
-            vec.push(Self::CreateInvite);
-            value -= 1;
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct UnavailableServer {
+    id: ServerId,
+    unavailable: bool,
+}
+
+impl<'de> Deserialize<'de> for PossibleServer {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = Value::deserialize(deserializer)?;
+        if value.get("unavailable").and_then(Value::as_bool) == Some(true) {
+            let server = decode_buffered::<UnavailableServer, _>(value, &Ignored::NONE)?;
+            return Ok(PossibleServer::Offline(server.id));
         }
-        if value != 0 {
-            panic!("error decoding permission, {:?}", value)
+        Ok(PossibleServer::Online(decode_buffered(
+            value,
+            &IGNORED_IN_SERVER,
+        )?))
+    }
+}
+
+impl Serialize for PossibleServer {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match *self {
+            PossibleServer::Online(ref server) => server.serialize(serializer),
+            PossibleServer::Offline(id) => UnavailableServer {
+                id,
+                unavailable: true,
+            }
+            .serialize(serializer),
         }
-        Ok(vec)
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Server {
-    afk_channel_id: Option<ChannelId>,
-    afk_timeout: u64,
-    channels: Vec<Channel>,
+    pub afk_channel_id: Option<ChannelId>,
+    pub afk_timeout: u64,
+    pub banner: Option<String>,
+    /// GUILD_UPDATE leaves out channels, members and threads
+    #[serde(default, deserialize_with = "nullable")]
+    pub channels: Vec<Channel>,
+    pub default_message_notifications: u64,
+    pub description: Option<String>,
+    pub emojis: Vec<ServerEmoji>,
+    pub explicit_content_filter: u64,
+    pub features: Vec<String>,
+    pub icon: Option<String>,
+    pub id: ServerId,
+    pub joined_at: Option<DateTime<FixedOffset>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub large: bool,
+    pub member_count: Option<u64>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub members: Vec<Member>,
+    pub mfa_level: u64,
+    pub name: String,
+    pub nsfw_level: u64,
+    pub owner_id: UserId,
+    pub preferred_locale: String,
+    pub premium_subscription_count: Option<u64>,
+    pub premium_tier: PremiumTier,
+    pub public_updates_channel_id: Option<ChannelId>,
+    pub roles: Vec<Role>,
+    pub rules_channel_id: Option<ChannelId>,
+    pub splash: Option<String>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub stickers: Vec<Sticker>,
+    pub system_channel_flags: u64,
+    pub system_channel_id: Option<ChannelId>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub threads: Vec<Channel>,
+    pub vanity_url_code: Option<String>,
+    pub verification_level: VerificationLevel,
 }
 
 impl Server {
-    fn decode(mut map: WrappedMap) -> Result<Self> {
-        let afk_channel_id = map
-            .get_value("afk_channel_id", ChannelId::decode)
-            .transpose()?;
-        let afk_timeout = map
-            .get_value("afk_timeout", WrappedValue::to_u64)
-            .unwrap()?;
-        let channels = map.get_array("channels", Channel::decode).unwrap()?;
-        map.check_empty_panic("Server");
-        Ok(Self {
-            afk_channel_id,
-            afk_timeout,
-            channels,
-        })
+    pub fn icon_url(&self) -> Option<String> {
+        self.icon
+            .as_ref()
+            .map(|x| format!("{}icons/{}/{}.jpg", CDN_URL, self.id.0, x))
+    }
+
+    pub fn role(&self, id: RoleId) -> Option<&Role> {
+        self.roles.iter().find(|x| x.id == id)
     }
 
-    // pub fn icon_url(&self) -> Option<String> {
-    //     self.icon
-    //         .as_ref()
-    //         .map(|x| format!("{}/icons/{}/{}.jpg", CDN_URL, self.id.0, x))
-    // }
+    pub fn member(&self, id: UserId) -> Option<&Member> {
+        self.members
+            .iter()
+            .find(|x| x.user.as_ref().map(|x| x.id) == Some(id))
+    }
 }
 
+number_enum! {
+    pub enum VerificationLevel ("verification level") {
+        None = 0,
+        Low = 1,
+        Medium = 2,
+        High = 3,
+        VeryHigh = 4,
+    }
+}
+
+number_enum! {
+    pub enum PremiumTier ("premium tier") {
+        None = 0,
+        Tier1 = 1,
+        Tier2 = 2,
+        Tier3 = 3,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Role {
-    // pub id: RoleId,
-    // pub name: String,
-    // /// Color in 0xRRGGBB form
-    // pub color: usize,
-    // pub hoist: bool,
-    // pub managed: bool,
-    // pub position: isize,
-    // pub mentionable: bool,
-    // pub permissions: Permissions,
+    /// Color in 0xRRGGBB form
+    pub color: u64,
+    pub description: Option<String>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub flags: u64,
+    pub hoist: bool,
+    pub icon: Option<String>,
+    pub id: RoleId,
+    pub managed: bool,
+    pub mentionable: bool,
+    pub name: String,
+    pub permissions: Permissions,
+    pub position: u64,
+    pub unicode_emoji: Option<String>,
 }
 
 impl Role {
-    // #[inline(always)]
-    // pub fn mention(&self) -> Mention {
-    //     self.id.mention()
-    // }
+    #[inline(always)]
+    pub fn mention(&self) -> Mention {
+        self.id.mention()
+    }
 }
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Ban {
-    // reason: Option<String>,
-    // user: User,
+    pub reason: Option<String>,
+    pub user: User,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+/// a custom emoji uploaded to a server
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerEmoji {
+    #[serde(default, deserialize_with = "nullable")]
+    pub animated: bool,
+    #[serde(default = "default_true", deserialize_with = "nullable_true")]
+    pub available: bool,
+    pub id: EmojiId,
+    #[serde(default, deserialize_with = "nullable")]
+    pub managed: bool,
+    pub name: String,
+    #[serde(default = "default_true", deserialize_with = "nullable_true")]
+    pub require_colons: bool,
+    /// if not empty, only these roles can use the emoji
+    #[serde(default, deserialize_with = "nullable")]
+    pub roles: Vec<RoleId>,
+}
+
+impl ServerEmoji {
+    pub fn url(&self) -> String {
+        let extension = if self.animated { "gif" } else { "png" };
+        format!("{}emojis/{}.{}", CDN_URL, self.id.0, extension)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Sticker {
+    #[serde(default = "default_true", deserialize_with = "nullable_true")]
+    pub available: bool,
+    pub description: Option<String>,
+    pub format_type: u64,
+    pub id: StickerId,
+    pub name: String,
+    #[serde(rename = "guild_id")]
+    pub server_id: Option<ServerId>,
+    /// autocomplete suggestions for the sticker
+    pub tags: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct User {
     pub avatar: Option<String>,
     pub avatar_decoration_data: Option<AvatarDecorationData>,
     pub bot: Option<bool>,
     pub clan: Option<Clan>,
+    #[serde(with = "discriminator")]
     pub discriminator: u16,
     pub global_name: Option<String>,
     pub id: UserId,
@@ -674,50 +962,6 @@ pub struct User {
 }
 
 impl User {
-    fn decode(mut map: WrappedMap) -> Result<Self> {
-        let avatar = map
-            .get_value("avatar", WrappedValue::to_string)
-            .transpose()?;
-        let avatar_decoration_data = map
-            .get("avatar_decoration_data", AvatarDecorationData::decode)
-            .transpose()?;
-        let bot = map.get_value("bot", WrappedValue::to_bool).transpose()?;
-        let clan = map.get("clan", Clan::decode).transpose()?;
-        let discriminator = map
-            .get_value("discriminator", WrappedValue::to_string)
-            .unwrap()?
-            .parse::<u16>()?;
-        let global_name = map
-            .get_value("global_name", WrappedValue::to_string)
-            .transpose()?;
-        let id = map.get_value("id", UserId::decode).unwrap()?;
-        // let primary_guild = map
-        //     .get("primary_guild")
-        //     .and_then(|x| Some(x.to_decoder(Clan::decode)))
-        //     .transpose()?;
-        let public_flags = map
-            .get_value("public_flags", WrappedValue::to_u64)
-            .transpose()?;
-        let system = map.get_value("system", WrappedValue::to_bool).transpose()?;
-        let username = map
-            .get_value("username", WrappedValue::to_string)
-            .unwrap()?;
-        map.check_empty_panic("User");
-        Ok(Self {
-            avatar,
-            avatar_decoration_data,
-            bot,
-            clan,
-            discriminator,
-            global_name,
-            id,
-            // primary_guild,
-            public_flags,
-            system,
-            username,
-        })
-    }
-
     #[inline(always)]
     pub fn mention(&self) -> Mention {
         self.id.mention()
@@ -725,7 +969,7 @@ impl User {
 
     pub fn avatar_url(&self) -> Option<String> {
         Some(format!(
-            "{}/avatars/{}/{}.jpg",
+            "{}avatars/{}/{}.jpg",
             CDN_URL,
             self.id.0,
             self.avatar.as_ref()?
@@ -733,7 +977,7 @@ impl User {
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Clan {
     pub badge: Option<String>,
     pub identity_enabled: bool,
@@ -741,68 +985,41 @@ pub struct Clan {
     pub tag: Option<String>,
 }
 
-impl Clan {
-    fn decode(mut map: WrappedMap) -> Result<Self> {
-        let badge = map
-            .get_value("badge", WrappedValue::to_string)
-            .transpose()?;
-        let identity_enabled = map
-            .get_value("identity_enabled", WrappedValue::to_bool)
-            .unwrap()?;
-        let identity_guild_id = map
-            .get_value("identity_guild_id", ServerId::decode)
-            .transpose()?;
-        let tag = map.get_value("tag", WrappedValue::to_string).transpose()?;
-        map.check_empty_panic("Clan");
-        Ok(Self {
-            badge,
-            identity_enabled,
-            identity_guild_id,
-            tag,
-        })
-    }
-}
-
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct AvatarDecorationData {
     pub asset: String,
     pub expires_at: Option<u64>,
     pub sku_id: String,
 }
 
-impl AvatarDecorationData {
-    fn decode(mut map: WrappedMap) -> Result<Self> {
-        let asset = map.get_value("asset", WrappedValue::to_string).unwrap()?;
-        let expires_at = map
-            .get_value("expires_at", WrappedValue::to_u64)
-            .transpose()?;
-        let sku_id = map.get_value("sku_id", WrappedValue::to_string).unwrap()?;
-        map.check_empty_panic("AvatarDecorationData");
-        Ok(Self {
-            asset,
-            expires_at,
-            sku_id,
-        })
-    }
-}
-
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Member {
-    // pub user: User,
-    // pub roles: Vec<RoleId>,
-    // pub nick: Option<String>,
-    // pub joined_at: String,
-    // pub mute: bool,
-    // pub deaf: bool,
+    pub avatar: Option<String>,
+    pub communication_disabled_until: Option<DateTime<FixedOffset>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub deaf: bool,
+    #[serde(default, deserialize_with = "nullable")]
+    pub flags: u64,
+    pub joined_at: Option<DateTime<FixedOffset>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub mute: bool,
+    pub nick: Option<String>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub pending: bool,
+    pub premium_since: Option<DateTime<FixedOffset>>,
+    pub roles: Vec<RoleId>,
+    /// not sent for the member attached to a message
+    pub user: Option<User>,
 }
 
 impl Member {
-    // pub fn display_name(&self) -> &str {
-    //     if let Some(name) = self.nick.as_ref() {
-    //         name
-    //     } else {
-    //         &self.user.username
-    //     }
-    // }
+    pub fn display_name(&self) -> Option<&str> {
+        if let Some(name) = self.nick.as_ref() {
+            return Some(name);
+        }
+        let user = self.user.as_ref()?;
+        Some(user.global_name.as_ref().unwrap_or(&user.username))
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -812,68 +1029,122 @@ pub enum Channel {
     Public(PublicChannel),
     Voice(VoiceChannel),
     Category(ChannelCategory),
-    News,
-    Store,
+    Thread(Thread),
+    /// an announcement channel, it has the fields of a text channel
+    News(PublicChannel),
+    /// channel types that are not decoded yet
+    Unknown(ChannelType, ChannelId),
 }
 
 impl Channel {
-    pub fn decode(mut map: WrappedMap) -> Result<Self> {
-        Ok(match map.get_value("type", ChannelType::decode).unwrap()? {
-            ChannelType::Group => Channel::Group(Group::decode(map)?),
-            ChannelType::Private => Channel::Private(PrivateChannel::decode(map)?),
-            ChannelType::Public => Channel::Public(PublicChannel::decode(map)?),
-            ChannelType::Voice => Channel::Voice(VoiceChannel::decode(map)?),
-            ChannelType::Category => Channel::Category(ChannelCategory::decode(map)?),
-            other => todo!("{:?}", other),
-        })
+    pub fn id(&self) -> ChannelId {
+        match *self {
+            Channel::Group(ref group) => group.id,
+            Channel::Private(ref channel) => channel.id,
+            Channel::Public(ref channel) | Channel::News(ref channel) => channel.id,
+            Channel::Voice(ref channel) => channel.id,
+            Channel::Category(ref category) => category.id,
+            Channel::Thread(ref thread) => thread.id,
+            Channel::Unknown(_, id) => id,
+        }
+    }
+
+    /// empty for channels that can't have overwrites, threads use the ones of their parent
+    pub fn permission_overwrites(&self) -> &[PermissionOverwrite] {
+        match *self {
+            Channel::Public(ref channel) | Channel::News(ref channel) => {
+                &channel.permission_overwrites
+            }
+            Channel::Voice(ref channel) => &channel.permission_overwrites,
+            Channel::Category(ref category) => &category.permission_overwrites,
+            _ => &[],
+        }
     }
 }
 
-#[derive(Copy, Clone, Hash, Eq, PartialEq, Debug)]
-pub enum ChannelType {
-    Group,
-    Private,
-    Public,
-    Voice,
-    Category,
-    News,
-    Store,
-    NewsThread,
-    PublicThread,
-    PrivateThread,
-    StageVoice,
-    Directory,
-    Forum,
-}
-
-impl ChannelType {
-    fn decode(value: WrappedValue) -> Result<Self> {
-        Ok(match value.to_u64()? {
-            0 => ChannelType::Public,
-            1 => ChannelType::Private,
-            2 => ChannelType::Voice,
-            3 => ChannelType::Group,
-            4 => ChannelType::Category,
-            5 => ChannelType::News,
-            6 => ChannelType::Store,
-            10 => ChannelType::NewsThread,
-            11 => ChannelType::PublicThread,
-            12 => ChannelType::PrivateThread,
-            13 => ChannelType::StageVoice,
-            14 => ChannelType::Directory,
-            15 => ChannelType::Forum,
-            other => panic!("unknown channel type {:?}", other),
+impl<'de> Deserialize<'de> for Channel {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut value = Value::deserialize(deserializer)?;
+        let kind = match value.get("type") {
+            Some(kind) => ChannelType::deserialize(kind).map_err(de::Error::custom)?,
+            None => return Err(de::Error::missing_field("type")),
+        };
+        // threads keep the type, it tells the kind of thread apart
+        if let (false, Value::Object(ref mut map)) = (
+            matches!(
+                kind,
+                ChannelType::NewsThread | ChannelType::PublicThread | ChannelType::PrivateThread
+            ),
+            &mut value,
+        ) {
+            map.remove("type");
+        }
+        Ok(match kind {
+            ChannelType::Group => Channel::Group(decode_buffered(value, &IGNORED_IN_CHANNEL)?),
+            ChannelType::Private => Channel::Private(decode_buffered(value, &IGNORED_IN_CHANNEL)?),
+            ChannelType::Public => Channel::Public(decode_buffered(value, &IGNORED_IN_CHANNEL)?),
+            ChannelType::News => Channel::News(decode_buffered(value, &IGNORED_IN_CHANNEL)?),
+            ChannelType::Voice => Channel::Voice(decode_buffered(value, &IGNORED_IN_CHANNEL)?),
+            ChannelType::Category => {
+                Channel::Category(decode_buffered(value, &IGNORED_IN_CHANNEL)?)
+            }
+            ChannelType::NewsThread | ChannelType::PublicThread | ChannelType::PrivateThread => {
+                Channel::Thread(decode_buffered(value, &IGNORED_IN_THREAD)?)
+            }
+            // the rest of the fields are not known for these
+            other => Channel::Unknown(
+                other,
+                ChannelId::deserialize(&value["id"]).map_err(de::Error::custom)?,
+            ),
         })
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+impl Serialize for Channel {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let (kind, value) = match *self {
+            Channel::Group(ref group) => (ChannelType::Group, serde_json::to_value(group)),
+            Channel::Private(ref channel) => (ChannelType::Private, serde_json::to_value(channel)),
+            Channel::Public(ref channel) => (ChannelType::Public, serde_json::to_value(channel)),
+            Channel::Voice(ref channel) => (ChannelType::Voice, serde_json::to_value(channel)),
+            Channel::Category(ref category) => {
+                (ChannelType::Category, serde_json::to_value(category))
+            }
+            Channel::Thread(ref thread) => return thread.serialize(serializer),
+            Channel::News(ref channel) => (ChannelType::News, serde_json::to_value(channel)),
+            Channel::Unknown(kind, id) => (kind, Ok(serde_json::json!({ "id": id }))),
+        };
+        let mut value = value.map_err(ser::Error::custom)?;
+        value["type"] = u64::from(kind).into();
+        value.serialize(serializer)
+    }
+}
+
+number_enum! {
+    pub enum ChannelType ("channel type") {
+        Public = 0,
+        Private = 1,
+        Voice = 2,
+        Group = 3,
+        Category = 4,
+        News = 5,
+        Store = 6,
+        NewsThread = 10,
+        PublicThread = 11,
+        PrivateThread = 12,
+        StageVoice = 13,
+        Directory = 14,
+        Forum = 15,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Group {
     pub blocked_user_warning_dismissed: bool,
     pub flags: u64,
     pub icon: Option<String>,
     pub id: ChannelId,
-    pub last_message_id: MessageId,
+    pub last_message_id: Option<MessageId>,
     pub last_pin_timestamp: Option<String>,
     pub name: Option<String>,
     pub owner_id: UserId,
@@ -882,41 +1153,7 @@ pub struct Group {
 }
 
 impl Group {
-    fn decode(mut map: WrappedMap) -> Result<Self> {
-        let blocked_user_warning_dismissed = map
-            .get_value("blocked_user_warning_dismissed", WrappedValue::to_bool)
-            .unwrap()?;
-        let flags = map.get_value("flags", WrappedValue::to_u64).unwrap()?;
-        let icon = map.get_value("icon", WrappedValue::to_string).transpose()?;
-        let id = map.get_value("id", ChannelId::decode).unwrap()?;
-        let last_message_id = map
-            .get_value("last_message_id", MessageId::decode)
-            .unwrap()?;
-        let last_pin_timestamp = map
-            .get_value("last_pin_timestamp", WrappedValue::to_string)
-            .transpose()?;
-        let name = map.get_value("name", WrappedValue::to_string).transpose()?;
-        let owner_id = map.get_value("owner_id", UserId::decode).unwrap()?;
-        let recipient_flags = map
-            .get_value("recipient_flags", WrappedValue::to_u64)
-            .unwrap()?;
-        let recipients = map.get_array("recipients", User::decode).unwrap()?;
-        map.check_empty_panic("Group");
-        Ok(Self {
-            blocked_user_warning_dismissed,
-            flags,
-            icon,
-            id,
-            last_message_id,
-            last_pin_timestamp,
-            name,
-            owner_id,
-            recipient_flags,
-            recipients,
-        })
-    }
-
-    pub fn name(&self) -> std::borrow::Cow<str> {
+    pub fn name(&self) -> std::borrow::Cow<'_, str> {
         match self.name {
             Some(ref name) => std::borrow::Cow::Borrowed(name),
             None => {
@@ -937,11 +1174,11 @@ impl Group {
     pub fn icon_url(&self) -> Option<String> {
         self.icon
             .as_ref()
-            .map(|x| format!("{}/channel-icons/{}/{}.jpg", CDN_URL, self.id.0, x))
+            .map(|x| format!("{}channel-icons/{}/{}.jpg", CDN_URL, self.id.0, x))
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PrivateChannel {
     pub flags: u64,
     pub id: ChannelId,
@@ -951,56 +1188,27 @@ pub struct PrivateChannel {
     pub last_message_id: Option<MessageId>,
     pub last_pin_timestamp: Option<String>,
     pub recipient_flags: u64,
+    #[serde(
+        rename = "recipients",
+        deserialize_with = "first_recipient",
+        serialize_with = "recipients"
+    )]
     pub recipient: User,
     pub safety_warnings: Vec<String>,
 }
 
-impl PrivateChannel {
-    pub fn decode(mut map: WrappedMap) -> Result<Self> {
-        let flags = map.get_value("flags", WrappedValue::to_u64).unwrap()?;
-        let id = map.get_value("id", ChannelId::decode).unwrap()?;
-        let is_message_request = map
-            .get_value("is_message_request", WrappedValue::to_bool)
-            .unwrap()?;
-        let is_message_request_timestamp = map
-            .get_value("is_message_request_timestamp", WrappedValue::to_string)
-            .transpose()?;
-        let is_spam = map.get_value("is_spam", WrappedValue::to_bool).unwrap()?;
-        let last_message_id = map
-            .get_value("last_message_id", MessageId::decode)
-            .transpose()?;
-        let last_pin_timestamp = map
-            .get_value("last_pin_timestamp", WrappedValue::to_string)
-            .transpose()?;
-        let recipient_flags = map
-            .get_value("recipient_flags", WrappedValue::to_u64)
-            .unwrap()?;
-        let recipient = map
-            .get_array("recipients", User::decode)
-            .unwrap()?
-            .first()
-            .unwrap()
-            .clone(); // TODO
-        let safety_warnings = map
-            .get_array_value("safety_warnings", WrappedValue::to_string)
-            .unwrap()?;
-        map.check_empty_panic("PrivateChannel");
-        Ok(Self {
-            flags,
-            id,
-            is_message_request,
-            is_message_request_timestamp,
-            is_spam,
-            last_message_id,
-            last_pin_timestamp,
-            recipient_flags,
-            recipient,
-            safety_warnings,
-        })
-    }
+fn first_recipient<'de, D: Deserializer<'de>>(deserializer: D) -> Result<User, D::Error> {
+    Vec::<User>::deserialize(deserializer)?
+        .into_iter()
+        .next()
+        .ok_or_else(|| de::Error::custom("no recipient")) // TODO
 }
 
-#[derive(Debug, Clone, PartialEq)]
+fn recipients<S: Serializer>(recipient: &User, serializer: S) -> Result<S::Ok, S::Error> {
+    [recipient].serialize(serializer)
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PublicChannel {
     pub flags: u64,
     pub id: ChannelId,
@@ -1015,47 +1223,13 @@ pub struct PublicChannel {
 }
 
 impl PublicChannel {
-    pub fn decode(mut map: WrappedMap) -> Result<Self> {
-        let flags = map.get_value("flags", WrappedValue::to_u64).unwrap()?;
-        let id = map.get_value("id", ChannelId::decode).unwrap()?;
-        let last_message_id = map
-            .get_value("last_message_id", MessageId::decode)
-            .transpose()?;
-        let name = map.get_value("name", WrappedValue::to_string).unwrap()?;
-        let parent_id = map.get_value("parent_id", ChannelId::decode).transpose()?;
-        let permission_overwrites = map
-            .get_array("permission_overwrites", PermissionOverwrite::decode)
-            .unwrap()?;
-        let position = map.get_value("position", WrappedValue::to_u64).unwrap()?;
-        let rate_limit_per_user = map
-            .get_value("rate_limit_per_user", WrappedValue::to_u64)
-            .unwrap()?;
-        let topic = map
-            .get_value("topic", WrappedValue::to_string)
-            .transpose()?;
-        let version = map.get_value("version", WrappedValue::to_u64).unwrap()?;
-        map.check_empty_panic("PublicChannel");
-        Ok(Self {
-            flags,
-            id,
-            last_message_id,
-            name,
-            parent_id,
-            permission_overwrites,
-            position,
-            rate_limit_per_user,
-            topic,
-            version,
-        })
-    }
-
     #[inline(always)]
     pub fn mention(&self) -> Mention {
         self.id.mention()
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct VoiceChannel {
     pub bitrate: u64,
     pub flags: u64,
@@ -1071,47 +1245,42 @@ pub struct VoiceChannel {
     pub version: u64,
 }
 
-impl VoiceChannel {
-    pub fn decode(mut map: WrappedMap) -> Result<Self> {
-        let bitrate = map.get_value("bitrate", WrappedValue::to_u64).unwrap()?;
-        let flags = map.get_value("flags", WrappedValue::to_u64).unwrap()?;
-        let id = map.get_value("id", ChannelId::decode).unwrap()?;
-        let last_message_id = map
-            .get_value("last_message_id", MessageId::decode)
-            .transpose()?;
-        let name = map.get_value("name", WrappedValue::to_string).unwrap()?;
-        let parent_id = map.get_value("parent_id", ChannelId::decode).transpose()?;
-        let permission_overwrites = map
-            .get_array("permission_overwrites", PermissionOverwrite::decode)
-            .unwrap()?;
-        let position = map.get_value("position", WrappedValue::to_u64).unwrap()?;
-        let rate_limit_per_user = map
-            .get_value("rate_limit_per_user", WrappedValue::to_u64)
-            .unwrap()?;
-        let rtc_region = map
-            .get_value("rtc_region", WrappedValue::to_string)
-            .transpose()?;
-        let user_limit = map.get_value("user_limit", WrappedValue::to_u64).unwrap()?;
-        let version = map.get_value("version", WrappedValue::to_u64).unwrap()?;
-        map.check_empty_panic("VoiceChannel");
-        Ok(Self {
-            bitrate,
-            flags,
-            id,
-            last_message_id,
-            name,
-            parent_id,
-            permission_overwrites,
-            position,
-            rate_limit_per_user,
-            rtc_region,
-            user_limit,
-            version,
-        })
-    }
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Thread {
+    #[serde(default, deserialize_with = "nullable")]
+    pub flags: u64,
+    pub id: ChannelId,
+    #[serde(rename = "type")]
+    pub kind: ChannelType,
+    pub last_message_id: Option<MessageId>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub member_count: u64,
+    #[serde(default, deserialize_with = "nullable")]
+    pub message_count: u64,
+    pub name: String,
+    pub owner_id: UserId,
+    pub parent_id: ChannelId,
+    #[serde(default, deserialize_with = "nullable")]
+    pub rate_limit_per_user: u64,
+    #[serde(rename = "guild_id")]
+    pub server_id: Option<ServerId>,
+    pub thread_metadata: ThreadMetadata,
+    #[serde(default, deserialize_with = "nullable")]
+    pub total_message_sent: u64,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ThreadMetadata {
+    pub archive_timestamp: DateTime<FixedOffset>,
+    pub archived: bool,
+    /// minutes of inactivity before the thread is archived
+    pub auto_archive_duration: u64,
+    pub create_timestamp: Option<DateTime<FixedOffset>>,
+    pub invitable: Option<bool>,
+    pub locked: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct CurrentUser {
     pub accent_color: Option<u64>,
     pub avatar: String,
@@ -1121,6 +1290,7 @@ pub struct CurrentUser {
     pub bio: String,
     pub clan: Option<Clan>,
     pub desktop: bool,
+    #[serde(with = "discriminator")]
     pub discriminator: u16,
     pub email: String,
     pub flags: u64,
@@ -1140,157 +1310,31 @@ pub struct CurrentUser {
     pub verified: bool,
 }
 
-impl CurrentUser {
-    fn decode(mut map: WrappedMap) -> Result<Self> {
-        let accent_color = map
-            .get_value("accent_color", WrappedValue::to_u64)
-            .transpose()?;
-        let avatar = map.get_value("avatar", WrappedValue::to_string).unwrap()?;
-        let avatar_decoration_data = map
-            .get_value("avatar_decoration_data", WrappedValue::to_string)
-            .transpose()?;
-        let banner = map
-            .get_value("banner", WrappedValue::to_string)
-            .transpose()?;
-        let banner_color = map
-            .get_value("banner_color", WrappedValue::to_string)
-            .transpose()?;
-        let bio = map.get_value("bio", WrappedValue::to_string).unwrap()?;
-        let clan = map.get("clan", Clan::decode).transpose()?;
-        let desktop = map.get_value("desktop", WrappedValue::to_bool).unwrap()?;
-        let discriminator = map
-            .get_value("discriminator", WrappedValue::to_string)
-            .unwrap()?
-            .parse::<u16>()?;
-        let email = map.get_value("email", WrappedValue::to_string).unwrap()?;
-        let flags = map.get_value("flags", WrappedValue::to_u64).unwrap()?;
-        let global_name = map
-            .get_value("global_name", WrappedValue::to_string)
-            .transpose()?;
-        let id = map.get_value("id", UserId::decode).unwrap()?;
-        let mfa_enabled = map
-            .get_value("mfa_enabled", WrappedValue::to_bool)
-            .unwrap()?;
-        let mobile = map.get_value("mobile", WrappedValue::to_bool).unwrap()?;
-        let nsfw_allowed = map
-            .get_value("nsfw_allowed", WrappedValue::to_bool)
-            .unwrap()?;
-        let phone = map
-            .get_value("phone", WrappedValue::to_string)
-            .transpose()?;
-        let premium = map.get_value("premium", WrappedValue::to_bool).unwrap()?;
-        let premium_type = map
-            .get_value("premium_type", WrappedValue::to_u64)
-            .unwrap()?;
-        let primary_guild = map
-            .get_value("primary_guild", WrappedValue::to_string)
-            .transpose()?;
-        let pronouns = map
-            .get_value("pronouns", WrappedValue::to_string)
-            .unwrap()?;
-        let public_flags = map
-            .get_value("public_flags", WrappedValue::to_u64)
-            .transpose()?;
-        let purchased_flags = map
-            .get_value("purchased_flags", WrappedValue::to_u64)
-            .unwrap()?;
-        let username = map
-            .get_value("username", WrappedValue::to_string)
-            .unwrap()?;
-        let verified = map.get_value("verified", WrappedValue::to_bool).unwrap()?;
-        map.check_empty_panic("CurrentUser");
-        Ok(Self {
-            accent_color,
-            avatar,
-            avatar_decoration_data,
-            banner,
-            banner_color,
-            bio,
-            clan,
-            desktop,
-            discriminator,
-            email,
-            flags,
-            global_name,
-            id,
-            mfa_enabled,
-            mobile,
-            nsfw_allowed,
-            phone,
-            premium,
-            premium_type,
-            primary_guild,
-            pronouns,
-            public_flags,
-            purchased_flags,
-            username,
-            verified,
-        })
-    }
-}
-
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Relationship {
     pub id: UserId,
+    #[serde(default, deserialize_with = "nullable")]
     pub is_spam_request: bool,
     pub nickname: Option<String>,
     pub since: String,
+    #[serde(rename = "type")]
     pub type_relationship: RelationshipType,
     pub user: User,
+    #[serde(default, deserialize_with = "nullable")]
     pub user_ignored: bool,
 }
 
-impl Relationship {
-    fn decode(mut map: WrappedMap) -> Result<Self> {
-        let id = map.get_value("id", UserId::decode).unwrap()?;
-        let is_spam_request = map
-            .get_value("is_spam_request", WrappedValue::to_bool)
-            .unwrap()?;
-        let nickname = map
-            .get_value("nickname", WrappedValue::to_string)
-            .transpose()?;
-        let since = map.get_value("since", WrappedValue::to_string).unwrap()?;
-        let type_relationship = map.get_value("type", RelationshipType::decode).unwrap()?;
-        let user = map.get("user", User::decode).unwrap()?;
-        let user_ignored = map
-            .get_value("user_ignored", WrappedValue::to_bool)
-            .unwrap()?;
-        map.check_empty_panic("Relationship");
-        Ok(Self {
-            id,
-            is_spam_request,
-            nickname,
-            since,
-            type_relationship,
-            user,
-            user_ignored,
-        })
+number_enum! {
+    pub enum RelationshipType ("relationship type") {
+        Ignored = 0,
+        Friends = 1,
+        Blocked = 2,
+        IncomingRequest = 3,
+        OutgoingRequest = 4,
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
-pub enum RelationshipType {
-    Ignored,
-    Friends,
-    Blocked,
-    IncomingRequest,
-    OutgoingRequest,
-}
-
-impl RelationshipType {
-    fn decode(value: WrappedValue) -> Result<Self> {
-        Ok(match value.to_u64()? {
-            0 => RelationshipType::Ignored,
-            1 => RelationshipType::Friends,
-            2 => RelationshipType::Blocked,
-            3 => RelationshipType::IncomingRequest,
-            4 => RelationshipType::OutgoingRequest,
-            other => panic!("unknown type, {:?}", other),
-        })
-    }
-}
-
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Presence {
     pub activities: Vec<PresenceActivity>,
     pub client_status: PresenceClientStatus,
@@ -1300,76 +1344,53 @@ pub struct Presence {
     pub user: User,
 }
 
-impl Presence {
-    fn decode(mut map: WrappedMap) -> Result<Self> {
-        let activities = map
-            .get_array("activities", PresenceActivity::decode)
-            .unwrap()?;
-        let client_status = map
-            .get("client_status", PresenceClientStatus::decode)
-            .unwrap()?;
-        let last_modified = map
-            .get_value("last_modified", WrappedValue::to_u64)
-            .unwrap()?;
-        let restricted_application_id = map
-            .get_value("restricted_application_id", WrappedValue::to_string)
-            .transpose()?;
-        let status = map.get_value("status", Status::decode).unwrap()?;
-        let user = map.get("user", User::decode).unwrap()?;
-        map.check_empty_panic("Presence");
-        Ok(Self {
-            activities,
-            client_status,
-            last_modified,
-            restricted_application_id,
-            status,
-            user,
-        })
-    }
-}
-
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
 pub enum Status {
     Online,
     Idle,
     Dnd,
     Offline,
+    Unknown(String),
 }
 
-impl Status {
-    fn decode(value: WrappedValue) -> Result<Self> {
-        Ok(match value.to_string()?.as_str() {
+impl TryFrom<String> for Status {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        Ok(match value.as_str() {
             "online" => Status::Online,
             "idle" => Status::Idle,
             "dnd" => Status::Dnd,
             "offline" => Status::Offline,
-            other => panic!("unknown status: {:?}", other),
+            other => {
+                unknown(format!("unknown status {:?}", other))?;
+                Status::Unknown(value)
+            }
         })
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+impl From<Status> for String {
+    fn from(status: Status) -> Self {
+        match status {
+            Status::Online => "online".to_owned(),
+            Status::Idle => "idle".to_owned(),
+            Status::Dnd => "dnd".to_owned(),
+            Status::Offline => "offline".to_owned(),
+            Status::Unknown(other) => other,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PresenceClientStatus {
     pub desktop: Option<Status>,
     pub mobile: Option<Status>,
     pub web: Option<Status>,
 }
 
-impl PresenceClientStatus {
-    fn decode(mut map: WrappedMap) -> Result<Self> {
-        let desktop = map.get_value("desktop", Status::decode).transpose()?;
-        let mobile = map.get_value("mobile", Status::decode).transpose()?;
-        let web = map.get_value("web", Status::decode).transpose()?;
-        map.check_empty_panic("PresenceClientStatus");
-        Ok(Self {
-            desktop,
-            mobile,
-            web,
-        })
-    }
-}
-
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PresenceActivity {
     pub application_id: Option<ApplicationId>,
     pub assets: Option<PresenceActivityAsset>,
@@ -1377,207 +1398,520 @@ pub struct PresenceActivity {
     pub details: Option<String>,
     pub emoji: Option<Emoji>,
     pub flags: Option<u64>,
-    pub id: String,
+    pub id: String, // TODO
     pub name: String,
     pub party: Option<PresenceActivityParty>,
     pub session_id: Option<String>,
     pub state: Option<String>,
     pub sync_id: Option<String>,
+    #[serde(rename = "timestamps")]
     pub timestamp: Option<Timestamp>,
+    #[serde(rename = "type")]
     pub type_activity: u64, // TODO separate into types
 }
 
-impl PresenceActivity {
-    fn decode(mut map: WrappedMap) -> Result<Self> {
-        let application_id = map
-            .get_value("application_id", ApplicationId::decode)
-            .transpose()?;
-        let assets = map
-            .get("assets", PresenceActivityAsset::decode)
-            .transpose()?;
-        let created_at = map.get_value("created_at", WrappedValue::to_u64).unwrap()?;
-        let details = map
-            .get_value("details", WrappedValue::to_string)
-            .transpose()?;
-        let emoji = map.get("emoji", Emoji::decode).transpose()?;
-        let flags = map.get_value("flags", WrappedValue::to_u64).transpose()?;
-        let id = map.get_value("id", WrappedValue::to_string).unwrap()?; // TODO
-        let name = map.get_value("name", WrappedValue::to_string).unwrap()?;
-        let party = map
-            .get("party", PresenceActivityParty::decode)
-            .transpose()?;
-        let session_id = map
-            .get_value("session_id", WrappedValue::to_string)
-            .transpose()?;
-        let state = map
-            .get_value("state", WrappedValue::to_string)
-            .transpose()?;
-        let sync_id = map
-            .get_value("sync_id", WrappedValue::to_string)
-            .transpose()?;
-        let type_activity = map.get_value("type", WrappedValue::to_u64).unwrap()?;
-        let timestamp = map.get("timestamps", Timestamp::decode).transpose()?;
-        map.check_empty_panic("PresenceActivity");
-        Ok(Self {
-            application_id,
-            assets,
-            created_at,
-            details,
-            emoji,
-            flags,
-            id,
-            name,
-            party,
-            session_id,
-            state,
-            sync_id,
-            timestamp,
-            type_activity,
-        })
-    }
-}
-
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Timestamp {
     pub end: Option<u64>,
     pub start: Option<u64>,
 }
 
-impl Timestamp {
-    fn decode(mut map: WrappedMap) -> Result<Self> {
-        let end = map.get_value("end", WrappedValue::to_u64).transpose()?;
-        let start = map.get_value("start", WrappedValue::to_u64).transpose()?;
-        map.check_empty_panic("Timestamp");
-        Ok(Self { end, start })
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PresenceActivityParty {
+    pub id: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PresenceActivityAsset {
+    pub large_image: String,
+    pub large_text: String,
+}
+
+/// an activity to set in a presence update, discord fills in the rest
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Activity {
+    pub name: String,
+    pub state: Option<String>,
+    #[serde(rename = "type")]
+    pub type_activity: u64,
+    /// only used for streaming activities
+    pub url: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Message {
+    pub attachments: Vec<Attachment>,
+    pub author: User,
+    pub channel_id: ChannelId,
+    pub content: String,
+    pub edited_timestamp: Option<DateTime<FixedOffset>>,
+    pub embeds: Vec<Embed>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub flags: u64,
+    pub id: MessageId,
+    /// only sent for messages in servers, without the user
+    pub member: Option<Member>,
+    pub mention_everyone: bool,
+    pub mention_roles: Vec<RoleId>,
+    pub mentions: Vec<User>,
+    pub message_reference: Option<MessageReference>,
+    #[serde(default, deserialize_with = "nonce")]
+    pub nonce: Option<String>,
+    pub pinned: bool,
+    #[serde(default, deserialize_with = "nullable")]
+    pub reactions: Vec<Reaction>,
+    pub referenced_message: Option<Box<Message>>,
+    #[serde(rename = "guild_id")]
+    pub server_id: Option<ServerId>,
+    pub timestamp: DateTime<FixedOffset>,
+    pub tts: bool,
+    #[serde(rename = "type")]
+    pub type_message: MessageType,
+    pub webhook_id: Option<UserId>,
+}
+
+/// the nonce is sent back as whatever type the client used, so both strings and integers are accepted
+fn nonce<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    Ok(
+        Option::<NumberOrString>::deserialize(deserializer)?.map(|x| match x {
+            NumberOrString::Number(nonce) => nonce.to_string(),
+            NumberOrString::String(nonce) => nonce,
+        }),
+    )
+}
+
+number_enum! {
+    pub enum MessageType ("message type") {
+        Default = 0,
+        RecipientAdd = 1,
+        RecipientRemove = 2,
+        Call = 3,
+        ChannelNameChange = 4,
+        ChannelIconChange = 5,
+        ChannelPinnedMessage = 6,
+        UserJoin = 7,
+        ServerBoost = 8,
+        ServerBoostTier1 = 9,
+        ServerBoostTier2 = 10,
+        ServerBoostTier3 = 11,
+        ChannelFollowAdd = 12,
+        ThreadCreated = 18,
+        Reply = 19,
+        ChatInputCommand = 20,
+        ThreadStarterMessage = 21,
+        ContextMenuCommand = 23,
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
-pub struct PresenceActivityParty {
-    pub id: String,
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MessageReference {
+    pub channel_id: Option<ChannelId>,
+    pub fail_if_not_exists: Option<bool>,
+    pub message_id: Option<MessageId>,
+    #[serde(rename = "guild_id")]
+    pub server_id: Option<ServerId>,
+    #[serde(rename = "type", default, deserialize_with = "nullable")]
+    pub type_reference: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Attachment {
+    pub content_type: Option<String>,
+    pub description: Option<String>,
+    pub ephemeral: Option<bool>,
+    pub filename: String,
+    pub flags: Option<u64>,
+    pub height: Option<u64>,
+    pub id: AttachmentId,
+    pub proxy_url: String,
+    pub size: u64,
+    pub url: String,
+    pub width: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Embed {
+    pub author: Option<EmbedAuthor>,
+    /// Color in 0xRRGGBB form
+    pub color: Option<u64>,
+    pub description: Option<String>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub fields: Vec<EmbedField>,
+    pub footer: Option<EmbedFooter>,
+    pub image: Option<EmbedMedia>,
+    pub provider: Option<EmbedProvider>,
+    pub thumbnail: Option<EmbedMedia>,
+    pub timestamp: Option<DateTime<FixedOffset>>,
+    pub title: Option<String>,
+    #[serde(rename = "type")]
+    pub type_embed: Option<String>,
+    pub url: Option<String>,
+    pub video: Option<EmbedMedia>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EmbedAuthor {
+    pub icon_url: Option<String>,
+    pub name: String,
+    pub proxy_icon_url: Option<String>,
+    pub url: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EmbedField {
+    #[serde(default, deserialize_with = "nullable")]
+    pub inline: bool,
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EmbedFooter {
+    pub icon_url: Option<String>,
+    pub proxy_icon_url: Option<String>,
+    pub text: String,
+}
+
+/// used for the image, thumbnail and video of an embed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EmbedMedia {
+    pub height: Option<u64>,
+    pub proxy_url: Option<String>,
+    pub url: String,
+    pub width: Option<u64>,
 }
 
-impl PresenceActivityParty {
-    fn decode(mut map: WrappedMap) -> Result<Self> {
-        let id = map.get_value("id", WrappedValue::to_string).unwrap()?;
-        map.check_empty_panic("PresenceActivityParty");
-        Ok(Self { id })
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EmbedProvider {
+    pub name: Option<String>,
+    pub url: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Reaction {
+    #[serde(default, deserialize_with = "nullable")]
+    pub burst_colors: Vec<String>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub burst_count: u64,
+    pub count: u64,
+    pub emoji: ReactionEmoji,
+    pub me: bool,
+    #[serde(default, deserialize_with = "nullable")]
+    pub me_burst: bool,
+}
+
+/// unicode emojis only have a name, custom emojis have an id and usually a name
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReactionEmoji {
+    #[serde(default, deserialize_with = "nullable")]
+    pub animated: bool,
+    pub id: Option<EmojiId>,
+    pub name: Option<String>,
+}
+
+/// MESSAGE_UPDATE can be partial, only the ids are guaranteed to be present
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MessageUpdateEvent {
+    pub attachments: Option<Vec<Attachment>>,
+    pub author: Option<User>,
+    pub channel_id: ChannelId,
+    pub content: Option<String>,
+    pub edited_timestamp: Option<DateTime<FixedOffset>>,
+    pub embeds: Option<Vec<Embed>>,
+    pub flags: Option<u64>,
+    pub id: MessageId,
+    pub mention_everyone: Option<bool>,
+    pub mention_roles: Option<Vec<RoleId>>,
+    pub mentions: Option<Vec<User>>,
+    pub pinned: Option<bool>,
+    pub reactions: Option<Vec<Reaction>>,
+    #[serde(rename = "guild_id")]
+    pub server_id: Option<ServerId>,
+}
+
+impl MessageUpdateEvent {
+    /// applies the fields present in the update to `message`
+    pub fn apply(&self, message: &mut Message) {
+        if let Some(ref attachments) = self.attachments {
+            message.attachments = attachments.clone();
+        }
+        if let Some(ref content) = self.content {
+            message.content = content.clone();
+        }
+        if self.edited_timestamp.is_some() {
+            message.edited_timestamp = self.edited_timestamp;
+        }
+        if let Some(ref embeds) = self.embeds {
+            message.embeds = embeds.clone();
+        }
+        if let Some(flags) = self.flags {
+            message.flags = flags;
+        }
+        if let Some(mention_everyone) = self.mention_everyone {
+            message.mention_everyone = mention_everyone;
+        }
+        if let Some(ref mention_roles) = self.mention_roles {
+            message.mention_roles = mention_roles.clone();
+        }
+        if let Some(ref mentions) = self.mentions {
+            message.mentions = mentions.clone();
+        }
+        if let Some(pinned) = self.pinned {
+            message.pinned = pinned;
+        }
+        if let Some(ref reactions) = self.reactions {
+            message.reactions = reactions.clone();
+        }
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
-pub struct PresenceActivityAsset {
-    pub large_image: String,
-    pub large_text: String,
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MessageDeleteEvent {
+    pub channel_id: ChannelId,
+    pub id: MessageId,
+    #[serde(rename = "guild_id")]
+    pub server_id: Option<ServerId>,
 }
 
-impl PresenceActivityAsset {
-    fn decode(mut map: WrappedMap) -> Result<Self> {
-        let large_image = map
-            .get_value("large_image", WrappedValue::to_string)
-            .unwrap()?;
-        let large_text = map
-            .get_value("large_text", WrappedValue::to_string)
-            .unwrap()?;
-        map.check_empty_panic("PresenceActivityAsset");
-        Ok(Self {
-            large_image,
-            large_text,
-        })
+/// the last message of each channel asked for with
+/// [`Connection::request_last_messages`](super::Connection::request_last_messages)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LastMessagesEvent {
+    pub messages: Vec<Message>,
+    #[serde(rename = "guild_id")]
+    pub server_id: ServerId,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MessageDeleteBulkEvent {
+    pub channel_id: ChannelId,
+    pub ids: Vec<MessageId>,
+    #[serde(rename = "guild_id")]
+    pub server_id: Option<ServerId>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerDeleteEvent {
+    pub id: ServerId,
+    /// true if the server went down in an outage, false if the user left or was removed
+    #[serde(default, deserialize_with = "nullable")]
+    pub unavailable: bool,
+}
+
+/// sent for both GUILD_ROLE_CREATE and GUILD_ROLE_UPDATE
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerRoleEvent {
+    pub role: Role,
+    #[serde(rename = "guild_id")]
+    pub server_id: ServerId,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerRoleDeleteEvent {
+    pub role_id: RoleId,
+    #[serde(rename = "guild_id")]
+    pub server_id: ServerId,
+}
+
+/// contains every emoji of the server, not only the changed ones
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerEmojisUpdateEvent {
+    pub emojis: Vec<ServerEmoji>,
+    #[serde(rename = "guild_id")]
+    pub server_id: ServerId,
+}
+
+/// contains every sticker of the server, not only the changed ones
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerStickersUpdateEvent {
+    #[serde(rename = "guild_id")]
+    pub server_id: ServerId,
+    pub stickers: Vec<Sticker>,
+}
+
+/// sent for CHANNEL_CREATE, CHANNEL_UPDATE and CHANNEL_DELETE
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChannelEvent {
+    #[serde(flatten)]
+    pub channel: Channel,
+    /// None for private channels and groups
+    #[serde(rename = "guild_id")]
+    pub server_id: Option<ServerId>,
+}
+
+/// sent for both GUILD_MEMBER_ADD and GUILD_MEMBER_UPDATE
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerMemberEvent {
+    #[serde(flatten)]
+    pub member: Member,
+    #[serde(rename = "guild_id")]
+    pub server_id: ServerId,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerMemberRemoveEvent {
+    #[serde(rename = "guild_id")]
+    pub server_id: ServerId,
+    pub user: User,
+}
+
+/// an answer to [`Connection::request_server_members`](super::Connection::request_server_members),
+/// large answers are split over several chunks
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerMembersChunkEvent {
+    pub chunk_count: u64,
+    pub chunk_index: u64,
+    pub members: Vec<Member>,
+    pub nonce: Option<String>,
+    /// requested user ids that are not in the server
+    #[serde(default)]
+    pub not_found: Vec<UserId>,
+    #[serde(rename = "guild_id")]
+    pub server_id: ServerId,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RelationshipRemoveEvent {
+    pub id: UserId,
+    #[serde(rename = "type")]
+    pub type_relationship: RelationshipType,
+}
+
+/// only the id of the user is guaranteed to be present
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PresenceUpdateEvent {
+    pub activities: Vec<PresenceActivity>,
+    pub client_status: PresenceClientStatus,
+    #[serde(rename = "guild_id")]
+    pub server_id: Option<ServerId>,
+    pub status: Status,
+    pub user: PresenceUser,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PresenceUser {
+    pub id: UserId,
+}
+
+impl PresenceUpdateEvent {
+    pub fn apply(&self, presence: &mut Presence) {
+        presence.activities = self.activities.clone();
+        presence.client_status = self.client_status.clone();
+        presence.status = self.status.clone();
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ReadyEvent {
     pub presences: Vec<Presence>,
     pub private_channels: Vec<Channel>,
     pub relationships: Vec<Relationship>,
+    pub resume_gateway_url: String,
+    #[serde(rename = "guilds")]
+    pub servers: Vec<PossibleServer>,
     pub session_id: String,
     pub user: CurrentUser,
     pub v: u64,
 }
 
-impl ReadyEvent {
-    pub fn decode(mut map: WrappedMap) -> Result<Self> {
-        map.get("_trace", |_| Err::<u32, Error>(Error::msg("")));
-        map.get("analytics_token", |_| Err::<u32, Error>(Error::msg("")));
-        map.get("api_code_version", |_| Err::<u32, Error>(Error::msg("")));
-        map.get("auth", |_| Err::<u32, Error>(Error::msg("")));
-        map.get("auth_session_id_hash", |_| {
-            Err::<u32, Error>(Error::msg(""))
-        });
-        map.get("broadcaster_user_ids", |_| {
-            Err::<u32, Error>(Error::msg(""))
-        });
-        map.get("connected_accounts", |_| Err::<u32, Error>(Error::msg("")));
-        map.get("consents", |_| Err::<u32, Error>(Error::msg("")));
-        map.get("country_code", |_| Err::<u32, Error>(Error::msg("")));
-        map.get("experiments", |_| Err::<u32, Error>(Error::msg("")));
-        map.get("explicit_content_scan_version", |_| {
-            Err::<u32, Error>(Error::msg(""))
-        });
-        map.get("friend_suggestion_count", |_| {
-            Err::<u32, Error>(Error::msg(""))
-        });
-        map.get("game_relationships", |_| Err::<u32, Error>(Error::msg("")));
-        map.get("geo_ordered_rtc_regions", |_| {
-            Err::<u32, Error>(Error::msg(""))
-        });
-        map.get("guild_experiments", |_| Err::<u32, Error>(Error::msg("")));
-        map.get("guild_join_requests", |_| Err::<u32, Error>(Error::msg("")));
-        let servers = map.get_array("guilds", Server::decode).unwrap()?;
-        map.get("notes", |_| Err::<u32, Error>(Error::msg("")));
-        map.get("notification_settings", |_| {
-            Err::<u32, Error>(Error::msg(""))
-        });
-        let presences = map.get_array("presences", Presence::decode).unwrap()?;
-        let private_channels = map
-            .get_array("private_channels", Channel::decode)
-            .unwrap()?;
-        map.get("read_state", |_| Err::<u32, Error>(Error::msg("")));
-        let relationships = map
-            .get_array("relationships", Relationship::decode)
-            .unwrap()?;
-        map.get("resume_gateway_url", |_| Err::<u32, Error>(Error::msg("")));
-        let session_id = map
-            .get_value("session_id", WrappedValue::to_string)
-            .unwrap()?;
-        map.get("session_type", |_| Err::<u32, Error>(Error::msg("")));
-        map.get("sessions", |_| Err::<u32, Error>(Error::msg("")));
-        map.get("static_client_session_id", |_| {
-            Err::<u32, Error>(Error::msg(""))
-        });
-        map.get("tutorial", |_| Err::<u32, Error>(Error::msg("")));
-        let user = map.get("user", CurrentUser::decode).unwrap()?;
-        map.get("user_guild_settings", |_| Err::<u32, Error>(Error::msg("")));
-        map.get("user_settings", |_| Err::<u32, Error>(Error::msg("")));
-        map.get("user_settings_proto", |_| Err::<u32, Error>(Error::msg("")));
-        let v = map.get_value("v", WrappedValue::to_u64).unwrap()?;
-        map.check_empty_panic("ReadyEvent");
-        Ok(Self {
-            presences,
-            private_channels,
-            relationships,
-            session_id,
-            user,
-            v,
-        })
-    }
-}
-
 #[derive(Debug)]
 pub enum Event {
-    Ready(ReadyEvent),
-    Unknown(String, WrappedValue),
+    Ready(Box<ReadyEvent>),
+    Resumed,
+    MessageCreate(Box<Message>),
+    MessageUpdate(MessageUpdateEvent),
+    MessageDelete(MessageDeleteEvent),
+    MessageDeleteBulk(MessageDeleteBulkEvent),
+    LastMessages(LastMessagesEvent),
+    ServerCreate(PossibleServer),
+    ServerUpdate(Box<Server>),
+    ServerDelete(ServerDeleteEvent),
+    ServerRoleCreate(ServerRoleEvent),
+    ServerRoleUpdate(ServerRoleEvent),
+    ServerRoleDelete(ServerRoleDeleteEvent),
+    ServerEmojisUpdate(ServerEmojisUpdateEvent),
+    ServerStickersUpdate(ServerStickersUpdateEvent),
+    ServerMemberAdd(ServerMemberEvent),
+    ServerMemberUpdate(ServerMemberEvent),
+    ServerMemberRemove(ServerMemberRemoveEvent),
+    ServerMembersChunk(ServerMembersChunkEvent),
+    ChannelCreate(ChannelEvent),
+    ChannelUpdate(ChannelEvent),
+    ChannelDelete(ChannelEvent),
+    RelationshipAdd(Relationship),
+    RelationshipRemove(RelationshipRemoveEvent),
+    PresenceUpdate(PresenceUpdateEvent),
+    Unknown(String, Value),
 }
 
 impl Event {
-    pub fn decode(kind: &str, value: WrappedValue) -> Result<Self> {
+    pub fn decode(kind: &str, value: Value) -> Result<Self> {
         match kind {
-            "READY" => Ok(Self::Ready(value.to_decoder(ReadyEvent::decode)?)),
-            // "SESSIONS_REPLACE" => Ok(Self::SessionsReplace(value.to_map()?.get(, ))),
+            "READY" => Ok(Self::Ready(decode_value(value, &IGNORED_IN_READY)?)),
+            "RESUMED" => Ok(Self::Resumed),
+            "MESSAGE_CREATE" => Ok(Self::MessageCreate(decode_value(
+                value,
+                &IGNORED_IN_MESSAGE,
+            )?)),
+            // the remaining fields of a full message are not useful for updating one
+            "MESSAGE_UPDATE" => Ok(Self::MessageUpdate(decode_partial_value(value)?)),
+            "MESSAGE_DELETE" => Ok(Self::MessageDelete(decode_value(value, &Ignored::NONE)?)),
+            "MESSAGE_DELETE_BULK" => Ok(Self::MessageDeleteBulk(decode_value(
+                value,
+                &Ignored::NONE,
+            )?)),
+            "LAST_MESSAGES" => Ok(Self::LastMessages(decode_value(
+                value,
+                &IGNORED_IN_LAST_MESSAGES,
+            )?)),
+            "GUILD_CREATE" => Ok(Self::ServerCreate(decode_value(value, &Ignored::NONE)?)),
+            "GUILD_UPDATE" => Ok(Self::ServerUpdate(decode_value(value, &IGNORED_IN_SERVER)?)),
+            "GUILD_DELETE" => Ok(Self::ServerDelete(decode_value(value, &Ignored::NONE)?)),
+            "GUILD_ROLE_CREATE" => Ok(Self::ServerRoleCreate(decode_value(
+                value,
+                &IGNORED_IN_ROLE_EVENT,
+            )?)),
+            "GUILD_ROLE_UPDATE" => Ok(Self::ServerRoleUpdate(decode_value(
+                value,
+                &IGNORED_IN_ROLE_EVENT,
+            )?)),
+            "GUILD_ROLE_DELETE" => Ok(Self::ServerRoleDelete(decode_value(value, &Ignored::NONE)?)),
+            "GUILD_EMOJIS_UPDATE" => Ok(Self::ServerEmojisUpdate(decode_value(
+                value,
+                &Ignored::NONE,
+            )?)),
+            "GUILD_STICKERS_UPDATE" => Ok(Self::ServerStickersUpdate(decode_value(
+                value,
+                &IGNORED_IN_STICKERS_EVENT,
+            )?)),
+            "GUILD_MEMBER_ADD" => Ok(Self::ServerMemberAdd(decode_value(
+                value,
+                &IGNORED_IN_MEMBER,
+            )?)),
+            "GUILD_MEMBER_UPDATE" => Ok(Self::ServerMemberUpdate(decode_value(
+                value,
+                &IGNORED_IN_MEMBER,
+            )?)),
+            "GUILD_MEMBER_REMOVE" => Ok(Self::ServerMemberRemove(decode_value(
+                value,
+                &Ignored::NONE,
+            )?)),
+            "GUILD_MEMBERS_CHUNK" => Ok(Self::ServerMembersChunk(decode_value(
+                value,
+                &IGNORED_IN_MEMBERS_CHUNK,
+            )?)),
+            "CHANNEL_CREATE" => Ok(Self::ChannelCreate(decode_value(value, &Ignored::NONE)?)),
+            "CHANNEL_UPDATE" => Ok(Self::ChannelUpdate(decode_value(value, &Ignored::NONE)?)),
+            "CHANNEL_DELETE" => Ok(Self::ChannelDelete(decode_value(value, &Ignored::NONE)?)),
+            "RELATIONSHIP_ADD" => Ok(Self::RelationshipAdd(decode_value(value, &Ignored::NONE)?)),
+            "RELATIONSHIP_REMOVE" => Ok(Self::RelationshipRemove(decode_value(
+                value,
+                &IGNORED_IN_RELATIONSHIP_REMOVE,
+            )?)),
+            // the rest of the user is only sent when it changed
+            "PRESENCE_UPDATE" => Ok(Self::PresenceUpdate(decode_partial_value(value)?)),
             _ => {
-                println!("unknown event: {:?}", kind);
+                unknown(format!("unknown event {}", kind))?;
                 Ok(Event::Unknown(kind.to_string(), value))
             }
         }
@@ -1586,92 +1920,331 @@ impl Event {
 
 #[derive(Debug)]
 pub enum GatewayEvent {
-    Dispatch(usize, Event),
+    Dispatch(usize, Box<Event>),
+    /// a dispatch that could not be decoded, its sequence still counts for resuming
+    DispatchError(usize, Error),
     Heartbeat(usize),
     Reconnect,
-    InvalidateSession,
+    /// true if the session can still be resumed
+    InvalidateSession(bool),
     Hello(usize),
     HeartbeatAck,
 }
 
+/// what every gateway message is wrapped in, `d` is decoded once `op` and `t` are known
+#[derive(Deserialize)]
+struct GatewayPayload {
+    d: Option<Value>,
+    op: u64,
+    s: Option<usize>,
+    t: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Hello {
+    heartbeat_interval: usize,
+}
+
+static IGNORED_IN_HELLO: Ignored = Ignored {
+    fields: &["_trace"],
+    nested: &[],
+};
+
 impl GatewayEvent {
-    pub fn decode(mut map: WrappedMap) -> Result<Self> {
-        Ok(
-            match map
-                .get_value("op", WrappedValue::to_u64)
-                .expect("op is null in GatewayEvent")
-            {
-                Ok(0) => GatewayEvent::Dispatch(
-                    map.get_value("s", WrappedValue::to_u64)
-                        .expect("s not found in websocket message")? as usize,
-                    Event::decode(
-                        &map.get_value("t", WrappedValue::to_string)
-                            .expect("t not found in websocket message")?,
-                        map.get_value("d", |x| Ok(x))
-                            .expect("d not found in websocket message")?,
-                    )?,
-                ),
-                Ok(1) => GatewayEvent::Heartbeat(
-                    map.get_value("s", WrappedValue::to_u64)
-                        .expect("s not found in websocket message")? as usize,
-                ),
-                Ok(7) => GatewayEvent::Reconnect,
-                Ok(9) => GatewayEvent::InvalidateSession,
-                Ok(10) => GatewayEvent::Hello(
-                    map.get_value("d", |x| {
-                        x.to_map()?
-                            .get_value("heartbeat_interval", WrappedValue::to_u64)
-                            .expect("heartbeat_interval not found in websocket message")
-                    })
-                    .expect("d not found in websocket message")? as usize,
-                ),
-                Ok(11) => Self::HeartbeatAck,
-                _ => return Err(Error::msg("unexpected opcode")),
-            },
-        )
+    pub fn decode(value: Value) -> Result<Self> {
+        let payload = decode_value::<GatewayPayload>(value, &Ignored::NONE)?;
+        let missing = |key| Error::decode("missing").in_field(key);
+        Ok(match payload.op {
+            0 => {
+                let sequence = payload.s.ok_or_else(|| missing("s"))?;
+                let event = payload.t.ok_or_else(|| missing("t")).and_then(|kind| {
+                    let data = payload.d.ok_or_else(|| missing("d"))?;
+                    Event::decode(&kind, data).map_err(|e| e.in_field("d"))
+                });
+                match event {
+                    Ok(event) => GatewayEvent::Dispatch(sequence, Box::new(event)),
+                    Err(err) => GatewayEvent::DispatchError(sequence, err),
+                }
+            }
+            // the gateway sends null when asking for a heartbeat
+            1 => GatewayEvent::Heartbeat(payload.s.unwrap_or_default()),
+            7 => GatewayEvent::Reconnect,
+            9 => GatewayEvent::InvalidateSession(
+                payload
+                    .d
+                    .map(|x| decode_value::<bool>(x, &Ignored::NONE))
+                    .transpose()
+                    .map_err(|e| e.in_field("d"))?
+                    .unwrap_or(false),
+            ),
+            10 => GatewayEvent::Hello(
+                decode_value::<Hello>(payload.d.ok_or_else(|| missing("d"))?, &IGNORED_IN_HELLO)
+                    .map_err(|e| e.in_field("d"))?
+                    .heartbeat_interval,
+            ),
+            11 => Self::HeartbeatAck,
+            other => {
+                return Err(Error::decode(format!("unexpected opcode {}", other)).in_field("op"))
+            }
+        })
     }
 }
 
-pub async fn receive_json<F, T>(ws_receiver: &mut WsReceiver, decode: F) -> Result<T>
+/// waits for the next complete payload, payloads split over several frames are put together
+pub(crate) async fn receive_json<F, T>(
+    ws_receiver: &mut (impl Stream<Item = WsEvent> + Unpin),
+    inflater: &mut Inflater,
+    encoding: Encoding,
+    decode: F,
+) -> Result<T>
 where
-    F: FnOnce(WrappedMap) -> Result<T>,
+    F: FnOnce(Value) -> Result<T>,
 {
-    let received = {
-        let mut a = ws_receiver.try_recv();
-        while a.is_none() {
-            sleep(std::time::Duration::from_millis(300)).await;
-            a = ws_receiver.try_recv();
+    while let Some(received) = ws_receiver.next().await {
+        if let Some(json) = inflate_ws_event(received, inflater, encoding)? {
+            return decode(json);
         }
-        a.unwrap()
-    };
+    }
+    Err(Error::Gateway { close_code: None })
+}
+
+/// None if the event was only part of a payload, etf payloads are decoded into json values
+pub(crate) fn inflate_ws_event(
+    received: WsEvent,
+    inflater: &mut Inflater,
+    encoding: Encoding,
+) -> Result<Option<Value>> {
     match received {
-        WsEvent::Opened => Err(Error::msg("websocket should have already been opened")),
+        WsEvent::Opened => Err(Error::Io("websocket opened twice".into())),
         WsEvent::Message(message) => match message {
-            WsMessage::Binary(bin) => {
-                let mut vec;
-                let text = {
-                    vec = Vec::new();
-                    flate2::read::ZlibDecoder::new(&bin[..]).read_to_end(&mut vec)?;
-                    &vec[..]
-                };
-                serde_json::from_reader(text)
-                    .map_err(From::from)
-                    .and_then(|x| WrappedValue(x).to_decoder(decode))
-                    .map_err(|e| e)
-            }
-            WsMessage::Text(text) => serde_json::from_str(&text)
-                .map_err(From::from)
-                .and_then(|x| WrappedValue(x).to_decoder(decode))
-                .map_err(|e| e),
-            WsMessage::Unknown(_) => todo!(),
-            WsMessage::Ping(vec) => todo!(),
-            WsMessage::Pong(vec) => todo!(),
+            WsMessage::Binary(bin) => match inflater.push(&bin)? {
+                Some(payload) => encoding.decode(&payload).map(Some),
+                None => Ok(None),
+            },
+            WsMessage::Text(text) => Ok(Some(serde_json::from_str(&text)?)),
         },
-        WsEvent::Error(_) => todo!(),
-        WsEvent::Closed => panic!("websocket closed"),
-        // other => {
-        //     println!("websocket message not text or binary: {:?}", other);
-        //     Err(Error::msg("websocket message not text or binary"))
-        // }
+        WsEvent::Error(err) => Err(Error::Io(err.into())),
+        WsEvent::Closed(close_code) => Err(Error::Gateway {
+            close_code: close_code.map(CloseCode::from),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::api::test_util::server_json;
+
+    /// decodes leniently, strict decoding is only turned off for the current test's thread
+    fn lenient<T: DeserializeOwned>(value: Value) -> Result<T> {
+        set_strict_decoding(false);
+        let decoded = decode_value(value, &Ignored::NONE);
+        set_strict_decoding(true);
+        decoded
+    }
+
+    #[test]
+    fn unknown_fields_are_errors_when_strict() {
+        let timestamp = json!({"end": null, "start": 1, "duration": 5});
+        let err = decode_value::<Timestamp>(timestamp.clone(), &Ignored::NONE).unwrap_err();
+        assert!(err.to_string().contains("duration"), "{}", err);
+        assert_eq!(
+            lenient::<Timestamp>(timestamp).unwrap(),
+            Timestamp {
+                end: None,
+                start: Some(1)
+            }
+        );
+    }
+
+    #[test]
+    fn snowflakes_round_trip() {
+        // the example from discord's documentation
+        let id = UserId(175928847299117063);
+        let created_at = DateTime::from_timestamp_millis(1462015105796).unwrap();
+        assert_eq!(id.created_at(), created_at);
+        assert_eq!(id.worker_id(), 1);
+        assert_eq!(id.process_id(), 0);
+        assert_eq!(id.increment(), 7);
+
+        let earliest = UserId::from_timestamp(created_at);
+        assert_eq!(earliest.0, id.0 >> 22 << 22);
+        assert_eq!(earliest.created_at(), created_at);
+        assert_eq!(
+            (
+                earliest.worker_id(),
+                earliest.process_id(),
+                earliest.increment()
+            ),
+            (0, 0, 0)
+        );
+        let before_discord = DateTime::from_timestamp_millis(0).unwrap();
+        assert_eq!(UserId::from_timestamp(before_discord), UserId(0));
+
+        assert_eq!(id.to_string().parse::<UserId>().unwrap(), id);
+        assert_eq!(
+            serde_json::to_value(id).unwrap(),
+            json!("175928847299117063")
+        );
+        assert_eq!(
+            decode_value::<UserId>(json!("175928847299117063"), &Ignored::NONE).unwrap(),
+            id
+        );
+    }
+
+    #[test]
+    fn cdn_urls() {
+        let mut server = server_json();
+        server["icon"] = json!("a1b2");
+        server["emojis"] = json!([
+            {"id": "20", "name": "still"},
+            {"animated": true, "id": "21", "name": "moving"},
+        ]);
+        let server = decode_value::<Server>(server, &Ignored::NONE).unwrap();
+        assert_eq!(
+            server.icon_url().unwrap(),
+            "https://cdn.discordapp.com/icons/10/a1b2.jpg"
+        );
+        assert_eq!(
+            server.emojis[0].url(),
+            "https://cdn.discordapp.com/emojis/20.png"
+        );
+        assert_eq!(
+            server.emojis[1].url(),
+            "https://cdn.discordapp.com/emojis/21.gif"
+        );
+    }
+
+    #[test]
+    fn ignored_fields_are_per_type() {
+        assert!(IGNORED_IN_MESSAGE.contains(&["components"]));
+        assert!(IGNORED_IN_MESSAGE.contains(&["embeds", "image", "flags"]));
+        assert!(IGNORED_IN_MESSAGE.contains(&["referenced_message", "reactions", "count_details"]));
+        assert!(!IGNORED_IN_MESSAGE.contains(&["embeds", "flags"]));
+        assert!(!IGNORED_IN_MESSAGE.contains(&["author", "components"]));
+
+        let role = json!({
+            "color": 0,
+            "hoist": false,
+            "id": "2",
+            "managed": false,
+            "mentionable": false,
+            "name": "role",
+            "permissions": "0",
+            "position": 0,
+            "tags": {},
+            "version": 1,
+        });
+        let event = json!({"guild_id": "1", "role": role});
+        assert!(Event::decode("GUILD_ROLE_UPDATE", event.clone()).is_ok());
+        let mut versioned = event;
+        versioned["version"] = json!(1);
+        let err = Event::decode("GUILD_ROLE_UPDATE", versioned).unwrap_err();
+        assert!(err.to_string().contains("version"), "{}", err);
+    }
+
+    #[test]
+    fn strict_decoding_is_per_thread() {
+        set_strict_decoding(false);
+        let other = std::thread::spawn(|| {
+            decode_value::<VerificationLevel>(json!(9), &Ignored::NONE).is_err()
+        });
+        assert!(other.join().unwrap());
+        assert_eq!(
+            decode_value::<VerificationLevel>(json!(9), &Ignored::NONE).unwrap(),
+            VerificationLevel::Unknown(9)
+        );
+    }
+
+    #[test]
+    fn unknown_values_are_kept() {
+        assert!(decode_value::<VerificationLevel>(json!(9), &Ignored::NONE).is_err());
+        let level = lenient::<VerificationLevel>(json!(9)).unwrap();
+        assert_eq!(level, VerificationLevel::Unknown(9));
+        assert_eq!(serde_json::to_value(level).unwrap(), json!(9));
+
+        assert!(decode_value::<Status>(json!("streaming"), &Ignored::NONE).is_err());
+        let status = lenient::<Status>(json!("streaming")).unwrap();
+        assert_eq!(status, Status::Unknown("streaming".to_owned()));
+        assert_eq!(serde_json::to_value(status).unwrap(), json!("streaming"));
+
+        let overwrite = json!({"allow": "0", "deny": "0", "id": "1", "type": 2});
+        assert!(decode_value::<PermissionOverwrite>(overwrite.clone(), &Ignored::NONE).is_err());
+        assert_eq!(
+            lenient::<PermissionOverwrite>(overwrite).unwrap(),
+            PermissionOverwrite::Unknown(2)
+        );
+        let overwrite = json!({"allow": "0", "deny": "0", "id": "1", "type": "channel"});
+        assert!(decode_value::<PermissionOverwrite>(overwrite.clone(), &Ignored::NONE).is_err());
+        assert_eq!(
+            lenient::<PermissionOverwrite>(overwrite).unwrap(),
+            PermissionOverwrite::Unknown(UNKNOWN_OVERWRITE_NAME)
+        );
+
+        let channel = json!({"id": "5", "name": "new", "type": 99});
+        assert!(decode_value::<Channel>(channel.clone(), &Ignored::NONE).is_err());
+        let channel = lenient::<Channel>(channel).unwrap();
+        assert_eq!(
+            channel,
+            Channel::Unknown(ChannelType::Unknown(99), ChannelId(5))
+        );
+        assert_eq!(channel.id(), ChannelId(5));
+    }
+
+    #[test]
+    fn new_groups_have_no_last_message() {
+        let group = json!({
+            "blocked_user_warning_dismissed": false,
+            "flags": 0,
+            "icon": null,
+            "id": "7",
+            "last_message_id": null,
+            "last_pin_timestamp": null,
+            "name": null,
+            "owner_id": "1",
+            "recipient_flags": 0,
+            "recipients": [],
+            "type": 3,
+        });
+        let channel = decode_value::<Channel>(group, &Ignored::NONE).unwrap();
+        assert!(matches!(channel, Channel::Group(ref x) if x.last_message_id.is_none()));
+    }
+
+    #[test]
+    fn news_channels_round_trip() {
+        let news = json!({
+            "flags": 0,
+            "id": "6",
+            "last_message_id": null,
+            "name": "announcements",
+            "parent_id": null,
+            "permission_overwrites": [],
+            "position": 0,
+            "rate_limit_per_user": 0,
+            "topic": null,
+            "type": 5,
+            "version": 0,
+        });
+        let channel = decode_value::<Channel>(news.clone(), &Ignored::NONE).unwrap();
+        assert!(matches!(channel, Channel::News(ref x) if x.name == "announcements"));
+        assert_eq!(serde_json::to_value(&channel).unwrap(), news);
+        assert_eq!(
+            decode_value::<Channel>(serde_json::to_value(&channel).unwrap(), &Ignored::NONE)
+                .unwrap(),
+            channel
+        );
+    }
+
+    #[test]
+    fn unknown_events_are_kept() {
+        let data = json!({"id": "1"});
+        assert!(Event::decode("SOMETHING_NEW", data.clone()).is_err());
+        set_strict_decoding(false);
+        let event = Event::decode("SOMETHING_NEW", data.clone());
+        set_strict_decoding(true);
+        assert!(matches!(event, Ok(Event::Unknown(ref kind, ref value))
+            if kind == "SOMETHING_NEW" && *value == data));
     }
 }
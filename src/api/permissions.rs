@@ -0,0 +1,328 @@
+use bitflags::bitflags;
+use chrono::Utc;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use super::model::{u64_or_string, Channel, Member, PermissionOverwrite, Server, UserId};
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+    pub struct Permissions: u64 {
+        const CREATE_INVITE = 1 << 0;
+        const KICK_MEMBERS = 1 << 1;
+        const BAN_MEMBERS = 1 << 2;
+        const ADMINISTRATOR = 1 << 3;
+        const MANAGE_CHANNELS = 1 << 4;
+        const MANAGE_SERVER = 1 << 5;
+        const ADD_REACTIONS = 1 << 6;
+        const VIEW_AUDIT_LOG = 1 << 7;
+        const PRIORITY_SPEAKER = 1 << 8;
+        const VIDEO = 1 << 9;
+        const VIEW_CHANNEL = 1 << 10;
+        const SEND_MESSAGES = 1 << 11;
+        const SEND_TTS_MESSAGES = 1 << 12;
+        const MANAGE_MESSAGES = 1 << 13;
+        const EMBED_LINKS = 1 << 14;
+        const ATTACH_FILES = 1 << 15;
+        const READ_MESSAGE_HISTORY = 1 << 16;
+        const MENTION_EVERYONE = 1 << 17;
+        const USE_EXTERNAL_EMOJIS = 1 << 18;
+        const VIEW_SERVER_INSIGHTS = 1 << 19;
+        const CONNECT = 1 << 20;
+        const SPEAK = 1 << 21;
+        const MUTE_MEMBERS = 1 << 22;
+        const DEAFEN_MEMBERS = 1 << 23;
+        const MOVE_MEMBERS = 1 << 24;
+        const USE_VOICE_ACTIVITY = 1 << 25;
+        const CHANGE_NICKNAME = 1 << 26;
+        const MANAGE_NICKNAMES = 1 << 27;
+        const MANAGE_ROLES = 1 << 28;
+        const MANAGE_WEBHOOKS = 1 << 29;
+        const MANAGE_EXPRESSIONS = 1 << 30;
+        const USE_APPLICATION_COMMANDS = 1 << 31;
+        const REQUEST_TO_SPEAK = 1 << 32;
+        const MANAGE_EVENTS = 1 << 33;
+        const MANAGE_THREADS = 1 << 34;
+        const CREATE_PUBLIC_THREADS = 1 << 35;
+        const CREATE_PRIVATE_THREADS = 1 << 36;
+        const USE_EXTERNAL_STICKERS = 1 << 37;
+        const SEND_MESSAGES_IN_THREADS = 1 << 38;
+        const USE_ACTIVITIES = 1 << 39;
+        const TIMEOUT_MEMBERS = 1 << 40;
+        const VIEW_CREATOR_MONETIZATION_ANALYTICS = 1 << 41;
+        const USE_SOUNDBOARD = 1 << 42;
+        const CREATE_EXPRESSIONS = 1 << 43;
+        const CREATE_EVENTS = 1 << 44;
+        const USE_EXTERNAL_SOUNDS = 1 << 45;
+        const SEND_VOICE_MESSAGES = 1 << 46;
+        const CREATE_POLLS = 1 << 49;
+        const USE_EXTERNAL_APPS = 1 << 50;
+    }
+}
+
+/// sent as a string since they don't fit in a javascript number, bits that are not known yet are kept
+impl Serialize for Permissions {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&self.bits())
+    }
+}
+
+impl<'de> Deserialize<'de> for Permissions {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        u64_or_string(deserializer).map(Permissions::from_bits_retain)
+    }
+}
+
+/// permissions that depend on being able to send messages
+const SEND_DEPENDENT: Permissions = Permissions::SEND_TTS_MESSAGES
+    .union(Permissions::MENTION_EVERYONE)
+    .union(Permissions::EMBED_LINKS)
+    .union(Permissions::ATTACH_FILES);
+
+/// what a timed out member is still allowed to do
+const TIMED_OUT: Permissions = Permissions::VIEW_CHANNEL.union(Permissions::READ_MESSAGE_HISTORY);
+
+/// permissions of `member` in the server itself, ignoring channel overwrites
+pub fn server_permissions(server: &Server, user: UserId, member: &Member) -> Permissions {
+    if server.owner_id == user {
+        return Permissions::all();
+    }
+    let everyone = server.id.everyone();
+    let permissions = server
+        .roles
+        .iter()
+        .filter(|x| x.id == everyone || member.roles.contains(&x.id))
+        .fold(Permissions::empty(), |acc, x| acc | x.permissions);
+    if permissions.contains(Permissions::ADMINISTRATOR) {
+        return Permissions::all();
+    }
+    permissions
+}
+
+/// permissions of `member` in `channel`, applying overwrites in the order discord does:
+/// @everyone, then the member's roles together, then the member itself.
+/// overwrites of unknown types are skipped.
+/// threads take the overwrites of their parent, so pass the parent channel for them
+pub fn channel_permissions(
+    server: &Server,
+    user: UserId,
+    member: &Member,
+    channel: &Channel,
+) -> Permissions {
+    let base = server_permissions(server, user, member);
+    if base.contains(Permissions::ADMINISTRATOR) {
+        return Permissions::all();
+    }
+    let mut permissions =
+        apply_overwrites(base, server, user, member, channel.permission_overwrites());
+
+    let timed_out = member
+        .communication_disabled_until
+        .is_some_and(|x| x > Utc::now());
+    if timed_out {
+        permissions &= TIMED_OUT;
+    }
+    if !permissions.contains(Permissions::VIEW_CHANNEL) {
+        return Permissions::empty();
+    }
+    if !permissions.contains(Permissions::SEND_MESSAGES) {
+        permissions.remove(SEND_DEPENDENT);
+    }
+    permissions
+}
+
+fn apply_overwrites(
+    mut permissions: Permissions,
+    server: &Server,
+    user: UserId,
+    member: &Member,
+    overwrites: &[PermissionOverwrite],
+) -> Permissions {
+    let everyone = server.id.everyone();
+    if let Some(overwrite) = overwrites
+        .iter()
+        .find(|x| matches!(x, PermissionOverwrite::Role(role) if role.id == everyone))
+    {
+        permissions.remove(overwrite.deny());
+        permissions.insert(overwrite.allow());
+    }
+
+    let (allow, deny) = overwrites
+        .iter()
+        .filter(|x| matches!(x, PermissionOverwrite::Role(role) if member.roles.contains(&role.id)))
+        .fold((Permissions::empty(), Permissions::empty()), |acc, x| {
+            (acc.0 | x.allow(), acc.1 | x.deny())
+        });
+    permissions.remove(deny);
+    permissions.insert(allow);
+
+    if let Some(overwrite) = overwrites
+        .iter()
+        .find(|x| matches!(x, PermissionOverwrite::Member(member) if member.id == user))
+    {
+        permissions.remove(overwrite.deny());
+        permissions.insert(overwrite.allow());
+    }
+    permissions
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::api::{
+        model::{decode_value, Ignored},
+        test_util::{channel_json, role_json, server_json},
+    };
+
+    const OWNER: UserId = UserId(1);
+    const USER: UserId = UserId(2);
+
+    fn bits(permissions: Permissions) -> String {
+        permissions.bits().to_string()
+    }
+
+    /// the @everyone role of the server has the id of the server
+    fn server(everyone: Permissions, roles: &[Value]) -> Server {
+        let mut all_roles = vec![role_json(10, "@everyone", everyone)];
+        all_roles.extend_from_slice(roles);
+        let mut server = server_json();
+        server["owner_id"] = json!(OWNER.0.to_string());
+        server["roles"] = all_roles.into();
+        decode_value(server, &Ignored::NONE).unwrap()
+    }
+
+    fn member(roles: &[u64]) -> Member {
+        let roles = roles.iter().map(u64::to_string).collect::<Vec<_>>();
+        decode_value(json!({"roles": roles}), &Ignored::NONE).unwrap()
+    }
+
+    /// kind is 0 for roles and 1 for members
+    fn overwrite(id: u64, kind: u64, allow: Permissions, deny: Permissions) -> Value {
+        json!({"allow": bits(allow), "deny": bits(deny), "id": id.to_string(), "type": kind})
+    }
+
+    fn channel(overwrites: &[Value]) -> Channel {
+        decode_value(channel_json(30, overwrites), &Ignored::NONE).unwrap()
+    }
+
+    const TEXT: Permissions = Permissions::VIEW_CHANNEL
+        .union(Permissions::SEND_MESSAGES)
+        .union(Permissions::READ_MESSAGE_HISTORY)
+        .union(Permissions::ATTACH_FILES)
+        .union(Permissions::EMBED_LINKS);
+
+    #[test]
+    fn owner_bypasses_overwrites() {
+        let server = server(Permissions::empty(), &[]);
+        let channel = channel(&[overwrite(10, 0, Permissions::empty(), Permissions::all())]);
+        assert_eq!(
+            channel_permissions(&server, OWNER, &member(&[]), &channel),
+            Permissions::all()
+        );
+    }
+
+    #[test]
+    fn administrator_bypasses_overwrites() {
+        let server = server(TEXT, &[role_json(20, "admin", Permissions::ADMINISTRATOR)]);
+        let channel = channel(&[
+            overwrite(10, 0, Permissions::empty(), Permissions::all()),
+            overwrite(USER.0, 1, Permissions::empty(), Permissions::all()),
+        ]);
+        assert_eq!(
+            channel_permissions(&server, USER, &member(&[20]), &channel),
+            Permissions::all()
+        );
+        assert_eq!(
+            channel_permissions(&server, USER, &member(&[]), &channel),
+            Permissions::empty()
+        );
+    }
+
+    #[test]
+    fn overwrites_apply_everyone_then_roles_then_member() {
+        let server = server(TEXT, &[]);
+        let member = member(&[20, 21]);
+        let send = Permissions::SEND_MESSAGES;
+        let none = Permissions::empty();
+        let everyone = overwrite(10, 0, none, send);
+        // an allow of one role wins over a deny of another
+        let roles = [overwrite(20, 0, send, none), overwrite(21, 0, none, send)];
+
+        let mut overwrites = vec![everyone];
+        let permissions = channel_permissions(&server, USER, &member, &channel(&overwrites));
+        assert!(!permissions.contains(send));
+
+        overwrites.extend_from_slice(&roles);
+        let permissions = channel_permissions(&server, USER, &member, &channel(&overwrites));
+        assert!(permissions.contains(send));
+
+        overwrites.push(overwrite(USER.0, 1, none, send));
+        let permissions = channel_permissions(&server, USER, &member, &channel(&overwrites));
+        assert!(!permissions.contains(send));
+
+        // overwrites of other members and roles the member doesn't have are not applied
+        let others = [overwrite(22, 0, none, send), overwrite(3, 1, none, send)];
+        let permissions = channel_permissions(&server, USER, &member, &channel(&others));
+        assert!(permissions.contains(send));
+    }
+
+    #[test]
+    fn unknown_overwrites_are_skipped() {
+        let server = server(TEXT, &[]);
+        let mut channel = channel(&[]);
+        if let Channel::Public(ref mut channel) = channel {
+            channel
+                .permission_overwrites
+                .push(PermissionOverwrite::Unknown(2));
+        }
+        assert_eq!(channel.permission_overwrites().len(), 1);
+        assert_eq!(
+            channel_permissions(&server, USER, &member(&[]), &channel),
+            TEXT
+        );
+    }
+
+    #[test]
+    fn timeouts_leave_reading() {
+        let server = server(TEXT, &[]);
+        let mut member = member(&[]);
+        member.communication_disabled_until = Some("2100-01-01T00:00:00+00:00".parse().unwrap());
+        assert_eq!(
+            channel_permissions(&server, USER, &member, &channel(&[])),
+            Permissions::VIEW_CHANNEL | Permissions::READ_MESSAGE_HISTORY
+        );
+
+        member.communication_disabled_until = Some("2000-01-01T00:00:00+00:00".parse().unwrap());
+        assert_eq!(
+            channel_permissions(&server, USER, &member, &channel(&[])),
+            TEXT
+        );
+    }
+
+    #[test]
+    fn permissions_depend_on_viewing_and_sending() {
+        let server = server(TEXT, &[]);
+        let hidden = channel(&[overwrite(
+            10,
+            0,
+            Permissions::empty(),
+            Permissions::VIEW_CHANNEL,
+        )]);
+        assert_eq!(
+            channel_permissions(&server, USER, &member(&[]), &hidden),
+            Permissions::empty()
+        );
+
+        let read_only = channel(&[overwrite(
+            10,
+            0,
+            Permissions::empty(),
+            Permissions::SEND_MESSAGES,
+        )]);
+        assert_eq!(
+            channel_permissions(&server, USER, &member(&[]), &read_only),
+            Permissions::VIEW_CHANNEL | Permissions::READ_MESSAGE_HISTORY
+        );
+    }
+}
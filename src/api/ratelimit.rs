@@ -1,107 +1,464 @@
-use std::{collections::BTreeMap, sync::Mutex};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use chrono::Utc;
+use futures::channel::oneshot;
+use reqwest::{header::HeaderMap, Method, Response};
+use web_time::Instant;
+
+use super::runtime::sleep;
+
+/// parameters that get their own limit instead of sharing it with the rest of the route
+const MAJOR_PARAMETERS: [&str; 3] = ["channels", "guilds", "webhooks"];
+/// longer waits from the headers are clamped, so a bad header can't block forever or overflow
+const MAX_WAIT: Duration = Duration::from_secs(60 * 60);
 
 #[derive(Default, Debug)]
 pub struct RateLimits {
-    global: Mutex<RateLimit>,
-    endpoints: Mutex<BTreeMap<String, RateLimit>>,
+    /// a global 429 blocks every request until then
+    global: Mutex<Option<Instant>>,
+    /// bucket hashes learned from X-RateLimit-Bucket, routes with the same hash share a limit
+    hashes: Mutex<BTreeMap<String, String>>,
+    /// keyed by the bucket hash, or the route until the hash is known, plus major parameters
+    buckets: Mutex<BTreeMap<String, Arc<Bucket>>>,
 }
 
 impl RateLimits {
-    pub fn pre_check(&self, url: &str) {
-        self.global.lock().expect("Ratelimits poisoned").pre_check();
-        if let Some(ratelimit) = self
-            .endpoints
-            .lock()
-            .expect("Ratelimits poisoned")
-            .get_mut(url)
-        {
-            ratelimit.pre_check();
+    /// waits until a request is allowed, requests in other buckets are not held up.
+    /// keep the returned turn until the response has been checked
+    pub async fn pre_check(&self, method: &Method, path: &str) -> Option<Turn> {
+        loop {
+            let blocked_until = *self.global.lock().expect("Ratelimits poisoned");
+            match blocked_until.map(|x| x.saturating_duration_since(Instant::now())) {
+                Some(delay) if !delay.is_zero() => sleep(delay).await,
+                _ => break,
+            }
         }
+        self.bucket(&Route::new(method, path)).pre_check().await
     }
 
-    pub fn check_for_ratelimit(&self, url: &str, response: &reqwest::Response) -> bool {
-        if response.headers().contains_key("X-RateLimit-Global") {
-            self.global
+    /// returns how long to wait before retrying if the response is a 429
+    pub fn check_for_ratelimit(
+        &self,
+        method: &Method,
+        path: &str,
+        response: &Response,
+    ) -> Option<Duration> {
+        let route = Route::new(method, path);
+        if let Some(hash) = header(response.headers(), "X-RateLimit-Bucket") {
+            self.learn_hash(&route, hash);
+        }
+        let bucket = self.bucket(&route);
+        bucket
+            .ratelimit
+            .lock()
+            .expect("Ratelimits poisoned")
+            .update(response.headers());
+
+        if response.status() != 429 {
+            return None;
+        }
+        // discord sends the seconds to wait, the +100ms accounts for clock drift
+        let retry_after = seconds(response.headers(), "Retry-After")
+            .unwrap_or(Duration::from_secs(1))
+            + Duration::from_millis(100);
+        let scope = header(response.headers(), "X-RateLimit-Scope");
+        if response.headers().contains_key("X-RateLimit-Global") || scope == Some("global") {
+            *self.global.lock().expect("Ratelimits poisoned") = Some(after(retry_after));
+        } else if scope != Some("shared") {
+            // a shared limit is on the resource and does not say anything about our bucket
+            bucket
+                .ratelimit
                 .lock()
                 .expect("Ratelimits poisoned")
-                .check_for_ratelimit(response)
-        } else {
-            self.endpoints
+                .exhaust(retry_after);
+        }
+        Some(retry_after)
+    }
+
+    fn bucket(&self, route: &Route) -> Arc<Bucket> {
+        let key = self.key(route);
+        self.buckets
+            .lock()
+            .expect("Ratelimits poisoned")
+            .entry(key)
+            .or_default()
+            .clone()
+    }
+
+    fn key(&self, route: &Route) -> String {
+        let hashes = self.hashes.lock().expect("Ratelimits poisoned");
+        let bucket = hashes.get(&route.template).unwrap_or(&route.template);
+        format!("{}:{}", bucket, route.major)
+    }
+
+    /// moves the limit tracked for the route over to the shared bucket
+    fn learn_hash(&self, route: &Route, hash: &str) {
+        let old_key = self.key(route);
+        self.hashes
+            .lock()
+            .expect("Ratelimits poisoned")
+            .insert(route.template.clone(), hash.to_owned());
+        let new_key = self.key(route);
+        if old_key != new_key {
+            let mut buckets = self.buckets.lock().expect("Ratelimits poisoned");
+            if let Some(bucket) = buckets.remove(&old_key) {
+                buckets.entry(new_key).or_insert(bucket);
+            }
+        }
+    }
+}
+
+/// what a request counts against: `GET channels/1/messages/2` has the template
+/// `GET channels/{}/messages/{id}` and the major parameters `1`
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Route {
+    template: String,
+    major: String,
+}
+
+impl Route {
+    fn new(method: &Method, path: &str) -> Self {
+        let path = path.split('?').next().unwrap_or_default();
+        let mut template = Vec::new();
+        let mut major = Vec::new();
+        let mut segments = path.split('/').filter(|x| !x.is_empty());
+        while let Some(segment) = segments.next() {
+            template.push(segment);
+            if !MAJOR_PARAMETERS.contains(&segment) {
+                continue;
+            }
+            if let Some(id) = segments.next() {
+                template.push("{}");
+                major.push(id);
+            }
+            // a webhook is identified by its id and token together
+            if segment == "webhooks" {
+                if let Some(token) = segments.next() {
+                    template.push("{}");
+                    major.push(token);
+                }
+            }
+        }
+        // other ids, like the message in channels/{}/messages/{id}, share the limit
+        let template = template
+            .into_iter()
+            .map(|x| match x.chars().all(|x| x.is_ascii_digit()) {
+                true => "{id}",
+                false => x,
+            })
+            .collect::<Vec<_>>()
+            .join("/");
+        Self {
+            template: format!("{} {}", method, template),
+            major: major.join("/"),
+        }
+    }
+}
+
+/// a ratelimit and the requests waiting on it
+#[derive(Default, Debug)]
+struct Bucket {
+    ratelimit: Mutex<RateLimit>,
+    queue: Mutex<Queue>,
+}
+
+/// hands out turns in the order they were asked for
+#[derive(Default, Debug)]
+struct Queue {
+    busy: bool,
+    waiting: VecDeque<oneshot::Sender<()>>,
+}
+
+impl Bucket {
+    /// waits for a turn, then for the ratelimit to have a request remaining.
+    /// while the limit is unknown the turn is kept, so only one request goes out
+    /// until its response tells us the limit
+    async fn pre_check(self: &Arc<Self>) -> Option<Turn> {
+        let turn = self.turn().await;
+        loop {
+            let delay = self
+                .ratelimit
                 .lock()
                 .expect("Ratelimits poisoned")
-                .entry(url.to_owned())
-                .or_insert_with(RateLimit::default)
-                .check_for_ratelimit(response)
+                .pre_check();
+            match delay {
+                Some(delay) => sleep(delay).await,
+                None => break,
+            }
+        }
+        let unknown = self.ratelimit.lock().expect("Ratelimits poisoned").limit == 0;
+        unknown.then_some(turn)
+    }
+
+    async fn turn(self: &Arc<Self>) -> Turn {
+        let receiver = {
+            let mut queue = self.queue.lock().expect("Ratelimits poisoned");
+            if !queue.busy {
+                queue.busy = true;
+                return Turn(self.clone());
+            }
+            let (sender, receiver) = oneshot::channel();
+            queue.waiting.push_back(sender);
+            receiver
+        };
+        let mut waiter = Waiter {
+            bucket: self,
+            receiver: Some(receiver),
+        };
+        // the sender is only dropped without sending if the bucket is dropped
+        let _ = waiter.receiver.as_mut().unwrap().await;
+        waiter.receiver = None;
+        Turn(self.clone())
+    }
+
+    fn pass_turn(&self) {
+        let mut queue = self.queue.lock().expect("Ratelimits poisoned");
+        while let Some(next) = queue.waiting.pop_front() {
+            // fails if the waiting request was cancelled
+            if next.send(()).is_ok() {
+                return;
+            }
+        }
+        queue.busy = false;
+    }
+}
+
+/// while alive, no one else can take a request from the bucket
+pub struct Turn(Arc<Bucket>);
+
+impl Drop for Turn {
+    fn drop(&mut self) {
+        self.0.pass_turn();
+    }
+}
+
+/// a place in the queue, passes the turn on if dropped after being given it
+struct Waiter<'a> {
+    bucket: &'a Bucket,
+    receiver: Option<oneshot::Receiver<()>>,
+}
+
+impl Drop for Waiter<'_> {
+    fn drop(&mut self) {
+        if let Some(mut receiver) = self.receiver.take() {
+            receiver.close();
+            if let Ok(Some(())) = receiver.try_recv() {
+                self.bucket.pass_turn();
+            }
         }
     }
 }
 
 #[derive(Default, Debug)]
 struct RateLimit {
-    reset: isize,
+    /// 0 until discord told us the limit, one request at a time goes out until then
     limit: isize,
     remaining: isize,
+    reset: Option<Instant>,
 }
 
 impl RateLimit {
-    fn pre_check(&mut self) {
+    /// takes a request from the remaining ones, or returns how long to wait before trying again
+    fn pre_check(&mut self) -> Option<Duration> {
         if self.limit == 0 {
-            // not initialized
-            return;
+            return None;
         }
 
-        let difference = self.reset - Utc::now().timestamp() as isize;
-        if difference < 0 {
-            self.reset += 3;
+        let now = Instant::now();
+        if self.reset.is_some_and(|x| now >= x) {
+            self.reset = None;
             self.remaining = self.limit;
-            return;
         }
 
         if self.remaining <= 0 {
-            let delay = difference as u64 * 1000 + 900;
-            std::thread::sleep(std::time::Duration::from_millis(delay));
+            // without a reset, the responses that will tell us are still on their way
+            return Some(self.reset.map_or(Duration::from_millis(250), |x| {
+                x.saturating_duration_since(now)
+            }));
         }
 
         self.remaining -= 1;
+        None
+    }
+
+    /// responses can arrive out of order and don't know about requests still in flight,
+    /// so within a window `remaining` only goes down. it goes back up in `pre_check`
+    /// once the reset has passed
+    fn update(&mut self, headers: &HeaderMap) {
+        let parse = |name| header(headers, name).and_then(|x| x.parse::<f64>().ok());
+        let known = self.limit != 0;
+        if let Some(limit) = parse("X-RateLimit-Limit") {
+            self.limit = limit as isize;
+        }
+        if let Some(remaining) = parse("X-RateLimit-Remaining") {
+            let remaining = remaining as isize;
+            self.remaining = match known {
+                true => self.remaining.min(remaining),
+                false => remaining,
+            };
+        }
+        // relative to now, so it does not depend on our clock matching discord's
+        if let Some(reset_after) = seconds(headers, "X-RateLimit-Reset-After") {
+            let reset = after(reset_after);
+            self.reset = Some(self.reset.map_or(reset, |x| x.max(reset)));
+        }
+    }
+
+    /// no more requests until `retry_after` has passed
+    fn exhaust(&mut self, retry_after: Duration) {
+        self.limit = self.limit.max(1);
+        self.remaining = 0;
+        self.reset = Some(after(retry_after));
     }
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name)?.to_str().ok()
+}
+
+/// a header in seconds, at most MAX_WAIT
+fn seconds(headers: &HeaderMap, name: &str) -> Option<Duration> {
+    let seconds = header(headers, name)?.parse::<f64>().ok()?;
+    Duration::try_from_secs_f64(seconds.min(MAX_WAIT.as_secs_f64())).ok()
+}
+
+/// now + duration, at most MAX_WAIT away
+fn after(duration: Duration) -> Instant {
+    let now = Instant::now();
+    now.checked_add(duration.min(MAX_WAIT)).unwrap_or(now)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{executor::block_on, poll};
+
+    use super::*;
+
+    fn route(path: &str) -> Route {
+        Route::new(&Method::GET, path)
+    }
+
+    #[test]
+    fn routes_are_keyed_by_template_and_major_parameters() {
+        let messages = route("channels/1/messages/2?around=3");
+        assert_eq!(messages.template, "GET channels/{}/messages/{id}");
+        assert_eq!(messages.major, "1");
+        assert_eq!(route("channels/1/messages/4"), messages);
+        assert_ne!(route("channels/5/messages/2").major, messages.major);
+
+        let webhook = Route::new(&Method::POST, "/webhooks/6/token/messages");
+        assert_eq!(webhook.template, "POST webhooks/{}/{}/messages");
+        assert_eq!(webhook.major, "6/token");
+
+        let members = route("guilds/7/members/8/roles/9");
+        assert_eq!(members.template, "GET guilds/{}/members/{id}/roles/{id}");
+        assert_eq!(members.major, "7");
+
+        let me = route("users/@me");
+        assert_eq!(me.template, "GET users/@me");
+        assert_eq!(me.major, "");
+    }
+
+    #[test]
+    fn learned_hashes_move_the_bucket() {
+        let limits = RateLimits::default();
+        let messages = route("channels/1/messages");
+        let pins = route("channels/1/pins");
+        let bucket = limits.bucket(&messages);
+        bucket.ratelimit.lock().unwrap().limit = 5;
+
+        limits.learn_hash(&messages, "abc");
+        assert!(Arc::ptr_eq(&limits.bucket(&messages), &bucket));
+        assert_eq!(limits.key(&messages), "abc:1");
+        assert_eq!(limits.buckets.lock().unwrap().len(), 1);
+
+        // routes with the same hash share the limit, other major parameters do not
+        limits.learn_hash(&pins, "abc");
+        assert!(Arc::ptr_eq(&limits.bucket(&pins), &bucket));
+        assert!(!Arc::ptr_eq(
+            &limits.bucket(&route("channels/2/pins")),
+            &bucket
+        ));
+    }
+
+    #[test]
+    fn one_request_at_a_time_until_the_limit_is_known() {
+        let limits = RateLimits::default();
+        block_on(async {
+            let first = limits.pre_check(&Method::GET, "channels/1/messages").await;
+            assert!(first.is_some());
+
+            let mut second = Box::pin(limits.pre_check(&Method::GET, "channels/1/messages"));
+            assert!(poll!(&mut second).is_pending());
+
+            // other buckets are not held up
+            let other = limits.pre_check(&Method::GET, "channels/2/messages").await;
+            assert!(other.is_some());
+
+            // the first response told us the limit
+            let bucket = limits.bucket(&route("channels/1/messages"));
+            bucket.ratelimit.lock().unwrap().limit = 5;
+            bucket.ratelimit.lock().unwrap().remaining = 4;
+            drop(first);
+            assert!(second.await.is_none());
+            assert!(limits
+                .pre_check(&Method::GET, "channels/1/messages")
+                .await
+                .is_none());
+            assert_eq!(bucket.ratelimit.lock().unwrap().remaining, 2);
+        });
+    }
+
+    fn headers(remaining: u64, reset_after: f64) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-RateLimit-Limit", 5.into());
+        headers.insert("X-RateLimit-Remaining", remaining.into());
+        headers.insert(
+            "X-RateLimit-Reset-After",
+            reset_after.to_string().parse().unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn responses_of_requests_in_flight_do_not_free_up_requests() {
+        let mut ratelimit = RateLimit::default();
+        ratelimit.update(&headers(4, 1.));
+        for _ in 0..4 {
+            assert!(ratelimit.pre_check().is_none());
+        }
+        let reset = ratelimit.reset.unwrap();
+
+        // the other four answered out of order
+        for remaining in [1, 3, 0, 2] {
+            ratelimit.update(&headers(remaining, 0.5));
+            assert!(ratelimit.pre_check().is_some());
+        }
+        assert_eq!(ratelimit.remaining, 0);
+        assert_eq!(ratelimit.reset, Some(reset));
+
+        // the window resetting frees them up again
+        ratelimit.reset = Some(Instant::now());
+        assert!(ratelimit.pre_check().is_none());
+        assert_eq!(ratelimit.remaining, 4);
+    }
+
+    #[test]
+    fn huge_waits_are_clamped() {
+        let mut ratelimit = RateLimit::default();
+        ratelimit.update(&headers(4, 1e300));
+        assert!(ratelimit.reset.unwrap() <= Instant::now() + MAX_WAIT);
+
+        ratelimit.exhaust(Duration::MAX);
+        assert!(ratelimit.reset.unwrap() <= Instant::now() + MAX_WAIT);
 
-    fn check_for_ratelimit(&mut self, response: &reqwest::Response) -> bool {
-        if let Some(reset) = &response.headers().get("X-RateLimit-Reset") {
-            self.reset = reset
-                .to_str()
-                .unwrap()
-                .parse::<f64>()
-                .expect("unable to parse ratelimit") as isize;
-        }
-        if let Some(limit) = &response.headers().get("X-RateLimit-Limit") {
-            self.limit = limit
-                .to_str()
-                .unwrap()
-                .parse::<f64>()
-                .expect("unable to parse ratelimit") as isize;
-        }
-        if let Some(remaining) = &response.headers().get("X-RateLimit-Remaining") {
-            self.remaining = remaining
-                .to_str()
-                .unwrap()
-                .parse::<f64>()
-                .expect("unable to parse ratelimit") as isize;
-        }
-        if response.status() == 429 {
-            let delay = response
-                .headers()
-                .get("Retry-After")
-                .expect("unable to parse ratelimit")
-                .to_str()
-                .unwrap()
-                .parse::<u64>()
-                .expect("unable to parse ratelimit")
-                + 100;
-            std::thread::sleep(std::time::Duration::from_millis(delay));
-            return true;
-        }
-        false
+        let mut headers = HeaderMap::new();
+        headers.insert("Retry-After", "1e300".parse().unwrap());
+        assert_eq!(seconds(&headers, "Retry-After"), Some(MAX_WAIT));
+        headers.insert("Retry-After", "-1".parse().unwrap());
+        assert_eq!(seconds(&headers, "Retry-After"), None);
     }
 }
@@ -0,0 +1,553 @@
+#[cfg(target_arch = "wasm32")]
+use std::sync::{Arc, Mutex};
+use std::{
+    fs::File,
+    io::{LineWriter, Write},
+    path::Path,
+};
+#[cfg(not(target_arch = "wasm32"))]
+use std::{sync::mpsc, thread};
+
+use secrecy::{ExposeSecret, SecretString};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::{
+    compression::{Compression, Inflater},
+    encoding::Encoding,
+    model::{receive_json, Event, GatewayEvent},
+    runtime,
+    state::State,
+    websocket::{WsEvent, WsMessage},
+    Error, Result,
+};
+
+/// what the token is replaced with in recorded payloads
+const REDACTED: &str = "[redacted]";
+
+/// one line of a recording
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Record {
+    /// a websocket was opened, the records up to the next one belong to it
+    Opened {
+        compression: Compression,
+        encoding: Encoding,
+    },
+    Received {
+        frame: Frame,
+    },
+    Sent {
+        frame: Frame,
+    },
+    Error {
+        message: String,
+    },
+    Closed {
+        code: Option<u16>,
+    },
+}
+
+/// a raw websocket frame, binary frames are written as hex
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Frame {
+    Text(String),
+    Binary(#[serde(with = "hex")] Vec<u8>),
+}
+
+impl From<WsMessage> for Frame {
+    fn from(message: WsMessage) -> Self {
+        match message {
+            WsMessage::Text(text) => Frame::Text(text),
+            WsMessage::Binary(binary) => Frame::Binary(binary),
+        }
+    }
+}
+
+impl From<Frame> for WsMessage {
+    fn from(frame: Frame) -> Self {
+        match frame {
+            Frame::Text(text) => WsMessage::Text(text),
+            Frame::Binary(binary) => WsMessage::Binary(binary),
+        }
+    }
+}
+
+/// writes every frame a [`Connection`](super::Connection) sends and receives as jsonl,
+/// with the token redacted, so it can be played back with [`Replay`]
+#[derive(Clone)]
+pub struct Recorder {
+    output: Output,
+}
+
+impl Recorder {
+    /// overwrites the file if it exists
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let file = File::create(path).map_err(|e| Error::Io(e.into()))?;
+        Ok(Self::new(LineWriter::new(file)))
+    }
+
+    pub fn new(writer: impl Write + Send + 'static) -> Self {
+        Self {
+            output: Output::new(writer),
+        }
+    }
+
+    /// blocks until everything recorded so far is written and flushed
+    pub fn flush(&self) {
+        self.output.flush();
+    }
+
+    pub(crate) fn opened(&self, compression: Compression, encoding: Encoding) {
+        self.write(&Record::Opened {
+            compression,
+            encoding,
+        });
+    }
+
+    pub(crate) fn received(&self, event: &WsEvent) {
+        let record = match *event {
+            // recorded with the compression and encoding by `opened`
+            WsEvent::Opened => return,
+            WsEvent::Message(ref message) => Record::Received {
+                frame: message.clone().into(),
+            },
+            WsEvent::Error(ref message) => Record::Error {
+                message: message.clone(),
+            },
+            WsEvent::Closed(code) => Record::Closed { code },
+        };
+        self.write(&record);
+    }
+
+    /// the payload is encoded again after redacting, so the frame is what was sent minus the token
+    pub(crate) fn sent(&self, payload: &Value, encoding: Encoding, token: &SecretString) {
+        let payload = redact(payload.clone(), token.expose_secret());
+        self.write(&Record::Sent {
+            frame: encoding.encode(&payload).into(),
+        });
+    }
+
+    fn write(&self, record: &Record) {
+        let mut line = serde_json::to_string(record).expect("records are valid json");
+        line.push('\n');
+        self.output.write(line);
+    }
+}
+
+/// writes on a thread of its own, so a slow disk doesn't hold up the gateway.
+/// the thread stops once every clone is dropped
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Clone)]
+struct Output(mpsc::Sender<Command>);
+
+/// what the recording thread is asked to do
+#[cfg(not(target_arch = "wasm32"))]
+enum Command {
+    Write(String),
+    Flush(mpsc::Sender<()>),
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Output {
+    fn new(mut writer: impl Write + Send + 'static) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        thread::Builder::new()
+            .name("recorder".to_owned())
+            .spawn(move || {
+                for command in receiver {
+                    match command {
+                        Command::Write(line) => write_line(&mut writer, &line),
+                        Command::Flush(done) => {
+                            flush(&mut writer);
+                            let _ = done.send(());
+                        }
+                    }
+                }
+                flush(&mut writer);
+            })
+            .expect("unable to start the recording thread");
+        Self(sender)
+    }
+
+    fn write(&self, line: String) {
+        // the thread only stops when all senders are gone
+        let _ = self.0.send(Command::Write(line));
+    }
+
+    fn flush(&self) {
+        let (done, wait) = mpsc::channel();
+        if self.0.send(Command::Flush(done)).is_ok() {
+            let _ = wait.recv();
+        }
+    }
+}
+
+/// the browser has no threads, so it is written right away there
+#[cfg(target_arch = "wasm32")]
+#[derive(Clone)]
+struct Output(Arc<Mutex<Box<dyn Write + Send>>>);
+
+#[cfg(target_arch = "wasm32")]
+impl Output {
+    fn new(writer: impl Write + Send + 'static) -> Self {
+        Self(Arc::new(Mutex::new(Box::new(writer))))
+    }
+
+    fn write(&self, line: String) {
+        write_line(&mut *self.0.lock().expect("recorder poisoned"), &line);
+    }
+
+    fn flush(&self) {
+        flush(&mut *self.0.lock().expect("recorder poisoned"));
+    }
+}
+
+/// a failed write is logged, the connection keeps going without it
+fn write_line(writer: &mut impl Write, line: &str) {
+    if let Err(err) = writer.write_all(line.as_bytes()) {
+        runtime::log(&format!("unable to record: {}", err));
+    }
+}
+
+fn flush(writer: &mut impl Write) {
+    if let Err(err) = writer.flush() {
+        runtime::log(&format!("unable to record: {}", err));
+    }
+}
+
+/// replaces every string that is the token, wherever it is in the payload
+fn redact(value: Value, token: &str) -> Value {
+    match value {
+        Value::String(string) if string == token => REDACTED.into(),
+        Value::Array(array) => array.into_iter().map(|x| redact(x, token)).collect(),
+        Value::Object(map) => map
+            .into_iter()
+            .map(|(key, value)| (key, redact(value, token)))
+            .collect(),
+        other => other,
+    }
+}
+
+/// a recording played back through the same decoding as a live connection, without a network
+#[derive(Debug, Clone)]
+pub struct Replay {
+    /// the received events of each websocket in the recording
+    connections: Vec<(Compression, Encoding, Vec<WsEvent>)>,
+}
+
+impl Replay {
+    pub fn new(recording: &str) -> Result<Self> {
+        let mut connections = Vec::new();
+        for line in recording.lines().filter(|x| !x.trim().is_empty()) {
+            let event = match serde_json::from_str(line)? {
+                Record::Opened {
+                    compression,
+                    encoding,
+                } => {
+                    connections.push((compression, encoding, Vec::new()));
+                    continue;
+                }
+                Record::Received { frame } => WsEvent::Message(frame.into()),
+                Record::Sent { .. } => continue,
+                Record::Error { message } => WsEvent::Error(message),
+                Record::Closed { code } => WsEvent::Closed(code),
+            };
+            let Some((_, _, events)) = connections.last_mut() else {
+                return Err(Error::decode(
+                    "frame recorded before a websocket was opened",
+                ));
+            };
+            events.push(event);
+        }
+        Ok(Self { connections })
+    }
+
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let recording = std::fs::read_to_string(path).map_err(|e| Error::Io(e.into()))?;
+        Self::new(&recording)
+    }
+
+    /// every payload received, in order. a websocket closing or failing ends its part
+    /// of the recording, anything that does not decode is an error
+    pub async fn events(&self) -> Result<Vec<GatewayEvent>> {
+        let mut decoded = Vec::new();
+        for &(compression, encoding, ref events) in &self.connections {
+            let mut inflater = Inflater::new(compression)?;
+            let mut ws_receiver = futures::stream::iter(events.iter().cloned());
+            loop {
+                match receive_json(
+                    &mut ws_receiver,
+                    &mut inflater,
+                    encoding,
+                    GatewayEvent::decode,
+                )
+                .await
+                {
+                    Ok(GatewayEvent::DispatchError(_, err)) => return Err(err),
+                    Ok(event) => decoded.push(event),
+                    Err(Error::Gateway { .. } | Error::Io(_)) => break,
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+        Ok(decoded)
+    }
+
+    /// the state after every dispatch from the first READY on
+    pub async fn state(&self) -> Result<State> {
+        let mut state: Option<State> = None;
+        for event in self.events().await? {
+            let GatewayEvent::Dispatch(_, event) = event else {
+                continue;
+            };
+            match (&mut state, *event) {
+                (Some(state), event) => state.update(&event),
+                (None, Event::Ready(ready)) => state = Some(State::new(*ready)),
+                (None, _) => {}
+            }
+        }
+        state.ok_or_else(|| Error::decode("no READY in the recording"))
+    }
+}
+
+mod hex {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub(super) fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        let hex = bytes
+            .iter()
+            .map(|x| format!("{:02x}", x))
+            .collect::<String>();
+        serializer.serialize_str(&hex)
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<u8>, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        if !hex.is_ascii() || hex.len() % 2 != 0 {
+            return Err(de::Error::custom("not a hex string"));
+        }
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(de::Error::custom))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, Write},
+        sync::{Arc, Mutex},
+    };
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::api::{test_util::to_hex, ChannelId, MessageId};
+
+    const TOKEN: &str = "secret-token";
+
+    /// collects what the recorder writes
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Buffer {
+        fn recording(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    fn text(payload: Value) -> WsEvent {
+        WsEvent::Message(WsMessage::Text(payload.to_string()))
+    }
+
+    fn hello() -> Value {
+        json!({"op": 10, "d": {"heartbeat_interval": 41250}})
+    }
+
+    fn ready() -> Value {
+        json!({"op": 0, "s": 1, "t": "READY", "d": {
+            "guilds": [],
+            "presences": [],
+            "private_channels": [],
+            "relationships": [],
+            "resume_gateway_url": "wss://gateway-us-east1-b.discord.gg",
+            "session_id": "abc",
+            "user": {
+                "accent_color": null,
+                "avatar": "a1b2",
+                "avatar_decoration_data": null,
+                "banner": null,
+                "banner_color": null,
+                "bio": "",
+                "clan": null,
+                "desktop": false,
+                "discriminator": "0",
+                "email": "me@example.com",
+                "flags": 0,
+                "global_name": null,
+                "id": "1",
+                "mfa_enabled": false,
+                "mobile": false,
+                "nsfw_allowed": true,
+                "phone": null,
+                "premium": false,
+                "premium_type": 0,
+                "pronouns": "",
+                "public_flags": 0,
+                "purchased_flags": 0,
+                "username": "me",
+                "verified": true,
+            },
+            "v": 9,
+        }})
+    }
+
+    fn message_create(sequence: u64, id: u64) -> Value {
+        json!({"op": 0, "s": sequence, "t": "MESSAGE_CREATE", "d": {
+            "attachments": [],
+            "author": {
+                "avatar": null,
+                "avatar_decoration_data": null,
+                "clan": null,
+                "discriminator": "0",
+                "global_name": null,
+                "id": "2",
+                "public_flags": 0,
+                "username": "them",
+            },
+            "channel_id": "20",
+            "content": "hi",
+            "edited_timestamp": null,
+            "embeds": [],
+            "flags": 0,
+            "id": id.to_string(),
+            "mention_everyone": false,
+            "mention_roles": [],
+            "mentions": [],
+            "pinned": false,
+            "timestamp": "2025-01-01T00:00:00.000000+00:00",
+            "tts": false,
+            "type": 0,
+        }})
+    }
+
+    fn message_delete(sequence: u64, id: u64) -> Value {
+        json!({"op": 0, "s": sequence, "t": "MESSAGE_DELETE", "d": {
+            "channel_id": "20",
+            "id": id.to_string(),
+        }})
+    }
+
+    #[test]
+    fn redacts_the_token() {
+        let buffer = Buffer::default();
+        let recorder = Recorder::new(buffer.clone());
+        let identify = json!({"op": 2, "d": {"token": TOKEN, "properties": {"$os": "linux"}}});
+        recorder.opened(Compression::None, Encoding::Json);
+        recorder.sent(&identify, Encoding::Json, &TOKEN.into());
+        recorder.sent(&identify, Encoding::Etf, &TOKEN.into());
+
+        recorder.flush();
+        let recording = buffer.recording();
+        assert_eq!(recording.lines().count(), 3);
+        assert!(!recording.contains(TOKEN));
+        assert!(!recording.contains(&to_hex(TOKEN.as_bytes())));
+        assert!(recording.contains(REDACTED));
+    }
+
+    #[test]
+    fn replays_into_state() {
+        let buffer = Buffer::default();
+        let recorder = Recorder::new(buffer.clone());
+        recorder.opened(Compression::None, Encoding::Json);
+        recorder.received(&WsEvent::Opened);
+        recorder.received(&text(hello()));
+        recorder.sent(
+            &json!({"op": 2, "d": {"token": TOKEN}}),
+            Encoding::Json,
+            &TOKEN.into(),
+        );
+        recorder.received(&text(ready()));
+        recorder.received(&text(message_create(2, 10)));
+        recorder.received(&text(message_create(3, 11)));
+        recorder.received(&text(message_delete(4, 10)));
+
+        recorder.flush();
+        let replay = Replay::new(&buffer.recording()).unwrap();
+        let events = futures::executor::block_on(replay.events()).unwrap();
+        assert_eq!(events.len(), 5);
+        assert!(matches!(events[0], GatewayEvent::Hello(41250)));
+
+        let state = futures::executor::block_on(replay.state()).unwrap();
+        assert_eq!(state.user().username, "me");
+        let messages = state.messages(ChannelId(20)).unwrap();
+        assert_eq!(
+            messages.iter().map(|x| x.id).collect::<Vec<_>>(),
+            [MessageId(11)]
+        );
+    }
+
+    #[test]
+    fn replays_compressed_etf_over_reconnects() {
+        let buffer = Buffer::default();
+        let recorder = Recorder::new(buffer.clone());
+        recorder.opened(Compression::None, Encoding::Etf);
+        let WsMessage::Binary(hello) = Encoding::Etf.encode(&hello()) else {
+            panic!("etf is sent as binary");
+        };
+        recorder.received(&WsEvent::Message(WsMessage::Binary(hello)));
+        recorder.received(&WsEvent::Closed(Some(4000)));
+
+        // a zlib-stream payload split over two frames
+        recorder.opened(Compression::ZlibStream, Encoding::Json);
+        let mut encoder =
+            flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(ready().to_string().as_bytes()).unwrap();
+        encoder.flush().unwrap();
+        let compressed = encoder.get_ref().clone();
+        let (first, second) = compressed.split_at(compressed.len() / 2);
+        recorder.received(&WsEvent::Message(WsMessage::Binary(first.to_vec())));
+        recorder.received(&WsEvent::Message(WsMessage::Binary(second.to_vec())));
+
+        recorder.flush();
+        let replay = Replay::new(&buffer.recording()).unwrap();
+        let events = futures::executor::block_on(replay.events()).unwrap();
+        assert_eq!(events.len(), 2);
+        assert!(matches!(events[0], GatewayEvent::Hello(41250)));
+        assert!(matches!(
+            events[1],
+            GatewayEvent::Dispatch(1, ref event) if matches!(**event, Event::Ready(_))
+        ));
+    }
+
+    #[test]
+    fn refuses_undecodable_payloads() {
+        let recording = [
+            json!({"kind": "opened", "compression": "none", "encoding": "json"}),
+            json!({"kind": "received", "frame": {"text": "{\"op\": 42}"}}),
+        ]
+        .map(|x| x.to_string())
+        .join("\n");
+        let replay = Replay::new(&recording).unwrap();
+        assert!(futures::executor::block_on(replay.events()).is_err());
+
+        let recording = json!({"kind": "closed", "code": 4000}).to_string();
+        assert!(Replay::new(&recording).is_err());
+    }
+}
@@ -0,0 +1,34 @@
+// what the api needs from the platform: the `web` feature runs on the browser event loop
+// through yew for the wasm ui, the `native` feature runs on tokio
+
+use std::{future::Future, time::Duration};
+
+#[cfg(not(any(feature = "web", feature = "native")))]
+compile_error!("enable either the `web` or the `native` feature");
+
+/// runs a task in the background, it is dropped with the runtime
+#[cfg(feature = "native")]
+pub(crate) fn spawn(future: impl Future<Output = ()> + Send + 'static) {
+    tokio::spawn(future);
+}
+
+#[cfg(all(feature = "web", not(feature = "native")))]
+pub(crate) fn spawn(future: impl Future<Output = ()> + 'static) {
+    yew::platform::spawn_local(future);
+}
+
+pub(crate) async fn sleep(duration: Duration) {
+    #[cfg(feature = "native")]
+    tokio::time::sleep(duration).await;
+    #[cfg(all(feature = "web", not(feature = "native")))]
+    yew::platform::time::sleep(duration).await;
+}
+
+/// the browser console has no logger set up, so it is written to directly there.
+/// elsewhere it is up to the application's logger
+pub(crate) fn log(message: &str) {
+    #[cfg(all(feature = "web", not(feature = "native"), target_arch = "wasm32"))]
+    web_sys::console::log_1(&message.into());
+    #[cfg(not(all(feature = "web", not(feature = "native"), target_arch = "wasm32")))]
+    log::warn!("{}", message);
+}
@@ -1,25 +1,615 @@
-use super::model::{Channel, CurrentUser, Event, Presence, ReadyEvent};
+use std::collections::{BTreeMap, VecDeque};
 
+use super::{
+    model::{
+        Channel, ChannelId, CurrentUser, Event, Member, Message, PossibleServer, Presence,
+        ReadyEvent, Relationship, Role, RoleId, Server, ServerId, User, UserId,
+    },
+    permissions::{self, Permissions},
+};
+
+/// how many of the most recent messages are kept for each channel
+pub const MESSAGE_LIMIT: usize = 100;
+
+/// in-memory cache of everything received from the gateway
 #[derive(Debug, Clone)]
 pub struct State {
+    messages: BTreeMap<ChannelId, VecDeque<Message>>,
     presences: Vec<Presence>,
     pub private_channels: Vec<Channel>,
+    relationships: Vec<Relationship>,
+    pub servers: Vec<PossibleServer>,
     user: CurrentUser,
 }
 
 impl State {
     pub fn new(ready: ReadyEvent) -> Self {
         Self {
+            messages: BTreeMap::new(),
             presences: ready.presences,
             private_channels: ready.private_channels,
+            relationships: ready.relationships,
+            servers: ready.servers,
             user: ready.user,
         }
     }
 
     pub fn update(&mut self, event: &Event) {
         match *event {
-            Event::Ready(ref ready) => *self = State::new(ready.clone()),
-            _ => {}
+            Event::Ready(ref ready) => *self = State::new((**ready).clone()),
+            Event::Resumed => {}
+            // the message may already have been fetched over REST
+            Event::MessageCreate(ref message) => {
+                self.insert_messages(message.channel_id, vec![(**message).clone()])
+            }
+            Event::MessageUpdate(ref event) => {
+                if let Some(message) = self
+                    .messages
+                    .get_mut(&event.channel_id)
+                    .and_then(|x| x.iter_mut().find(|x| x.id == event.id))
+                {
+                    event.apply(message);
+                }
+            }
+            Event::MessageDelete(ref event) => {
+                if let Some(messages) = self.messages.get_mut(&event.channel_id) {
+                    messages.retain(|x| x.id != event.id);
+                }
+            }
+            Event::MessageDeleteBulk(ref event) => {
+                if let Some(messages) = self.messages.get_mut(&event.channel_id) {
+                    messages.retain(|x| !event.ids.contains(&x.id));
+                }
+            }
+            Event::LastMessages(ref event) => {
+                for message in &event.messages {
+                    self.insert_messages(message.channel_id, vec![message.clone()]);
+                }
+            }
+            Event::ServerCreate(ref server) => {
+                let id = server.id();
+                match self.servers.iter_mut().find(|x| x.id() == id) {
+                    Some(existing) => *existing = server.clone(),
+                    None => self.servers.push(server.clone()),
+                }
+            }
+            Event::ServerUpdate(ref server) => {
+                if let Some(existing) = self.server_mut(server.id) {
+                    // GUILD_UPDATE does not contain these
+                    let mut server = (**server).clone();
+                    server.channels = std::mem::take(&mut existing.channels);
+                    server.joined_at = existing.joined_at;
+                    server.large = existing.large;
+                    server.member_count = existing.member_count;
+                    server.members = std::mem::take(&mut existing.members);
+                    server.threads = std::mem::take(&mut existing.threads);
+                    *existing = server;
+                }
+            }
+            Event::ServerDelete(ref event) => {
+                if event.unavailable {
+                    if let Some(existing) = self.servers.iter_mut().find(|x| x.id() == event.id) {
+                        *existing = PossibleServer::Offline(event.id);
+                    }
+                } else {
+                    if let Some(PossibleServer::Online(server)) =
+                        self.servers.iter().find(|x| x.id() == event.id)
+                    {
+                        for channel in server.channels.iter().map(Channel::id) {
+                            self.messages.remove(&channel);
+                        }
+                    }
+                    self.servers.retain(|x| x.id() != event.id);
+                }
+            }
+            Event::ServerRoleCreate(ref event) | Event::ServerRoleUpdate(ref event) => {
+                if let Some(server) = self.server_mut(event.server_id) {
+                    match server.roles.iter_mut().find(|x| x.id == event.role.id) {
+                        Some(role) => *role = event.role.clone(),
+                        None => server.roles.push(event.role.clone()),
+                    }
+                }
+            }
+            Event::ServerRoleDelete(ref event) => {
+                if let Some(server) = self.server_mut(event.server_id) {
+                    server.roles.retain(|x| x.id != event.role_id);
+                }
+            }
+            Event::ServerEmojisUpdate(ref event) => {
+                if let Some(server) = self.server_mut(event.server_id) {
+                    server.emojis = event.emojis.clone();
+                }
+            }
+            Event::ServerStickersUpdate(ref event) => {
+                if let Some(server) = self.server_mut(event.server_id) {
+                    server.stickers = event.stickers.clone();
+                }
+            }
+            Event::ServerMemberAdd(ref event) => {
+                if self.upsert_member(event.server_id, &event.member) {
+                    if let Some(server) = self.server_mut(event.server_id) {
+                        server.member_count = server.member_count.map(|x| x + 1);
+                    }
+                }
+            }
+            Event::ServerMemberUpdate(ref event) => {
+                self.upsert_member(event.server_id, &event.member);
+            }
+            Event::ServerMemberRemove(ref event) => {
+                if let Some(server) = self.server_mut(event.server_id) {
+                    server
+                        .members
+                        .retain(|x| x.user.as_ref().map(|x| x.id) != Some(event.user.id));
+                    server.member_count = server.member_count.map(|x| x.saturating_sub(1));
+                }
+            }
+            Event::ServerMembersChunk(ref event) => {
+                for member in &event.members {
+                    self.upsert_member(event.server_id, member);
+                }
+            }
+            Event::ChannelCreate(ref event) | Event::ChannelUpdate(ref event) => {
+                let channels = match event.server_id {
+                    Some(server_id) => match self.server_mut(server_id) {
+                        // threads are kept apart from the channels they are in
+                        Some(server) if matches!(event.channel, Channel::Thread(_)) => {
+                            &mut server.threads
+                        }
+                        Some(server) => &mut server.channels,
+                        None => return,
+                    },
+                    None => &mut self.private_channels,
+                };
+                let id = event.channel.id();
+                match channels.iter_mut().find(|x| x.id() == id) {
+                    Some(channel) => *channel = event.channel.clone(),
+                    None => channels.push(event.channel.clone()),
+                }
+            }
+            Event::ChannelDelete(ref event) => {
+                let id = event.channel.id();
+                match event.server_id {
+                    Some(server_id) => {
+                        if let Some(server) = self.server_mut(server_id) {
+                            server.channels.retain(|x| x.id() != id);
+                            server.threads.retain(|x| x.id() != id);
+                        }
+                    }
+                    None => self.private_channels.retain(|x| x.id() != id),
+                }
+                self.messages.remove(&id);
+            }
+            Event::RelationshipAdd(ref relationship) => {
+                match self
+                    .relationships
+                    .iter_mut()
+                    .find(|x| x.id == relationship.id)
+                {
+                    Some(existing) => *existing = relationship.clone(),
+                    None => self.relationships.push(relationship.clone()),
+                }
+            }
+            Event::RelationshipRemove(ref event) => {
+                self.relationships.retain(|x| x.id != event.id);
+            }
+            Event::PresenceUpdate(ref event) => {
+                let id = event.user.id;
+                match self.presences.iter_mut().find(|x| x.user.id == id) {
+                    Some(presence) => event.apply(presence),
+                    // a presence can only be added for a user that is already cached
+                    None => {
+                        if let Some(user) = self.cached_user(id).cloned() {
+                            self.presences.push(Presence {
+                                activities: event.activities.clone(),
+                                client_status: event.client_status.clone(),
+                                last_modified: 0,
+                                restricted_application_id: None,
+                                status: event.status.clone(),
+                                user,
+                            });
+                        }
+                    }
+                }
+            }
+            Event::Unknown(..) => {}
+        }
+    }
+
+    /// true if the member was not cached yet
+    fn upsert_member(&mut self, server_id: ServerId, member: &Member) -> bool {
+        let Some(server) = self.server_mut(server_id) else {
+            return false;
+        };
+        let id = member.user.as_ref().map(|x| x.id);
+        match server
+            .members
+            .iter_mut()
+            .find(|x| x.user.as_ref().map(|x| x.id) == id)
+        {
+            Some(existing) => {
+                *existing = member.clone();
+                false
+            }
+            None => {
+                server.members.push(member.clone());
+                true
+            }
+        }
+    }
+
+    /// looks through relationships and server members
+    fn cached_user(&self, id: UserId) -> Option<&User> {
+        self.relationships
+            .iter()
+            .find(|x| x.id == id)
+            .map(|x| &x.user)
+            .or_else(|| {
+                self.servers.iter().find_map(|x| match *x {
+                    PossibleServer::Online(ref server) => server.member(id)?.user.as_ref(),
+                    PossibleServer::Offline(_) => None,
+                })
+            })
+    }
+
+    /// adds messages fetched over REST, keeping the newest MESSAGE_LIMIT
+    pub fn insert_messages(&mut self, channel: ChannelId, fetched: Vec<Message>) {
+        let messages = self.messages.entry(channel).or_default();
+        for message in fetched {
+            if !messages.iter().any(|x| x.id == message.id) {
+                messages.push_back(message);
+            }
+        }
+        messages.make_contiguous().sort_by_key(|x| x.id);
+        while messages.len() > MESSAGE_LIMIT {
+            messages.pop_front();
+        }
+    }
+
+    pub fn user(&self) -> &CurrentUser {
+        &self.user
+    }
+
+    pub fn server(&self, id: ServerId) -> Option<&Server> {
+        self.servers.iter().find_map(|x| match *x {
+            PossibleServer::Online(ref server) if server.id == id => Some(&**server),
+            _ => None,
+        })
+    }
+
+    fn server_mut(&mut self, id: ServerId) -> Option<&mut Server> {
+        self.servers.iter_mut().find_map(|x| match *x {
+            PossibleServer::Online(ref mut server) if server.id == id => Some(&mut **server),
+            _ => None,
+        })
+    }
+
+    /// looks through private channels, server channels and threads
+    pub fn channel(&self, id: ChannelId) -> Option<&Channel> {
+        self.private_channels
+            .iter()
+            .chain(self.servers.iter().flat_map(|x| match *x {
+                PossibleServer::Online(ref server) => {
+                    server.channels.iter().chain(server.threads.iter())
+                }
+                PossibleServer::Offline(_) => [].iter().chain([].iter()),
+            }))
+            .find(|x| x.id() == id)
+    }
+
+    /// the server a channel belongs to, None for private channels
+    pub fn channel_server(&self, id: ChannelId) -> Option<&Server> {
+        self.servers.iter().find_map(|x| match *x {
+            PossibleServer::Online(ref server)
+                if server
+                    .channels
+                    .iter()
+                    .chain(server.threads.iter())
+                    .any(|x| x.id() == id) =>
+            {
+                Some(&**server)
+            }
+            _ => None,
+        })
+    }
+
+    /// the cached messages of a channel, oldest first
+    pub fn messages(&self, channel: ChannelId) -> Option<&VecDeque<Message>> {
+        self.messages.get(&channel)
+    }
+
+    pub fn member(&self, server: ServerId, user: UserId) -> Option<&Member> {
+        self.server(server)?.member(user)
+    }
+
+    pub fn role(&self, server: ServerId, role: RoleId) -> Option<&Role> {
+        self.server(server)?.role(role)
+    }
+
+    /// permissions of the current user in a server channel, None for private channels
+    /// or if the channel or the user's member is not cached
+    pub fn permissions(&self, channel: ChannelId) -> Option<Permissions> {
+        let server = self.channel_server(channel)?;
+        let member = server.member(self.user.id)?;
+        let channel = match *self.channel(channel)? {
+            Channel::Thread(ref thread) => self.channel(thread.parent_id)?,
+            ref channel => channel,
+        };
+        Some(permissions::channel_permissions(
+            server,
+            self.user.id,
+            member,
+            channel,
+        ))
+    }
+
+    pub fn presence(&self, user: UserId) -> Option<&Presence> {
+        self.presences.iter().find(|x| x.user.id == user)
+    }
+
+    pub fn relationships(&self) -> &[Relationship] {
+        &self.relationships
+    }
+
+    pub fn relationship(&self, user: UserId) -> Option<&Relationship> {
+        self.relationships.iter().find(|x| x.id == user)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::api::{
+        model::{decode_value, Ignored},
+        permissions::Permissions,
+        test_util::{channel_json, role_json, server_json, user_json},
+        MessageId, Status,
+    };
+
+    fn thread(id: u64, name: &str) -> Value {
+        json!({
+            "guild_id": "10",
+            "id": id.to_string(),
+            "last_message_id": null,
+            "name": name,
+            "owner_id": "2",
+            "parent_id": "20",
+            "thread_metadata": {
+                "archive_timestamp": "2025-01-01T00:00:00.000000+00:00",
+                "archived": false,
+                "auto_archive_duration": 1440,
+                "create_timestamp": null,
+                "invitable": null,
+                "locked": false,
+            },
+            "type": 11,
+        })
+    }
+
+    /// a server with everything GUILD_UPDATE leaves out
+    fn server() -> Value {
+        let mut server = server_json();
+        server["channels"] = json!([channel_json(20, &[])]);
+        server["joined_at"] = json!("2025-01-01T00:00:00.000000+00:00");
+        server["large"] = json!(true);
+        server["member_count"] = json!(1);
+        server["members"] = json!([]);
+        server["roles"] = json!([role_json(10, "@everyone", Permissions::empty())]);
+        server["threads"] = json!([thread(21, "thread")]);
+        server
+    }
+
+    fn state() -> State {
+        let ready = json!({
+                "guilds": [server()],
+                "presences": [],
+                "private_channels": [],
+                "relationships": [{
+                    "id": "2",
+                    "nickname": null,
+                    "since": "2025-01-01T00:00:00.000000+00:00",
+                    "type": 1,
+                    "user": user_json(2),
+                }],
+                "resume_gateway_url": "wss://gateway.discord.gg",
+                "session_id": "abc",
+                "user": {
+                    "accent_color": null,
+                    "avatar": "a1b2",
+                    "banner": null,
+                    "banner_color": null,
+                    "bio": "",
+                    "desktop": false,
+                    "discriminator": "0",
+                    "email": "me@example.com",
+                    "flags": 0,
+                    "id": "1",
+                    "mfa_enabled": false,
+                    "mobile": false,
+                    "nsfw_allowed": true,
+                    "phone": null,
+                    "premium": false,
+                    "premium_type": 0,
+                    "pronouns": "",
+                    "public_flags": 0,
+                    "purchased_flags": 0,
+                    "username": "me",
+                    "verified": true,
+                },
+                "v": 9,
+        });
+        State::new(decode_value(ready, &Ignored::NONE).unwrap())
+    }
+
+    fn event(kind: &str, d: Value) -> Event {
+        Event::decode(kind, d).unwrap()
+    }
+
+    fn message(id: u64) -> Value {
+        json!({
+            "attachments": [],
+            "author": user_json(2),
+            "channel_id": "20",
+            "content": "hi",
+            "edited_timestamp": null,
+            "embeds": [],
+            "flags": 0,
+            "id": id.to_string(),
+            "mention_everyone": false,
+            "mention_roles": [],
+            "mentions": [],
+            "pinned": false,
+            "timestamp": "2025-01-01T00:00:00.000000+00:00",
+            "tts": false,
+            "type": 0,
+        })
+    }
+
+    fn presence_update(user: u64, status: &str) -> Event {
+        event(
+            "PRESENCE_UPDATE",
+            json!({
+                "activities": [],
+                "client_status": {"desktop": status},
+                "status": status,
+                "user": {"id": user.to_string()},
+            }),
+        )
+    }
+
+    #[test]
+    fn created_messages_are_not_duplicated() {
+        let mut state = state();
+        let fetched = decode_value(message(11), &Ignored::NONE).unwrap();
+        state.insert_messages(ChannelId(20), vec![fetched]);
+        state.update(&event("MESSAGE_CREATE", message(11)));
+        state.update(&event("MESSAGE_CREATE", message(12)));
+        let messages = state.messages(ChannelId(20)).unwrap();
+        assert_eq!(
+            messages.iter().map(|x| x.id).collect::<Vec<_>>(),
+            [MessageId(11), MessageId(12)]
+        );
+    }
+
+    #[test]
+    fn only_new_members_are_counted() {
+        let mut state = state();
+        let member_add = || {
+            event(
+                "GUILD_MEMBER_ADD",
+                json!({"guild_id": "10", "roles": [], "user": user_json(3)}),
+            )
+        };
+        state.update(&member_add());
+        state.update(&member_add());
+        let server = state.server(ServerId(10)).unwrap();
+        assert_eq!(server.member_count, Some(2));
+        assert!(state.member(ServerId(10), UserId(3)).is_some());
+    }
+
+    #[test]
+    fn presences_are_updated() {
+        let mut state = state();
+        state.update(&presence_update(2, "idle"));
+        assert_eq!(state.presence(UserId(2)).unwrap().status, Status::Idle);
+        state.update(&presence_update(2, "dnd"));
+        let presence = state.presence(UserId(2)).unwrap();
+        assert_eq!(presence.status, Status::Dnd);
+        assert_eq!(presence.client_status.desktop, Some(Status::Dnd));
+        // there is no user to put in the presence
+        state.update(&presence_update(3, "online"));
+        assert!(state.presence(UserId(3)).is_none());
+    }
+
+    #[test]
+    fn server_updates_keep_what_they_leave_out() {
+        let mut state = state();
+        state.update(&event(
+            "GUILD_MEMBER_ADD",
+            json!({"guild_id": "10", "roles": [], "user": user_json(3)}),
+        ));
+        let mut update = server();
+        for field in [
+            "channels",
+            "joined_at",
+            "large",
+            "member_count",
+            "members",
+            "threads",
+        ] {
+            update.as_object_mut().unwrap().remove(field);
+        }
+        update["name"] = json!("renamed");
+        state.update(&event("GUILD_UPDATE", update));
+
+        let server = state.server(ServerId(10)).unwrap();
+        assert_eq!(server.name, "renamed");
+        assert_eq!(server.channels.len(), 1);
+        assert_eq!(server.threads.len(), 1);
+        assert!(server.member(UserId(3)).is_some());
+        assert!(server.joined_at.is_some());
+        assert!(server.large);
+        assert_eq!(server.member_count, Some(2));
+    }
+
+    #[test]
+    fn unavailable_servers_are_kept_offline() {
+        let mut state = state();
+        state.update(&event("MESSAGE_CREATE", message(11)));
+        state.update(&event(
+            "GUILD_DELETE",
+            json!({"id": "10", "unavailable": true}),
+        ));
+        assert!(matches!(
+            state.servers[..],
+            [PossibleServer::Offline(ServerId(10))]
+        ));
+        assert!(state.messages(ChannelId(20)).is_some());
+    }
+
+    #[test]
+    fn left_servers_are_removed() {
+        let mut state = state();
+        state.update(&event("MESSAGE_CREATE", message(11)));
+        state.update(&event("GUILD_DELETE", json!({"id": "10"})));
+        assert!(state.servers.is_empty());
+        assert!(state.messages(ChannelId(20)).is_none());
+        assert!(state.channel(ChannelId(20)).is_none());
+    }
+
+    #[test]
+    fn roles_are_created_updated_and_deleted() {
+        let mut state = state();
+        let role_event =
+            |name| json!({"guild_id": "10", "role": role_json(11, name, Permissions::empty())});
+        state.update(&event("GUILD_ROLE_CREATE", role_event("new")));
+        assert_eq!(state.role(ServerId(10), RoleId(11)).unwrap().name, "new");
+        state.update(&event("GUILD_ROLE_UPDATE", role_event("renamed")));
+        let server = state.server(ServerId(10)).unwrap();
+        assert_eq!(server.roles.len(), 2);
+        assert_eq!(server.role(RoleId(11)).unwrap().name, "renamed");
+        state.update(&event(
+            "GUILD_ROLE_DELETE",
+            json!({"guild_id": "10", "role_id": "11"}),
+        ));
+        assert!(state.role(ServerId(10), RoleId(11)).is_none());
+        assert!(state.role(ServerId(10), RoleId(10)).is_some());
+    }
+
+    #[test]
+    fn threads_are_kept_apart_from_channels() {
+        let mut state = state();
+        state.update(&event("CHANNEL_CREATE", thread(22, "new")));
+        state.update(&event("CHANNEL_UPDATE", thread(21, "renamed")));
+        let server = state.server(ServerId(10)).unwrap();
+        assert_eq!(server.channels.len(), 1);
+        assert_eq!(server.threads.len(), 2);
+        match *state.channel(ChannelId(21)).unwrap() {
+            Channel::Thread(ref thread) => assert_eq!(thread.name, "renamed"),
+            ref other => panic!("expected a thread, got {:?}", other),
         }
+        state.update(&event("CHANNEL_DELETE", thread(21, "renamed")));
+        assert_eq!(state.server(ServerId(10)).unwrap().threads.len(), 1);
     }
 }
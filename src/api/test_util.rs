@@ -0,0 +1,71 @@
+// helpers shared by the unit tests
+
+use serde_json::{json, Value};
+
+use super::permissions::Permissions;
+
+/// parses a lowercase hex dump, as the fixtures are written
+pub(crate) fn hex(hex: &str) -> Vec<u8> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+/// the other way around, as binary frames end up in a recording
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+/// server 10 owned by user 1, with only what a server can't do without
+pub(crate) fn server_json() -> Value {
+    json!({
+        "afk_timeout": 300,
+        "default_message_notifications": 0,
+        "emojis": [],
+        "explicit_content_filter": 0,
+        "features": [],
+        "id": "10",
+        "mfa_level": 0,
+        "name": "server",
+        "nsfw_level": 0,
+        "owner_id": "1",
+        "preferred_locale": "en-US",
+        "premium_tier": 0,
+        "roles": [],
+        "system_channel_flags": 0,
+        "verification_level": 0,
+    })
+}
+
+/// a user as sent inside other objects, e.g. as the author of a message
+pub(crate) fn user_json(id: u64) -> Value {
+    json!({"discriminator": "0", "id": id.to_string(), "username": format!("user{}", id)})
+}
+
+pub(crate) fn role_json(id: u64, name: &str, permissions: Permissions) -> Value {
+    json!({
+        "color": 0,
+        "hoist": false,
+        "id": id.to_string(),
+        "managed": false,
+        "mentionable": false,
+        "name": name,
+        "permissions": permissions.bits().to_string(),
+        "position": 0,
+    })
+}
+
+/// a text channel
+pub(crate) fn channel_json(id: u64, overwrites: &[Value]) -> Value {
+    json!({
+        "flags": 0,
+        "id": id.to_string(),
+        "name": "general",
+        "permission_overwrites": overwrites,
+        "position": 0,
+        "rate_limit_per_user": 0,
+        "type": 0,
+        "version": 0,
+    })
+}
@@ -0,0 +1,322 @@
+// the websocket the gateway runs on, the browser's own with the `web` feature
+// and tokio-tungstenite with the `native` feature. both are a Stream of events and a Sink
+// of messages that wake the task as soon as something arrives
+
+#[cfg(feature = "native")]
+pub(crate) use native::{connect, WsSender};
+#[cfg(all(feature = "web", not(feature = "native")))]
+pub(crate) use web::{connect, WsSender};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum WsMessage {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum WsEvent {
+    Opened,
+    Message(WsMessage),
+    Error(String),
+    /// with the close code if the backend knows it
+    Closed(Option<u16>),
+}
+
+#[cfg(all(feature = "web", not(feature = "native")))]
+mod web {
+    use std::{
+        pin::Pin,
+        rc::Rc,
+        task::{Context, Poll},
+    };
+
+    use futures::{
+        channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
+        Sink, Stream, StreamExt,
+    };
+    use wasm_bindgen::{closure::Closure, convert::FromWasmAbi, JsCast, JsValue};
+    use web_sys::{BinaryType, CloseEvent, Event, MessageEvent, WebSocket};
+
+    use super::{WsEvent, WsMessage};
+    use crate::api::{Error, Result};
+
+    /// dropping it closes the websocket
+    pub(crate) struct WsSender(Rc<Socket>);
+
+    pub(crate) struct WsReceiver {
+        events: UnboundedReceiver<WsEvent>,
+        closed: bool,
+        /// the callbacks have to live as long as the browser can call them
+        _socket: Rc<Socket>,
+    }
+
+    /// the browser's websocket and the callbacks it was given
+    struct Socket {
+        socket: WebSocket,
+        _onopen: Closure<dyn FnMut(Event)>,
+        _onmessage: Closure<dyn FnMut(MessageEvent)>,
+        _onerror: Closure<dyn FnMut(Event)>,
+        _onclose: Closure<dyn FnMut(CloseEvent)>,
+    }
+
+    /// opens in the background, [`WsEvent::Opened`] is received once it is open
+    pub(crate) fn connect(url: &str) -> Result<(WsSender, WsReceiver)> {
+        let socket = WebSocket::new(url).map_err(js_error)?;
+        socket.set_binary_type(BinaryType::Arraybuffer);
+        let (events, receiver) = mpsc::unbounded();
+
+        let onopen = callback(&events, |_: Event| Some(WsEvent::Opened));
+        let onmessage = callback(&events, |event: MessageEvent| {
+            let data = event.data();
+            let message = match data.as_string() {
+                Some(text) => WsMessage::Text(text),
+                None => {
+                    let binary = data.dyn_into::<js_sys::ArrayBuffer>().ok()?;
+                    WsMessage::Binary(js_sys::Uint8Array::new(&binary).to_vec())
+                }
+            };
+            Some(WsEvent::Message(message))
+        });
+        // the browser doesn't say what went wrong, a close event follows
+        let onerror = callback(&events, |_: Event| {
+            Some(WsEvent::Error("websocket error".to_owned()))
+        });
+        // 1005 is the browser's way of saying there was no close code
+        let onclose = callback(&events, |event: CloseEvent| {
+            Some(WsEvent::Closed(Some(event.code()).filter(|&x| x != 1005)))
+        });
+        socket.set_onopen(Some(onopen.as_ref().unchecked_ref()));
+        socket.set_onmessage(Some(onmessage.as_ref().unchecked_ref()));
+        socket.set_onerror(Some(onerror.as_ref().unchecked_ref()));
+        socket.set_onclose(Some(onclose.as_ref().unchecked_ref()));
+
+        let socket = Rc::new(Socket {
+            socket,
+            _onopen: onopen,
+            _onmessage: onmessage,
+            _onerror: onerror,
+            _onclose: onclose,
+        });
+        let receiver = WsReceiver {
+            events: receiver,
+            closed: false,
+            _socket: socket.clone(),
+        };
+        Ok((WsSender(socket), receiver))
+    }
+
+    /// passes what `f` makes of a browser event to the receiver, None skips it
+    fn callback<T: FromWasmAbi + 'static>(
+        events: &UnboundedSender<WsEvent>,
+        f: impl Fn(T) -> Option<WsEvent> + 'static,
+    ) -> Closure<dyn FnMut(T)> {
+        let events = events.clone();
+        Closure::new(move |event: T| {
+            if let Some(event) = f(event) {
+                let _ = events.unbounded_send(event);
+            }
+        })
+    }
+
+    fn js_error(err: JsValue) -> Error {
+        Error::Io(format!("{:?}", err).into())
+    }
+
+    impl WsSender {
+        /// closes with 4000, discord ends the session when closed with 1000 or 1001
+        pub(crate) fn close_resumable(self) {
+            // closing again on drop does nothing
+            let _ = self.0.socket.close_with_code(4000);
+        }
+    }
+
+    impl Drop for WsSender {
+        fn drop(&mut self) {
+            let _ = self.0.socket.close();
+        }
+    }
+
+    impl Drop for Socket {
+        fn drop(&mut self) {
+            self.socket.set_onopen(None);
+            self.socket.set_onmessage(None);
+            self.socket.set_onerror(None);
+            self.socket.set_onclose(None);
+        }
+    }
+
+    /// the browser buffers what is sent, so it is always ready
+    impl Sink<WsMessage> for WsSender {
+        type Error = Error;
+
+        fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(self: Pin<&mut Self>, message: WsMessage) -> Result<()> {
+            match message {
+                WsMessage::Text(text) => self.0.socket.send_with_str(&text),
+                WsMessage::Binary(binary) => self.0.socket.send_with_u8_array(&binary),
+            }
+            .map_err(js_error)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<()>> {
+            Poll::Ready(self.0.socket.close().map_err(js_error))
+        }
+    }
+
+    /// ends after [`WsEvent::Closed`]
+    impl Stream for WsReceiver {
+        type Item = WsEvent;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<WsEvent>> {
+            if self.closed {
+                return Poll::Ready(None);
+            }
+            let event = self.events.poll_next_unpin(cx);
+            if let Poll::Ready(Some(WsEvent::Closed(_))) = event {
+                self.closed = true;
+            }
+            event
+        }
+    }
+}
+
+#[cfg(feature = "native")]
+mod native {
+    use std::{
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use futures::{
+        channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
+        Sink, SinkExt, Stream, StreamExt,
+    };
+    use tokio_tungstenite::tungstenite::{protocol::CloseFrame, Message};
+
+    use super::{WsEvent, WsMessage};
+    use crate::api::{Error, Result};
+
+    /// dropping it closes the websocket
+    pub(crate) struct WsSender(UnboundedSender<Message>);
+
+    pub(crate) struct WsReceiver(UnboundedReceiver<WsEvent>);
+
+    /// opens in the background, [`WsEvent::Opened`] is received once it is open.
+    /// has to be called from inside a tokio runtime
+    pub(crate) fn connect(url: &str) -> Result<(WsSender, WsReceiver)> {
+        let (to_socket, mut outgoing) = mpsc::unbounded();
+        let (events, from_socket) = mpsc::unbounded();
+        let url = url.to_owned();
+        tokio::spawn(async move {
+            let socket = match tokio_tungstenite::connect_async(url).await {
+                Ok((socket, _)) => socket,
+                Err(err) => {
+                    let _ = events.unbounded_send(WsEvent::Error(err.to_string()));
+                    return;
+                }
+            };
+            let _ = events.unbounded_send(WsEvent::Opened);
+            let (mut write, mut read) = socket.split();
+            loop {
+                tokio::select! {
+                    message = outgoing.next() => match message {
+                        Some(message) => {
+                            if let Err(err) = write.send(message).await {
+                                let _ = events.unbounded_send(WsEvent::Error(err.to_string()));
+                                break;
+                            }
+                        }
+                        None => {
+                            let _ = write.close().await;
+                            break;
+                        }
+                    },
+                    received = read.next() => {
+                        let event = match received {
+                            Some(Ok(Message::Text(text))) => WsEvent::Message(WsMessage::Text(text)),
+                            Some(Ok(Message::Binary(binary))) => {
+                                WsEvent::Message(WsMessage::Binary(binary))
+                            }
+                            Some(Ok(Message::Close(frame))) => {
+                                let _ = events
+                                    .unbounded_send(WsEvent::Closed(frame.map(|x| x.code.into())));
+                                break;
+                            }
+                            // tungstenite answers pings itself
+                            Some(Ok(_)) => continue,
+                            Some(Err(err)) => {
+                                let _ = events.unbounded_send(WsEvent::Error(err.to_string()));
+                                break;
+                            }
+                            None => {
+                                let _ = events.unbounded_send(WsEvent::Closed(None));
+                                break;
+                            }
+                        };
+                        if events.unbounded_send(event).is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+        Ok((WsSender(to_socket), WsReceiver(from_socket)))
+    }
+
+    impl WsSender {
+        /// closes with 4000, discord ends the session when closed with 1000 or 1001
+        pub(crate) fn close_resumable(self) {
+            let frame = CloseFrame {
+                code: 4000.into(),
+                reason: "".into(),
+            };
+            // the socket task may have stopped already
+            let _ = self.0.unbounded_send(Message::Close(Some(frame)));
+        }
+    }
+
+    /// the socket task does the writing, this only fails once it has stopped
+    impl Sink<WsMessage> for WsSender {
+        type Error = Error;
+
+        fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+            self.0.poll_ready_unpin(cx).map_err(closed)
+        }
+
+        fn start_send(mut self: Pin<&mut Self>, message: WsMessage) -> Result<()> {
+            let message = match message {
+                WsMessage::Text(text) => Message::Text(text),
+                WsMessage::Binary(binary) => Message::Binary(binary),
+            };
+            self.0.start_send_unpin(message).map_err(closed)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+            self.0.poll_flush_unpin(cx).map_err(closed)
+        }
+
+        /// the socket task sends a close frame once the channel is closed
+        fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+            self.0.poll_close_unpin(cx).map_err(closed)
+        }
+    }
+
+    fn closed(_: mpsc::SendError) -> Error {
+        Error::Io("websocket is closed".into())
+    }
+
+    impl Stream for WsReceiver {
+        type Item = WsEvent;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<WsEvent>> {
+            self.0.poll_next_unpin(cx)
+        }
+    }
+}
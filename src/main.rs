@@ -1,6 +1,5 @@
 use ui::create_ui;
 
-mod api;
 mod ui;
 
 fn main() {
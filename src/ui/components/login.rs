@@ -1,13 +1,7 @@
-use std::sync::mpsc::Sender;
-
-use futures::FutureExt;
-use wasm_bindgen::{JsCast, UnwrapThrowExt};
+use wasm_bindgen::JsCast;
 use wasm_bindgen_futures::spawn_local;
 use web_sys::HtmlInputElement;
-use yew::{
-    function_component, html, platform::pinned::mpsc::UnboundedSender, use_state, Callback, Html,
-    InputEvent, UseStateHandle,
-};
+use yew::{function_component, html, use_state, Callback, Html, InputEvent};
 use yew_autoprops::autoprops;
 
 use crate::ui::{DiscidiumData, Message};
@@ -15,7 +9,7 @@ use crate::ui::{DiscidiumData, Message};
 #[autoprops]
 #[function_component]
 pub fn Login(sender_callback: Callback<Message>) -> Html {
-    let input = use_state(|| String::new());
+    let input = use_state(String::new);
     let oninput = {
         let input = input.clone();
         move |input_event: InputEvent| {
@@ -32,18 +26,14 @@ pub fn Login(sender_callback: Callback<Message>) -> Html {
     let onclick = Callback::from(move |_| {
         let input = input.clone();
         let sender_callback = sender_callback.clone();
-        spawn_local(async {
-            async move {
-                let data = DiscidiumData::from_token(input.to_string()).await;
-                web_sys::console::log_1(&format!("{:?}", data.is_some()).into());
-                if data.is_some() {
-                    web_sys::console::log_1(&format!("logged in").into());
-                    sender_callback.emit(Message::Login(data.unwrap()));
-                    input.set(String::new());
-                }
-                web_sys::console::log_1(&format!("{:?}", input.to_string()).into());
+        spawn_local(async move {
+            let data = DiscidiumData::from_token(input.to_string()).await;
+            web_sys::console::log_1(&format!("{:?}", data.is_some()).into());
+            if let Some(data) = data {
+                web_sys::console::log_1(&"logged in".into());
+                sender_callback.emit(Message::Login(data));
+                input.set(String::new());
             }
-            .await
         })
     });
     html! {
@@ -1,6 +1,8 @@
 mod components;
 
-use crate::api::{client::Client, state::State, Connection};
+use std::{cell::RefCell, rc::Rc};
+
+use discidium::api::{client::Client, state::State, Connection, Error, Event};
 
 use components::Login;
 use keyring::Entry;
@@ -22,25 +24,84 @@ pub fn create_ui() {
     yew::Renderer::<App>::new().render();
 }
 
+/// the cache of the data thread, shared with the ui instead of copied for every event
+#[derive(Clone)]
+struct SharedState {
+    state: Rc<RefCell<State>>,
+    /// counts the events applied, so the ui can tell the state changed
+    version: u64,
+}
+
+impl SharedState {
+    fn new(state: State) -> Self {
+        Self {
+            state: Rc::new(RefCell::new(state)),
+            version: 0,
+        }
+    }
+
+    fn update(&mut self, event: &Event) {
+        self.state.borrow_mut().update(event);
+        self.version += 1;
+    }
+}
+
+impl PartialEq for SharedState {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.state, &other.state) && self.version == other.version
+    }
+}
+
 async fn data_thread(
     mut reciever: UnboundedReceiver<Message>,
-    state: UseStateHandle<Option<State>>,
+    state: UseStateHandle<Option<SharedState>>,
 ) {
+    use futures::{future::Either, StreamExt};
+
     let mut data = DiscidiumData::init().await;
-    if data.is_some() {
-        state.set(Some(data.as_ref().unwrap().state.clone()));
+    if let Some(ref data) = data {
+        state.set(Some(data.state.clone()));
     }
     loop {
-        use futures::StreamExt;
-        if let Some(message) = reciever.next().await {
-            match message {
-                Message::Login(new_data) => {
-                    let _ = data.insert(new_data);
-                    state.set(Some(data.as_ref().unwrap().state.clone()));
+        // gateway events only arrive once logged in
+        let received = match data.as_mut() {
+            Some(data) => {
+                match futures::future::select(reciever.next(), data.connection.next()).await {
+                    Either::Left((message, _)) => Either::Left(message),
+                    Either::Right((event, _)) => Either::Right(event),
                 }
-            };
-        } else {
-            break;
+            }
+            None => Either::Left(reciever.next().await),
+        };
+        match received {
+            Either::Left(Some(Message::Login(new_data))) => {
+                let data = data.insert(new_data);
+                state.set(Some(data.state.clone()));
+            }
+            Either::Left(None) => break,
+            Either::Right(Some(Ok(event))) => {
+                let data = data.as_mut().unwrap();
+                data.state.update(&event);
+                state.set(Some(data.state.clone()));
+            }
+            // an event that could not be decoded, the connection goes on
+            Either::Right(Some(Err(err @ Error::Decode { .. }))) => {
+                web_sys::console::log_1(&format!("{}", err).into());
+            }
+            // the gateway gave up, an invalid token has to be entered again
+            Either::Right(Some(Err(err))) => {
+                web_sys::console::log_1(&format!("connection closed: {}", err).into());
+                if let Error::Auth = err {
+                    DiscidiumData::delete_token();
+                }
+                data = None;
+                state.set(None);
+            }
+            Either::Right(None) => {
+                web_sys::console::log_1(&"connection closed".into());
+                data = None;
+                state.set(None);
+            }
         }
     }
 }
@@ -53,8 +114,15 @@ enum Message {
 fn app() -> Html {
     // let state = Mutable::new(None);
     let state = use_state(|| None);
-    let (sender, receiver) = unbounded();
-    spawn_local(data_thread(receiver, state.clone()));
+    // every state change re-renders, the data thread must only be started once
+    let sender = {
+        let state = state.clone();
+        use_memo((), move |_| {
+            let (sender, receiver) = unbounded();
+            spawn_local(data_thread(receiver, state));
+            sender
+        })
+    };
     let sender_callback = {
         let sender = sender.clone();
         move |message: Message| {
@@ -74,9 +142,11 @@ fn app() -> Html {
 }
 
 struct DiscidiumData {
+    /// for the rest calls, e.g. sending messages, once the ui makes them
+    #[allow(dead_code)]
     client: Client,
     connection: Connection,
-    state: State,
+    state: SharedState,
 }
 
 impl DiscidiumData {
@@ -89,12 +159,17 @@ impl DiscidiumData {
         let client = Client::from_user_token(token.into());
         let (connection, ready) = match client.connect().await {
             Ok(a) => a,
+            // the saved token stopped working, fall back to the login
+            Err(Error::Auth) => {
+                Self::delete_token();
+                return None;
+            }
             Err(err) => {
-                eprintln!("error connecting, Err: {:?}", err); // TODO if token doesnt work
+                web_sys::console::log_1(&format!("error connecting, Err: {:?}", err).into());
                 return None;
             }
         };
-        let state = State::new(ready);
+        let state = SharedState::new(State::new(ready));
         Some(Self {
             client,
             connection,
@@ -118,12 +193,14 @@ impl DiscidiumData {
             Ok(a) => a,
             Err(err) => {
                 web_sys::console::log_1(&format!("error connecting, Err: {:?}", err).into());
-                eprintln!("error connecting, Err: {:?}", err); // TODO if token doesnt work
+                if let Error::Auth = err {
+                    Self::delete_token();
+                }
                 return None;
             }
         };
-        web_sys::console::log_1(&format!("done").into());
-        let state = State::new(ready);
+        web_sys::console::log_1(&"done".into());
+        let state = SharedState::new(State::new(ready));
         Some(Self {
             client,
             connection,
@@ -0,0 +1,142 @@
+use std::time::{Duration, Instant};
+
+use discidium::api::{client::Client, ChannelId, Error, MessageId};
+use serde_json::json;
+
+use crate::mock::{message, MockDiscord, MockResponse};
+
+fn client(mock: &MockDiscord) -> Client {
+    Client::with_urls("token".to_owned().into(), &mock.endpoint_url, None)
+}
+
+#[tokio::test]
+async fn sends_messages() {
+    let mock = MockDiscord::start().await;
+    mock.respond(
+        "POST",
+        "channels/20/messages",
+        MockResponse::json(200, message(20, 10, "hello")),
+    );
+    let sent = client(&mock)
+        .send_message(ChannelId(20), "hello", None, None, Some("nonce"))
+        .await
+        .unwrap();
+    assert_eq!(sent.id, MessageId(10));
+
+    let requests = mock.requests();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].method, "POST");
+    assert_eq!(requests[0].headers["authorization"], "token");
+    assert_eq!(
+        requests[0].json(),
+        json!({ "content": "hello", "nonce": "nonce" })
+    );
+}
+
+#[tokio::test]
+async fn rejected_token_is_an_auth_error() {
+    let mock = MockDiscord::start().await;
+    mock.respond(
+        "GET",
+        "channels/20/messages/10",
+        MockResponse::json(401, json!({ "message": "401: Unauthorized", "code": 0 })),
+    );
+    let result = client(&mock)
+        .get_message(ChannelId(20), MessageId(10))
+        .await;
+    assert!(matches!(result, Err(Error::Auth)));
+}
+
+#[tokio::test]
+async fn retries_after_too_many_requests() {
+    let mock = MockDiscord::start().await;
+    mock.respond(
+        "GET",
+        "channels/20/messages/10",
+        MockResponse::too_many_requests(0.2, "user"),
+    );
+    mock.respond(
+        "GET",
+        "channels/20/messages/10",
+        MockResponse::json(200, message(20, 10, "hello")),
+    );
+    let start = Instant::now();
+    let fetched = client(&mock)
+        .get_message(ChannelId(20), MessageId(10))
+        .await
+        .unwrap();
+    assert_eq!(fetched.content, "hello");
+    assert!(start.elapsed() >= Duration::from_millis(200));
+    assert_eq!(mock.requests().len(), 2);
+}
+
+#[tokio::test]
+async fn gives_up_when_still_ratelimited() {
+    let mock = MockDiscord::start().await;
+    mock.respond(
+        "DELETE",
+        "channels/20/messages/10",
+        MockResponse::too_many_requests(0.05, "user"),
+    );
+    let result = client(&mock)
+        .delete_message(ChannelId(20), MessageId(10))
+        .await;
+    assert!(matches!(result, Err(Error::RateLimited { .. })));
+    assert_eq!(mock.requests().len(), 3);
+}
+
+#[tokio::test]
+async fn waits_for_an_exhausted_bucket() {
+    let mock = MockDiscord::start().await;
+    mock.respond(
+        "GET",
+        "channels/20/messages/10",
+        MockResponse::json(200, message(20, 10, "hello")).ratelimit("abc", 1, 0, 0.3),
+    );
+    let client = client(&mock);
+    let start = Instant::now();
+    client
+        .get_message(ChannelId(20), MessageId(10))
+        .await
+        .unwrap();
+    client
+        .get_message(ChannelId(20), MessageId(10))
+        .await
+        .unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(300));
+    // the wait happened before sending, not after a 429
+    assert_eq!(mock.requests().len(), 2);
+}
+
+#[tokio::test]
+async fn global_limit_holds_up_other_routes() {
+    let mock = MockDiscord::start().await;
+    mock.respond(
+        "GET",
+        "channels/20/messages/10",
+        MockResponse::too_many_requests(0.3, "global"),
+    );
+    mock.respond(
+        "GET",
+        "channels/20/messages/10",
+        MockResponse::json(200, message(20, 10, "hello")),
+    );
+    mock.respond(
+        "GET",
+        "channels/30/messages/11",
+        MockResponse::json(200, message(30, 11, "other")),
+    );
+    let client = client(&mock);
+    let start = Instant::now();
+    let first = client.get_message(ChannelId(20), MessageId(10));
+    let second = async {
+        // sent after the 429 arrived
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        client.get_message(ChannelId(30), MessageId(11)).await
+    };
+    let (first, second) = tokio::join!(first, second);
+    first.unwrap();
+    second.unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(300));
+    assert_eq!(mock.requests().len(), 3);
+}
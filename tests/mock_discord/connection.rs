@@ -0,0 +1,231 @@
+use discidium::api::{
+    client::Client, ChannelId, Compression, Connection, Encoding, Error, Event, MemberQuery,
+    MessageId, ServerId, Status, UserId,
+};
+use futures::StreamExt;
+use serde_json::json;
+use tokio::time::{timeout, Duration, Instant};
+
+use crate::mock::{message, MockDiscord, TIMEOUT, USERNAME, USER_ID};
+
+async fn connect(mock: &mut MockDiscord) -> Connection {
+    let client = Client::with_urls("token".to_owned().into(), &mock.endpoint_url, None);
+    let (connection, ready) = timeout(TIMEOUT, client.connect())
+        .await
+        .expect("no READY")
+        .unwrap();
+    assert_eq!(ready.user.username, USERNAME);
+    let identify = mock.next_command().await;
+    assert_eq!(identify["op"], 2);
+    assert_eq!(identify["d"]["token"], "token");
+    connection
+}
+
+async fn next_event(connection: &mut Connection) -> Event {
+    let next = timeout(TIMEOUT, connection.next());
+    next.await
+        .expect("no event")
+        .expect("stream ended")
+        .unwrap()
+}
+
+#[tokio::test]
+async fn receives_dispatches() {
+    let mut mock = MockDiscord::start().await;
+    let mut connection = connect(&mut mock).await;
+    assert_eq!(connection.user_id(), UserId(USER_ID));
+
+    mock.dispatch("MESSAGE_CREATE", message(20, 10, "hello"));
+    match next_event(&mut connection).await {
+        Event::MessageCreate(message) => {
+            assert_eq!(message.channel_id, ChannelId(20));
+            assert_eq!(message.id, MessageId(10));
+        }
+        other => panic!("expected MESSAGE_CREATE: {:?}", other),
+    }
+    // the gateway url came from GET /gateway
+    assert_eq!(mock.requests()[0].path, "gateway");
+}
+
+#[tokio::test]
+async fn uncompressed_without_client() {
+    let mut mock = MockDiscord::start().await;
+    let connection = Connection::new(
+        &mock.gateway_url,
+        "token".to_owned().into(),
+        Compression::None,
+        Encoding::Json,
+        None,
+    );
+    let (_connection, ready) = timeout(TIMEOUT, connection)
+        .await
+        .expect("no READY")
+        .unwrap();
+    assert_eq!(ready.session_id, "session-1");
+    assert_eq!(mock.next_command().await["op"], 2);
+}
+
+#[tokio::test]
+async fn sends_commands() {
+    let mut mock = MockDiscord::start().await;
+    let connection = connect(&mut mock).await;
+    connection
+        .update_presence(Status::Idle, &[], false, None)
+        .unwrap();
+    let presence = mock.next_command().await;
+    assert_eq!(presence["op"], 3);
+    assert_eq!(presence["d"]["status"], "idle");
+}
+
+#[tokio::test]
+async fn resumes_after_the_connection_drops() {
+    let mut mock = MockDiscord::start().await;
+    let mut connection = connect(&mut mock).await;
+    mock.dispatch("MESSAGE_CREATE", message(20, 10, "before"));
+    next_event(&mut connection).await;
+
+    mock.close(4000);
+    let resume = mock.next_command().await;
+    assert_eq!(resume["op"], 6);
+    assert_eq!(resume["d"]["session_id"], "session-1");
+    assert_eq!(resume["d"]["seq"], 2);
+    assert!(matches!(next_event(&mut connection).await, Event::Resumed));
+
+    mock.dispatch("MESSAGE_CREATE", message(20, 11, "after"));
+    assert!(matches!(
+        next_event(&mut connection).await,
+        Event::MessageCreate(message) if message.id == MessageId(11)
+    ));
+}
+
+#[tokio::test]
+async fn resumed_sessions_reconnect_without_backing_off() {
+    let mut mock = MockDiscord::start().await;
+    let mut connection = connect(&mut mock).await;
+    for _ in 0..2 {
+        // the second close would wait a second if the first still counted
+        let closed = Instant::now();
+        mock.close(4000);
+        assert_eq!(mock.next_command().await["op"], 6);
+        assert!(closed.elapsed() < Duration::from_secs(1));
+        assert!(matches!(next_event(&mut connection).await, Event::Resumed));
+    }
+}
+
+#[tokio::test]
+async fn resumes_after_a_corrupt_compressed_frame() {
+    let mut mock = MockDiscord::start().await;
+    let mut connection = connect(&mut mock).await;
+    mock.send_corrupt_frame();
+    // the zlib context can't be used anymore, the next connection starts a new one
+    let resume = mock.next_command().await;
+    assert_eq!(resume["op"], 6);
+    assert_eq!(resume["d"]["session_id"], "session-1");
+    assert!(matches!(next_event(&mut connection).await, Event::Resumed));
+    assert_eq!(mock.client_closes(), [Some(4000)]);
+
+    mock.dispatch("MESSAGE_CREATE", message(20, 11, "after"));
+    assert!(matches!(
+        next_event(&mut connection).await,
+        Event::MessageCreate(message) if message.id == MessageId(11)
+    ));
+}
+
+#[tokio::test]
+async fn undecodable_dispatches_are_errors_that_count() {
+    let mut mock = MockDiscord::start().await;
+    let mut connection = connect(&mut mock).await;
+    mock.dispatch("MESSAGE_CREATE", json!({"id": "10"}));
+    let next = timeout(TIMEOUT, connection.next());
+    assert!(matches!(
+        next.await.unwrap(),
+        Some(Err(Error::Decode { .. }))
+    ));
+
+    mock.close(4000);
+    let resume = mock.next_command().await;
+    assert_eq!(resume["op"], 6);
+    assert_eq!(resume["d"]["seq"], 2);
+}
+
+#[tokio::test]
+async fn resumes_when_asked_to_reconnect() {
+    let mut mock = MockDiscord::start().await;
+    let mut connection = connect(&mut mock).await;
+    mock.request_reconnect();
+    assert_eq!(mock.next_command().await["op"], 6);
+    assert!(matches!(next_event(&mut connection).await, Event::Resumed));
+    // closing normally would end the session
+    assert_eq!(mock.client_closes(), [Some(4000)]);
+}
+
+#[tokio::test]
+async fn resumes_after_a_resumable_invalid_session() {
+    let mut mock = MockDiscord::start().await;
+    let mut connection = connect(&mut mock).await;
+    mock.invalidate_session(true);
+    // after a random wait of 1-5 seconds
+    assert_eq!(mock.next_command().await["op"], 6);
+    assert!(matches!(next_event(&mut connection).await, Event::Resumed));
+    assert_eq!(mock.client_closes(), [Some(4000)]);
+}
+
+#[tokio::test]
+async fn identifies_again_after_an_invalid_session() {
+    let mut mock = MockDiscord::start().await;
+    let mut connection = connect(&mut mock).await;
+    mock.invalidate_session(false);
+    // after a random wait of 1-5 seconds
+    let identify = mock.next_command().await;
+    assert_eq!(identify["op"], 2);
+    match next_event(&mut connection).await {
+        Event::Ready(ready) => assert_eq!(ready.session_id, "session-2"),
+        other => panic!("expected READY: {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn stops_when_the_token_is_rejected() {
+    let mut mock = MockDiscord::start().await;
+    let mut connection = connect(&mut mock).await;
+    mock.close(4004);
+    let next = timeout(TIMEOUT, connection.next());
+    assert!(matches!(next.await.unwrap(), Some(Err(Error::Auth))));
+    assert!(connection.next().await.is_none());
+}
+
+#[tokio::test]
+async fn server_members_are_put_together() {
+    let mut mock = MockDiscord::start().await;
+    let connection = connect(&mut mock).await;
+    let members = connection
+        .request_server_members(ServerId(30), MemberQuery::Users(vec![UserId(2), UserId(3)]));
+    let answer = async {
+        let request = mock.next_command().await;
+        assert_eq!(request["op"], 8);
+        let nonce = request["d"]["nonce"].clone();
+        let member = json!({
+            "deaf": false,
+            "joined_at": "2025-01-01T00:00:00.000000+00:00",
+            "mute": false,
+            "roles": [],
+            "user": message(20, 10, "")["author"],
+        });
+        let chunk = |index, members, not_found| {
+            json!({
+                "chunk_count": 2,
+                "chunk_index": index,
+                "guild_id": "30",
+                "members": members,
+                "nonce": nonce,
+                "not_found": not_found,
+            })
+        };
+        mock.dispatch("GUILD_MEMBERS_CHUNK", chunk(0, json!([member]), json!([])));
+        mock.dispatch("GUILD_MEMBERS_CHUNK", chunk(1, json!([]), json!(["3"])));
+    };
+    let (members, ()) = tokio::join!(members, answer);
+    let members = members.unwrap();
+    assert_eq!(members.members.len(), 1);
+    assert_eq!(members.not_found, [UserId(3)]);
+}
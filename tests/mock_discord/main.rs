@@ -0,0 +1,5 @@
+// Client and Connection against a mock discord on localhost, needs the `native` feature
+
+mod client;
+mod connection;
+mod mock;
@@ -0,0 +1,506 @@
+// an in-process discord: a small http/1.1 server for the rest api and a websocket gateway,
+// both on localhost. only what the crate uses is implemented, the gateway speaks json with
+// or without zlib-stream
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io::Write,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use flate2::{write::ZlibEncoder, Compression};
+use futures::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+};
+use tokio_tungstenite::tungstenite::{
+    handshake::server::{ErrorResponse, Request, Response},
+    protocol::{frame::coding::CloseCode, CloseFrame},
+    Message,
+};
+
+/// the id and username of the user every token logs in as
+pub const USER_ID: u64 = 1;
+pub const USERNAME: &str = "mock";
+
+/// how long to wait for the client before failing the test
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// an answer of the rest api
+#[derive(Debug, Clone)]
+pub struct MockResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl MockResponse {
+    pub fn json(status: u16, body: Value) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: body.to_string(),
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: impl ToString) -> Self {
+        self.headers.push((name.to_owned(), value.to_string()));
+        self
+    }
+
+    /// the headers discord sends with every response of a bucket
+    pub fn ratelimit(self, bucket: &str, limit: u64, remaining: u64, reset_after: f64) -> Self {
+        self.header("X-RateLimit-Bucket", bucket)
+            .header("X-RateLimit-Limit", limit)
+            .header("X-RateLimit-Remaining", remaining)
+            .header("X-RateLimit-Reset-After", reset_after)
+    }
+
+    /// a 429, `scope` is `user`, `global` or `shared`
+    pub fn too_many_requests(retry_after: f64, scope: &str) -> Self {
+        let global = scope == "global";
+        let mut response = Self::json(
+            429,
+            json!({
+                "message": "You are being rate limited.",
+                "retry_after": retry_after,
+                "global": global,
+            }),
+        )
+        .header("Retry-After", retry_after)
+        .header("X-RateLimit-Scope", scope);
+        if global {
+            response = response.header("X-RateLimit-Global", "true");
+        }
+        response
+    }
+}
+
+/// a request the rest api received
+#[derive(Debug, Clone)]
+pub struct MockRequest {
+    pub method: String,
+    /// relative to the endpoint url, with the query
+    pub path: String,
+    /// lowercase names
+    pub headers: BTreeMap<String, String>,
+    pub body: String,
+}
+
+impl MockRequest {
+    pub fn json(&self) -> Value {
+        serde_json::from_str(&self.body).expect("request body is not json")
+    }
+}
+
+/// what the test tells the open gateway connection to do
+#[derive(Debug)]
+enum Control {
+    Dispatch(String, Value),
+    Send(Value),
+    /// a binary frame as is, not compressed with the connection's context
+    SendRaw(Vec<u8>),
+    Close(u16),
+}
+
+#[derive(Default)]
+struct Shared {
+    /// by `METHOD path` without the query, the last one keeps being given
+    responses: BTreeMap<String, VecDeque<MockResponse>>,
+    requests: Vec<MockRequest>,
+    /// close codes the client closed gateway connections with, None for a close without one
+    client_closes: Vec<Option<u16>>,
+    /// the most recently opened gateway connection
+    gateway: Option<UnboundedSender<Control>>,
+    /// of the current session, READY is 1
+    sequence: u64,
+    sessions: u64,
+}
+
+pub struct MockDiscord {
+    /// pass to [`discidium::api::client::Client::with_urls`]
+    pub endpoint_url: String,
+    pub gateway_url: String,
+    shared: Arc<Mutex<Shared>>,
+    /// every payload sent to the gateway except heartbeats
+    commands: UnboundedReceiver<Value>,
+}
+
+impl MockDiscord {
+    /// GET /gateway answers with the gateway of the mock
+    pub async fn start() -> Self {
+        let http = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let gateway = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint_url = format!("http://{}/api/v9/", http.local_addr().unwrap());
+        let gateway_url = format!("ws://{}", gateway.local_addr().unwrap());
+
+        let shared = Arc::new(Mutex::new(Shared::default()));
+        let (command_sender, commands) = mpsc::unbounded_channel();
+        tokio::spawn(serve_http(http, shared.clone()));
+        tokio::spawn(serve_gateway(
+            gateway,
+            gateway_url.clone(),
+            shared.clone(),
+            command_sender,
+        ));
+
+        let mock = Self {
+            endpoint_url,
+            gateway_url,
+            shared,
+            commands,
+        };
+        mock.respond(
+            "GET",
+            "gateway",
+            MockResponse::json(200, json!({ "url": mock.gateway_url })),
+        );
+        mock
+    }
+
+    /// queues the answer to `method path`, queued answers are given in order and the last
+    /// one keeps being given. routes without one are a 404
+    pub fn respond(&self, method: &str, path: &str, response: MockResponse) {
+        self.shared
+            .lock()
+            .unwrap()
+            .responses
+            .entry(format!("{} {}", method, path))
+            .or_default()
+            .push_back(response);
+    }
+
+    /// every request received so far
+    pub fn requests(&self) -> Vec<MockRequest> {
+        self.shared.lock().unwrap().requests.clone()
+    }
+
+    /// how the client closed gateway connections so far
+    pub fn client_closes(&self) -> Vec<Option<u16>> {
+        self.shared.lock().unwrap().client_closes.clone()
+    }
+
+    /// sends an op 0 with the next sequence number
+    pub fn dispatch(&self, kind: &str, d: Value) {
+        self.control(Control::Dispatch(kind.to_owned(), d));
+    }
+
+    /// closes the gateway connection with a close code
+    pub fn close(&self, code: u16) {
+        self.control(Control::Close(code));
+    }
+
+    /// op 9
+    pub fn invalidate_session(&self, resumable: bool) {
+        self.control(Control::Send(json!({ "op": 9, "d": resumable })));
+    }
+
+    /// op 7
+    pub fn request_reconnect(&self) {
+        self.control(Control::Send(json!({ "op": 7, "d": null })));
+    }
+
+    /// a binary frame that looks like the end of a zlib-stream payload but can't be inflated
+    pub fn send_corrupt_frame(&self) {
+        let frame = vec![0xde, 0xad, 0xbe, 0xef, 0x00, 0x00, 0xff, 0xff];
+        self.control(Control::SendRaw(frame));
+    }
+
+    /// the next payload the client sent to the gateway, heartbeats are answered and skipped
+    pub async fn next_command(&mut self) -> Value {
+        tokio::time::timeout(TIMEOUT, self.commands.recv())
+            .await
+            .expect("no command from the client")
+            .expect("the gateway stopped")
+    }
+
+    fn control(&self, control: Control) {
+        let shared = self.shared.lock().unwrap();
+        let gateway = shared.gateway.as_ref().expect("no gateway connection");
+        gateway
+            .send(control)
+            .expect("the gateway connection is gone");
+    }
+}
+
+/// a message from another user, as in MESSAGE_CREATE and the messages endpoints
+pub fn message(channel: u64, id: u64, content: &str) -> Value {
+    json!({
+        "attachments": [],
+        "author": {
+            "avatar": null,
+            "avatar_decoration_data": null,
+            "clan": null,
+            "discriminator": "0",
+            "global_name": null,
+            "id": "2",
+            "public_flags": 0,
+            "username": "someone",
+        },
+        "channel_id": channel.to_string(),
+        "content": content,
+        "edited_timestamp": null,
+        "embeds": [],
+        "flags": 0,
+        "id": id.to_string(),
+        "mention_everyone": false,
+        "mention_roles": [],
+        "mentions": [],
+        "pinned": false,
+        "timestamp": "2025-01-01T00:00:00.000000+00:00",
+        "tts": false,
+        "type": 0,
+    })
+}
+
+fn ready(session_id: &str, resume_gateway_url: &str) -> Value {
+    json!({
+        "guilds": [],
+        "presences": [],
+        "private_channels": [],
+        "relationships": [],
+        "resume_gateway_url": resume_gateway_url,
+        "session_id": session_id,
+        "user": {
+            "accent_color": null,
+            "avatar": "a1b2",
+            "avatar_decoration_data": null,
+            "banner": null,
+            "banner_color": null,
+            "bio": "",
+            "clan": null,
+            "desktop": false,
+            "discriminator": "0",
+            "email": "mock@example.com",
+            "flags": 0,
+            "global_name": null,
+            "id": USER_ID.to_string(),
+            "mfa_enabled": false,
+            "mobile": false,
+            "nsfw_allowed": true,
+            "phone": null,
+            "premium": false,
+            "premium_type": 0,
+            "pronouns": "",
+            "public_flags": 0,
+            "purchased_flags": 0,
+            "username": USERNAME,
+            "verified": true,
+        },
+        "v": 9,
+    })
+}
+
+async fn serve_http(listener: TcpListener, shared: Arc<Mutex<Shared>>) {
+    while let Ok((stream, _)) = listener.accept().await {
+        tokio::spawn(serve_http_connection(stream, shared.clone()));
+    }
+}
+
+/// answers requests on a kept-alive connection until the client closes it
+async fn serve_http_connection(stream: TcpStream, shared: Arc<Mutex<Shared>>) {
+    let mut stream = BufReader::new(stream);
+    loop {
+        let mut line = String::new();
+        if stream.read_line(&mut line).await.unwrap_or(0) == 0 {
+            return;
+        }
+        let mut parts = line.split_whitespace();
+        let method = parts.next().unwrap_or_default().to_owned();
+        let target = parts.next().unwrap_or_default();
+        let path = target.trim_start_matches("/api/v9/").to_owned();
+
+        let mut headers = BTreeMap::new();
+        loop {
+            let mut line = String::new();
+            stream.read_line(&mut line).await.unwrap();
+            let Some((name, value)) = line.trim_end().split_once(':') else {
+                break;
+            };
+            headers.insert(name.to_lowercase(), value.trim().to_owned());
+        }
+        let length = headers
+            .get("content-length")
+            .map_or(0, |x| x.parse().unwrap());
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).await.unwrap();
+
+        let route = format!("{} {}", method, path.split('?').next().unwrap());
+        let response = {
+            let mut shared = shared.lock().unwrap();
+            shared.requests.push(MockRequest {
+                method,
+                path,
+                headers,
+                body: String::from_utf8(body).unwrap(),
+            });
+            match shared.responses.get_mut(&route) {
+                Some(queued) if queued.len() > 1 => queued.pop_front().unwrap(),
+                Some(queued) => queued.front().unwrap().clone(),
+                None => MockResponse::json(404, json!({"message": "404: Not Found", "code": 0})),
+            }
+        };
+
+        let mut head = format!(
+            "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\n",
+            response.status,
+            response.body.len()
+        );
+        for (name, value) in response.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+        let stream = stream.get_mut();
+        if stream.write_all(head.as_bytes()).await.is_err()
+            || stream.write_all(response.body.as_bytes()).await.is_err()
+        {
+            return;
+        }
+    }
+}
+
+async fn serve_gateway(
+    listener: TcpListener,
+    url: String,
+    shared: Arc<Mutex<Shared>>,
+    commands: UnboundedSender<Value>,
+) {
+    while let Ok((stream, _)) = listener.accept().await {
+        tokio::spawn(serve_gateway_connection(
+            stream,
+            url.clone(),
+            shared.clone(),
+            commands.clone(),
+        ));
+    }
+}
+
+/// one websocket connection, it becomes the one the test controls
+async fn serve_gateway_connection(
+    stream: TcpStream,
+    url: String,
+    shared: Arc<Mutex<Shared>>,
+    commands: UnboundedSender<Value>,
+) {
+    let mut zlib = false;
+    // the signature tungstenite asks for
+    #[allow(clippy::result_large_err)]
+    let callback = |request: &Request, response: Response| -> Result<Response, ErrorResponse> {
+        zlib = request
+            .uri()
+            .query()
+            .is_some_and(|x| x.contains("compress=zlib-stream"));
+        Ok(response)
+    };
+    let mut ws = tokio_tungstenite::accept_hdr_async(stream, callback)
+        .await
+        .unwrap();
+    let mut sender = GatewaySender {
+        zlib: zlib.then(|| ZlibEncoder::new(Vec::new(), Compression::default())),
+    };
+    let (control_sender, mut control) = mpsc::unbounded_channel();
+    shared.lock().unwrap().gateway = Some(control_sender);
+
+    let hello = json!({ "op": 10, "d": { "heartbeat_interval": 45000 } });
+    if ws.send(sender.message(&hello)).await.is_err() {
+        return;
+    }
+    loop {
+        let payload = tokio::select! {
+            received = ws.next() => {
+                let payload = match received {
+                    Some(Ok(Message::Text(text))) => serde_json::from_str::<Value>(&text).unwrap(),
+                    Some(Ok(Message::Close(frame))) => {
+                        let code = frame.map(|x| u16::from(x.code));
+                        shared.lock().unwrap().client_closes.push(code);
+                        return;
+                    }
+                    Some(Err(_)) | None => return,
+                    Some(Ok(_)) => continue,
+                };
+                let answer = match payload["op"].as_u64() {
+                    // HEARTBEAT
+                    Some(1) => {
+                        let ack = json!({ "op": 11, "d": null });
+                        if ws.send(sender.message(&ack)).await.is_err() {
+                            return;
+                        }
+                        continue;
+                    }
+                    // IDENTIFY
+                    Some(2) => {
+                        let mut shared = shared.lock().unwrap();
+                        shared.sessions += 1;
+                        shared.sequence = 1;
+                        let session_id = format!("session-{}", shared.sessions);
+                        Some(dispatch(1, "READY", ready(&session_id, &url)))
+                    }
+                    // RESUME
+                    Some(6) => {
+                        let mut shared = shared.lock().unwrap();
+                        shared.sequence += 1;
+                        Some(dispatch(shared.sequence, "RESUMED", json!({})))
+                    }
+                    _ => None,
+                };
+                let _ = commands.send(payload);
+                match answer {
+                    Some(answer) => answer,
+                    None => continue,
+                }
+            }
+            control = control.recv() => match control {
+                Some(Control::Dispatch(kind, d)) => {
+                    let mut shared = shared.lock().unwrap();
+                    shared.sequence += 1;
+                    dispatch(shared.sequence, &kind, d)
+                }
+                Some(Control::Send(payload)) => payload,
+                Some(Control::SendRaw(frame)) => {
+                    if ws.send(Message::Binary(frame)).await.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+                Some(Control::Close(code)) => {
+                    let frame = CloseFrame {
+                        code: CloseCode::from(code),
+                        reason: "".into(),
+                    };
+                    let _ = ws.close(Some(frame)).await;
+                    return;
+                }
+                // a newer connection took over
+                None => return,
+            },
+        };
+        if ws.send(sender.message(&payload)).await.is_err() {
+            return;
+        }
+    }
+}
+
+fn dispatch(sequence: u64, kind: &str, d: Value) -> Value {
+    json!({ "op": 0, "s": sequence, "t": kind, "d": d })
+}
+
+/// compresses everything sent on one connection with the same zlib context if asked to
+struct GatewaySender {
+    zlib: Option<ZlibEncoder<Vec<u8>>>,
+}
+
+impl GatewaySender {
+    fn message(&mut self, payload: &Value) -> Message {
+        let Some(ref mut zlib) = self.zlib else {
+            return Message::Text(payload.to_string());
+        };
+        zlib.write_all(payload.to_string().as_bytes()).unwrap();
+        // a sync flush ends the payload with 00 00 ff ff
+        zlib.flush().unwrap();
+        Message::Binary(std::mem::take(zlib.get_mut()))
+    }
+}